@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use r2d2::CustomizeConnection;
+use rusqlite::{Connection, Error as SqlError};
+
+pub const DEFAULT_JOURNAL_MODE: &str = "WAL";
+pub const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000u64;
+pub const DEFAULT_SYNCHRONOUS: &str = "NORMAL";
+pub const DEFAULT_FOREIGN_KEYS: bool = true;
+pub const DEFAULT_CACHE_SIZE_KB: i64 = 8192i64;
+
+/// Pragmas applied to every connection when it is opened by the pool
+#[derive(Clone, Debug)]
+pub struct ConnectionOptions {
+    /// Journal mode is stored in the database file, so it is only set by writers
+    pub journal_mode: String,
+    pub busy_timeout_ms: u64,
+    pub synchronous: String,
+    pub foreign_keys: bool,
+    /// Page cache size per connection in KiB
+    pub cache_size_kb: i64,
+    pub read_only: bool,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            journal_mode: String::from(DEFAULT_JOURNAL_MODE),
+            busy_timeout_ms: DEFAULT_BUSY_TIMEOUT_MS,
+            synchronous: String::from(DEFAULT_SYNCHRONOUS),
+            foreign_keys: DEFAULT_FOREIGN_KEYS,
+            cache_size_kb: DEFAULT_CACHE_SIZE_KB,
+            read_only: false,
+        }
+    }
+}
+
+impl ConnectionOptions {
+    pub fn read_only(&self) -> ConnectionOptions {
+        ConnectionOptions {
+            read_only: true,
+            ..self.clone()
+        }
+    }
+}
+
+impl CustomizeConnection<Connection, SqlError> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut Connection) -> Result<(), SqlError> {
+        conn.busy_timeout(Duration::from_millis(self.busy_timeout_ms))?;
+        if !self.read_only {
+            let journal_mode: String =
+                conn.pragma_update_and_check(None, "journal_mode", &self.journal_mode, |row| {
+                    row.get(0)
+                })?;
+            if !journal_mode.eq_ignore_ascii_case(&self.journal_mode) {
+                println!(
+                    "on_acquire journal_mode {:?} not applied, using {:?}",
+                    self.journal_mode, journal_mode
+                );
+            }
+        }
+        conn.pragma_update(None, "synchronous", &self.synchronous)?;
+        conn.pragma_update(None, "foreign_keys", self.foreign_keys)?;
+        // Negative values are in KiB instead of pages
+        conn.pragma_update(None, "cache_size", -self.cache_size_kb)?;
+        Ok(())
+    }
+}
@@ -1,2 +0,0 @@
-CREATE INDEX IF NOT EXISTS feeds_feed_at_idx
-ON feeds(feed_at DESC);
\ No newline at end of file
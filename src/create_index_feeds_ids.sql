@@ -1,2 +0,0 @@
-CREATE INDEX IF NOT EXISTS feeds_ids_idx
-ON feeds(feed_id, retweet_id);
\ No newline at end of file
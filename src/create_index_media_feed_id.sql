@@ -1,2 +0,0 @@
-CREATE INDEX IF NOT EXISTS media_feed_id_idx
-ON media(feed_id);
\ No newline at end of file
@@ -1,2 +0,0 @@
-CREATE UNIQUE INDEX IF NOT EXISTS media_ids_idx
-ON media(feed_id, media_id);
\ No newline at end of file
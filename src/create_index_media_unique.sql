@@ -1,2 +0,0 @@
-CREATE UNIQUE INDEX IF NOT EXISTS media_unique_idx
-ON media(feed_id, media_url);
\ No newline at end of file
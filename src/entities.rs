@@ -0,0 +1,116 @@
+use regex::Regex;
+use serde::Serialize;
+
+// Hashtags need a letter, `#2023` is not one, and do not start inside a word or url
+const HASHTAG_REGEX: &str = r"(?:^|[^\w&/])[#＃](\w*[^\W\d]\w*)";
+// Mentions do not start inside a word, so e-mail addresses are skipped
+const MENTION_REGEX: &str = r"(?:^|[^\w@])[@＠]([A-Za-z0-9_]{1,15})\b";
+const URL_REGEX: &str = r#"https?://[^\s<>"]+"#;
+// Punctuation ending a sentence after a url is not part of it
+const URL_TRAILING_CHARS: &[char] = &['.', ',', ';', ':', '!', '?', ')', '\'', '"', '…'];
+
+/// Hashtags, mentions and urls found in the contents of a feed
+#[derive(Serialize, Debug, Default)]
+pub struct Entities {
+    /// Lowercase, without `#`
+    pub hashtags: Vec<String>,
+    /// Lowercase, with `@` like `feeds.user_name`
+    pub mentions: Vec<String>,
+    pub urls: Vec<String>,
+}
+
+/// Compiled once per archive, scanning calls `extract` for every record
+pub struct EntityExtractor {
+    hashtag_re: Regex,
+    mention_re: Regex,
+    url_re: Regex,
+}
+
+impl EntityExtractor {
+    pub fn new() -> EntityExtractor {
+        EntityExtractor {
+            hashtag_re: Regex::new(HASHTAG_REGEX).unwrap(),
+            mention_re: Regex::new(MENTION_REGEX).unwrap(),
+            url_re: Regex::new(URL_REGEX).unwrap(),
+        }
+    }
+
+    pub fn extract(&self, contents: &str) -> Entities {
+        let mut entities = Entities::default();
+        for cap in self.hashtag_re.captures_iter(contents) {
+            push_unique(&mut entities.hashtags, normalize_hashtag(&cap[1]));
+        }
+        for cap in self.mention_re.captures_iter(contents) {
+            push_unique(&mut entities.mentions, normalize_mention(&cap[1]));
+        }
+        for url in self.url_re.find_iter(contents) {
+            let url = url.as_str().trim_end_matches(URL_TRAILING_CHARS);
+            if url.len() > "https://".len() {
+                push_unique(&mut entities.urls, url.to_owned());
+            }
+        }
+        entities
+    }
+}
+
+/// Host of a url in lowercase without `www.`, `https://www.Example.com:8080/a` is `example.com`
+pub fn url_domain(url: &str) -> Option<String> {
+    let (_scheme, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_user, host)| host);
+    let host = match host.rsplit_once(':') {
+        Some((host, port)) if port.chars().all(|c| c.is_ascii_digit()) => host,
+        _ => host,
+    };
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    match host.strip_prefix("www.").unwrap_or(&host) {
+        "" => None,
+        domain => Some(domain.to_owned()),
+    }
+}
+
+/// Id of a tweet url like `https://twitter.com/alice/status/123` or `https://x.com/i/web/status/123`
+pub fn url_status_id(url: &str) -> Option<i64> {
+    match url_domain(url)?.as_str() {
+        "twitter.com" | "mobile.twitter.com" | "x.com" => {}
+        _ => return None,
+    }
+    let (_scheme, rest) = url.split_once("://")?;
+    let mut segments = rest.split(['?', '#']).next()?.split('/');
+    segments.find(|segment| *segment == "status" || *segment == "statuses")?;
+    segments.next()?.parse().ok()
+}
+
+/// A domain filter may be given as a url or a host, `www.Example.com` is `example.com`
+pub fn normalize_domain(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.contains("://") {
+        url_domain(value)
+    } else {
+        url_domain(&format!("https://{}", value))
+    }
+}
+
+/// `#Rust` and `rust` are the same hashtag
+pub fn normalize_hashtag(value: &str) -> String {
+    value.trim().trim_start_matches(['#', '＃']).to_lowercase()
+}
+
+/// `@Alice` and `alice` are the same account
+pub fn normalize_mention(value: &str) -> String {
+    format!(
+        "@{}",
+        value
+            .trim()
+            .trim_start_matches(['@', '＠'])
+            .to_ascii_lowercase()
+    )
+}
+
+fn push_unique(values: &mut Vec<String>, value: String) {
+    if !values.contains(&value) {
+        values.push(value);
+    }
+}
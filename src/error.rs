@@ -0,0 +1,238 @@
+use std::fmt;
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use rusqlite::ErrorCode;
+use serde::Serialize;
+
+/// JSON body of every error response
+#[derive(Serialize, Debug)]
+pub struct AppError {
+    pub code: String,
+    pub message: String,
+}
+
+/// Errors returned by request handlers.
+///
+/// Handler specific failures carry their own stable `code`, e.g.
+/// `media_service_01`, shared failures use the fixed codes below.
+#[derive(Debug)]
+pub enum ApiError {
+    /// Invalid query, form or path parameter
+    BadRequest(&'static str, String),
+    /// Feed, media or archive entry does not exist
+    NotFound(&'static str, String),
+    /// The file exists but could not be read or decoded
+    Unprocessable(&'static str, String),
+    /// A background job or another request is holding a resource
+    Unavailable(&'static str, String),
+    /// Reading or writing a file failed
+    Io(&'static str, std::io::Error),
+    /// Database file is not accessible, or its schema is newer than supported
+    DatabaseUnavailable(String),
+    /// Database stayed locked past the busy timeout, or no pooled connection was free
+    DatabaseBusy(String),
+    Database(rusqlite::Error),
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(code, _)
+            | ApiError::NotFound(code, _)
+            | ApiError::Unprocessable(code, _)
+            | ApiError::Unavailable(code, _)
+            | ApiError::Io(code, _) => code,
+            ApiError::DatabaseUnavailable(_) => "database_01",
+            ApiError::DatabaseBusy(_) => "database_02",
+            ApiError::Database(_) => "database_03",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::BadRequest(_, message)
+            | ApiError::NotFound(_, message)
+            | ApiError::Unprocessable(_, message)
+            | ApiError::Unavailable(_, message)
+            | ApiError::DatabaseUnavailable(message)
+            | ApiError::DatabaseBusy(message) => write!(f, "{}", message),
+            ApiError::Io(_, err) => write!(f, "{}", err),
+            ApiError::Database(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(..) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(..) => StatusCode::NOT_FOUND,
+            ApiError::Unprocessable(..) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unavailable(..)
+            | ApiError::DatabaseUnavailable(_)
+            | ApiError::DatabaseBusy(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Io(..) | ApiError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(AppError {
+            code: String::from(self.code()),
+            message: self.to_string(),
+        })
+    }
+}
+
+impl From<rusqlite::Error> for ApiError {
+    fn from(err: rusqlite::Error) -> ApiError {
+        match err.sqlite_error_code() {
+            Some(ErrorCode::DatabaseBusy) | Some(ErrorCode::DatabaseLocked) => {
+                ApiError::DatabaseBusy(err.to_string())
+            }
+            _ => ApiError::Database(err),
+        }
+    }
+}
+
+impl From<r2d2::Error> for ApiError {
+    fn from(err: r2d2::Error) -> ApiError {
+        ApiError::DatabaseBusy(err.to_string())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::time::Duration;
+
+    use actix_web::dev::{Body, ResponseBody};
+    use r2d2_sqlite::SqliteConnectionManager;
+    use rusqlite::ffi;
+
+    use super::*;
+
+    /// Status and JSON body of the response of an error
+    pub fn error_response_json(err: &dyn ResponseError) -> (StatusCode, serde_json::Value) {
+        let response = err.error_response();
+        let json = match response.body() {
+            ResponseBody::Body(Body::Bytes(bytes)) | ResponseBody::Other(Body::Bytes(bytes)) => {
+                serde_json::from_slice(bytes).unwrap()
+            }
+            _ => panic!("not a json body"),
+        };
+        (response.status(), json)
+    }
+
+    fn assert_response(err: ApiError, status: StatusCode, code: &str, message: &str) {
+        let (response_status, json) = error_response_json(&err);
+        assert_eq!(response_status, status);
+        assert_eq!(json["code"], code);
+        assert_eq!(json["message"], message);
+    }
+
+    fn sqlite_failure(code: i32) -> rusqlite::Error {
+        rusqlite::Error::SqliteFailure(ffi::Error::new(code), Some(String::from("failed")))
+    }
+
+    #[test]
+    fn handler_errors_keep_their_code() {
+        assert_response(
+            ApiError::BadRequest("media_service_01", String::from("bad")),
+            StatusCode::BAD_REQUEST,
+            "media_service_01",
+            "bad",
+        );
+        assert_response(
+            ApiError::NotFound("zip_service_01", String::from("missing")),
+            StatusCode::NOT_FOUND,
+            "zip_service_01",
+            "missing",
+        );
+        assert_response(
+            ApiError::Unprocessable("media_file_service_02", String::from("broken")),
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "media_file_service_02",
+            "broken",
+        );
+        assert_response(
+            ApiError::Unavailable("db_restore_service_02", String::from("busy")),
+            StatusCode::SERVICE_UNAVAILABLE,
+            "db_restore_service_02",
+            "busy",
+        );
+        assert_response(
+            ApiError::Io("db_backup_02", std::io::Error::other("disk full")),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "db_backup_02",
+            "disk full",
+        );
+    }
+
+    #[test]
+    fn database_errors_use_fixed_codes() {
+        assert_response(
+            ApiError::DatabaseUnavailable(String::from("no file")),
+            StatusCode::SERVICE_UNAVAILABLE,
+            "database_01",
+            "no file",
+        );
+        assert_response(
+            ApiError::DatabaseBusy(String::from("locked")),
+            StatusCode::SERVICE_UNAVAILABLE,
+            "database_02",
+            "locked",
+        );
+        let (status, json) =
+            error_response_json(&ApiError::Database(rusqlite::Error::QueryReturnedNoRows));
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(json["code"], "database_03");
+    }
+
+    #[test]
+    fn busy_and_locked_sqlite_errors_are_busy() {
+        for code in [ffi::SQLITE_BUSY, ffi::SQLITE_LOCKED] {
+            let err = ApiError::from(sqlite_failure(code));
+            assert!(matches!(err, ApiError::DatabaseBusy(_)), "{:?}", err);
+            assert_eq!(err.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+        let err = ApiError::from(sqlite_failure(ffi::SQLITE_CONSTRAINT));
+        assert!(matches!(err, ApiError::Database(_)), "{:?}", err);
+        assert_eq!(err.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[test]
+    fn locked_database_is_busy() {
+        let path = std::env::temp_dir().join(format!("tmd-viewer-busy-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let holder = rusqlite::Connection::open(&path).unwrap();
+        holder
+            .execute_batch("CREATE TABLE t (v INTEGER); BEGIN EXCLUSIVE;")
+            .unwrap();
+        let writer = rusqlite::Connection::open(&path).unwrap();
+        writer.busy_timeout(Duration::from_millis(0)).unwrap();
+
+        let err = ApiError::from(writer.execute("INSERT INTO t VALUES (1)", []).unwrap_err());
+        assert!(matches!(err, ApiError::DatabaseBusy(_)), "{:?}", err);
+        assert_eq!(err.code(), "database_02");
+
+        drop(writer);
+        drop(holder);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn pool_timeout_is_busy() {
+        let pool = r2d2::Pool::builder()
+            .max_size(1)
+            .connection_timeout(Duration::from_millis(10))
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        let _held = pool.get().unwrap();
+        let err = ApiError::from(pool.get().unwrap_err());
+        assert!(matches!(err, ApiError::DatabaseBusy(_)), "{:?}", err);
+        let (status, json) = error_response_json(&err);
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(json["code"], "database_02");
+    }
+}
@@ -1,6 +1,14 @@
+mod connection_options;
+mod entities;
+mod error;
+mod media_info;
+mod migration;
+mod phash;
+mod search;
 mod server;
 #[cfg(target_os = "windows")]
 mod service;
+mod thumbnail_cache;
 use std::sync::{mpsc::channel, Arc, Mutex, RwLock};
 use std::thread;
 
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use image::DynamicImage;
+
+/// Most common colour of an image as `#rrggbb`.
+///
+/// Pixels are grouped into 4096 buckets (4 bits per channel) and the average
+/// of the biggest bucket is returned, which picks the background colour of
+/// most images rather than a muddy average of everything.
+pub fn dominant_color(img: &DynamicImage) -> String {
+    let rgb = img.to_rgb8();
+    let mut buckets: HashMap<u16, (u64, u64, u64, u64)> = HashMap::new();
+    for pixel in rgb.pixels() {
+        let [r, g, b] = pixel.0;
+        let key = (u16::from(r >> 4) << 8) | (u16::from(g >> 4) << 4) | u16::from(b >> 4);
+        let bucket = buckets.entry(key).or_insert((0, 0, 0, 0));
+        bucket.0 += 1;
+        bucket.1 += u64::from(r);
+        bucket.2 += u64::from(g);
+        bucket.3 += u64::from(b);
+    }
+    match buckets.values().max_by_key(|bucket| bucket.0) {
+        Some((count, r, g, b)) => format!("#{:02x}{:02x}{:02x}", r / count, g / count, b / count),
+        None => String::from("#000000"),
+    }
+}
+
+/// Dimensions and duration read from the boxes of an MP4/MOV file
+#[derive(Debug, Default, PartialEq)]
+pub struct VideoInfo {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub duration_ms: Option<i64>,
+}
+
+/// Read duration from `moov/mvhd`, and dimensions from the first `moov/trak/tkhd` with a size.
+///
+/// Returns `None` when the file is not an ISO base media file.
+pub fn video_info(blob: &[u8]) -> Option<VideoInfo> {
+    let moov = find_box(blob, b"moov")?;
+    let mut info = VideoInfo::default();
+
+    if let Some(mvhd) = find_box(moov, b"mvhd") {
+        // version(1) flags(3) then times, timescale and duration sized by version
+        let version = *mvhd.first()?;
+        let (timescale, duration) = if version == 1 {
+            (read_u32(mvhd, 20)? as u64, read_u64(mvhd, 24)?)
+        } else {
+            (read_u32(mvhd, 12)? as u64, read_u32(mvhd, 16)? as u64)
+        };
+        info.duration_ms = duration
+            .saturating_mul(1000)
+            .checked_div(timescale)
+            .map(|ms| ms as i64);
+    }
+
+    for trak in boxes(moov).filter(|(kind, _)| kind == b"trak") {
+        if let Some(tkhd) = find_box(trak.1, b"tkhd") {
+            // Width and height are 16.16 fixed point at the end of the box
+            let version = *tkhd.first()?;
+            let offset = if version == 1 { 88 } else { 76 };
+            let width = read_u32(tkhd, offset)? >> 16;
+            let height = read_u32(tkhd, offset + 4)? >> 16;
+            if width > 0 && height > 0 {
+                info.width = Some(width);
+                info.height = Some(height);
+                break;
+            }
+        }
+    }
+
+    Some(info)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        buf.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        buf.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+fn find_box<'a>(buf: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes(buf).find(|(k, _)| k == kind).map(|(_, body)| body)
+}
+
+/// Iterate over `(type, body)` of the boxes in `buf`, stopping at the first malformed one
+fn boxes(buf: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    let mut offset = 0usize;
+    std::iter::from_fn(move || {
+        let size = read_u32(buf, offset)? as u64;
+        let kind: [u8; 4] = buf.get(offset + 4..offset + 8)?.try_into().ok()?;
+        let (header, size) = match size {
+            // Size in the following 64 bits
+            1 => (16u64, read_u64(buf, offset + 8)?),
+            // Box extends to the end of the file
+            0 => (8u64, (buf.len() - offset) as u64),
+            _ => (8u64, size),
+        };
+        if size < header {
+            return None;
+        }
+        let start = offset + header as usize;
+        let end = offset.checked_add(size as usize)?;
+        let body = buf.get(start..end)?;
+        offset = end;
+        Some((kind, body))
+    })
+}
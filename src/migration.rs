@@ -0,0 +1,365 @@
+use std::fmt;
+
+use rusqlite::{functions::FunctionFlags, Connection, Error as SqlError};
+
+use crate::entities::{self, EntityExtractor};
+
+/// Schema migrations, `MIGRATIONS[n]` upgrades a database from `user_version` n to n + 1.
+///
+/// Never edit a released script, append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    include_str!("migrations/0001_baseline.sql"),
+    include_str!("migrations/0002_media_details.sql"),
+    include_str!("migrations/0003_feed_files.sql"),
+    include_str!("migrations/0004_retweets.sql"),
+    include_str!("migrations/0005_file_sources.sql"),
+    include_str!("migrations/0006_collections.sql"),
+    include_str!("migrations/0007_tags.sql"),
+    include_str!("migrations/0008_notes.sql"),
+    include_str!("migrations/0009_saved_searches.sql"),
+    include_str!("migrations/0010_entities.sql"),
+    include_str!("migrations/0011_link_domains.sql"),
+    include_str!("migrations/0012_feed_counts.sql"),
+    include_str!("migrations/0013_muted_users.sql"),
+    include_str!("migrations/0014_link_status_ids.sql"),
+    include_str!("migrations/0015_saved_search_orders.sql"),
+    include_str!("migrations/0016_evicted_thumbnails.sql"),
+];
+
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The database was written by a newer version of tmd-viewer
+    NewerVersion(i64),
+    Sql(SqlError),
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::NewerVersion(version) => write!(
+                f,
+                "database schema version {} is newer than supported version {}",
+                version, SCHEMA_VERSION
+            ),
+            MigrationError::Sql(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<SqlError> for MigrationError {
+    fn from(err: SqlError) -> MigrationError {
+        MigrationError::Sql(err)
+    }
+}
+
+pub fn schema_version(conn: &Connection) -> Result<i64, SqlError> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+/// Bring the database up to `SCHEMA_VERSION`, returning the version it started from.
+///
+/// Each script runs in its own transaction together with the version bump,
+/// so a failed upgrade leaves the database at the last good version.
+pub fn migrate(conn: &mut Connection) -> Result<i64, MigrationError> {
+    run_migrations(conn, MIGRATIONS)
+}
+
+fn run_migrations(conn: &mut Connection, migrations: &[&str]) -> Result<i64, MigrationError> {
+    let from_version = schema_version(conn)?;
+    register_functions(conn)?;
+    if from_version > migrations.len() as i64 {
+        return Err(MigrationError::NewerVersion(from_version));
+    }
+    for (index, sql) in migrations.iter().enumerate().skip(from_version as usize) {
+        let version = index as i64 + 1;
+        println!("migrate to schema version {}", version);
+        let txn = conn.transaction()?;
+        txn.execute_batch(sql)?;
+        txn.pragma_update(None, "user_version", version)?;
+        txn.commit()?;
+    }
+    Ok(from_version)
+}
+
+/// Functions scripts use to fill new columns from existing rows the same way a scan would
+fn register_functions(conn: &Connection) -> Result<(), SqlError> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    conn.create_scalar_function("url_domain", 1, flags, |ctx| {
+        Ok(entities::url_domain(&ctx.get::<String>(0)?))
+    })?;
+    conn.create_scalar_function("url_status_id", 1, flags, |ctx| {
+        Ok(entities::url_status_id(&ctx.get::<String>(0)?))
+    })?;
+    // Entities of a feed as json, `{"hashtags": [..], "mentions": [..], "urls": [..]}`
+    let extractor = EntityExtractor::new();
+    conn.create_scalar_function("contents_entities", 1, flags, move |ctx| {
+        let contents: Option<String> = ctx.get(0)?;
+        let entities = extractor.extract(contents.as_deref().unwrap_or(""));
+        serde_json::to_string(&entities).map_err(|err| SqlError::UserFunctionError(Box::new(err)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database of the first release, before versioning, with a tweet, its media and a retweet
+    fn baseline_fixture() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO files (file_path, scan_started_at, scan_ended_at) \
+            VALUES ('alice.zip', 1, 2); \
+            INSERT INTO feeds (feed_id, user_name, retweet_id, retweet_user_name, feed_at, \
+                twitter_url, contents) \
+            VALUES (100, '@alice', 0, '', 1000, 'https://twitter.com/alice/status/100', 'hello'), \
+                (200, '@bob', 0, '', 2000, 'https://twitter.com/bob/status/200', 'hi'), \
+                (0, '@alice', 200, '@bob', 3000, 'https://twitter.com/bob/status/200', 'hi'); \
+            INSERT INTO media (feed_id, media_id, media_type, media_url, file_path, media_path) \
+            VALUES (100, 1, 'Image', 'https://pbs.twimg.com/media/1.png', 'alice.zip', '1.png');",
+        )
+        .unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 0);
+        conn
+    }
+
+    #[test]
+    fn baseline_is_upgraded_to_the_latest_version() {
+        let mut conn = baseline_fixture();
+        assert_eq!(migrate(&mut conn).unwrap(), 0);
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        let feed_count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM feeds", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(feed_count, 2);
+        let media_count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM media", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(media_count, 1);
+        // Archives already read are not read again
+        let unscanned_count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM files WHERE scan_ended_at IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(unscanned_count, 0);
+
+        // Nothing left to do the second time
+        assert_eq!(migrate(&mut conn).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn retweets_move_out_of_feeds() {
+        let mut conn = baseline_fixture();
+        run_migrations(&mut conn, &MIGRATIONS[..3]).unwrap();
+        conn.execute(
+            "INSERT INTO feed_files (feed_id, user_name, retweet_id, retweet_user_name, file_path) \
+            VALUES (0, '@alice', 200, '@bob', 'alice.zip')",
+            [],
+        )
+        .unwrap();
+        run_migrations(&mut conn, &MIGRATIONS[..4]).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), 4);
+
+        let retweet: (String, i64, String, i64) = conn
+            .query_row(
+                "SELECT user_name, feed_id, feed_user_name, retweet_at FROM retweets",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            retweet,
+            (String::from("@alice"), 200, String::from("@bob"), 3000)
+        );
+        let retweet_feed_count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM feeds WHERE retweet_id != 0",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(retweet_feed_count, 0);
+
+        // The feed_files key of the retweet still finds it
+        let linked_count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM feed_files ff \
+                INNER JOIN retweets rt \
+                ON rt.user_name = ff.user_name AND rt.feed_id = ff.retweet_id \
+                AND rt.feed_user_name = ff.retweet_user_name \
+                WHERE ff.feed_id = 0",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(linked_count, 1);
+    }
+
+    #[test]
+    fn entities_are_filled_in_without_a_rescan() {
+        let mut conn = baseline_fixture();
+        run_migrations(&mut conn, &MIGRATIONS[..9]).unwrap();
+        conn.execute_batch(
+            "UPDATE feeds SET contents = '#Rust ＃rust @Bob https://example.com/a' \
+            WHERE feed_id = 100;",
+        )
+        .unwrap();
+        run_migrations(&mut conn, &MIGRATIONS[..10]).unwrap();
+
+        let entities: (String, String, String) = conn
+            .query_row(
+                "SELECT (SELECT group_concat(hashtag) FROM hashtags WHERE feed_id = 100), \
+                (SELECT group_concat(user_name) FROM mentions WHERE feed_id = 100), \
+                (SELECT group_concat(url) FROM links WHERE feed_id = 100)",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            entities,
+            (
+                String::from("rust"),
+                String::from("@bob"),
+                String::from("https://example.com/a")
+            )
+        );
+        let unscanned_count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM files WHERE scan_ended_at IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(unscanned_count, 0);
+    }
+
+    #[test]
+    fn link_domains_are_filled_in_without_a_rescan() {
+        let mut conn = baseline_fixture();
+        run_migrations(&mut conn, &MIGRATIONS[..10]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO links (feed_id, url) \
+            VALUES (100, 'https://www.Example.com:8080/a'), (100, 'https:///nohost');",
+        )
+        .unwrap();
+        run_migrations(&mut conn, &MIGRATIONS[..11]).unwrap();
+
+        let links: Vec<(String, String)> = conn
+            .prepare("SELECT url, domain FROM links ORDER BY url")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(
+            links,
+            vec![(
+                String::from("https://www.Example.com:8080/a"),
+                String::from("example.com")
+            )]
+        );
+        let unscanned_count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM files WHERE scan_ended_at IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(unscanned_count, 0);
+    }
+
+    #[test]
+    fn link_status_ids_are_filled_in() {
+        let mut conn = baseline_fixture();
+        run_migrations(&mut conn, &MIGRATIONS[..13]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO links (feed_id, url, domain) \
+            VALUES (100, 'https://twitter.com/bob/status/200?s=20', 'twitter.com'), \
+                (100, 'https://x.com/i/web/status/2000', 'x.com'), \
+                (100, 'https://example.com/status/200', 'example.com');",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+
+        let status_ids: Vec<Option<i64>> = conn
+            .prepare("SELECT status_id FROM links ORDER BY url")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        // Only twitter urls are tweets, and ids are matched in full
+        assert_eq!(status_ids, vec![None, Some(200), Some(2000)]);
+    }
+
+    #[test]
+    fn saved_search_sorts_move_into_queries() {
+        let mut conn = baseline_fixture();
+        run_migrations(&mut conn, &MIGRATIONS[..14]).unwrap();
+        conn.execute_batch(
+            "INSERT INTO saved_searches (name, query, sort) \
+            VALUES ('newest', '{\"q\":\"rust\",\"order\":null}', 'newest'), \
+                ('oldest', '{\"q\":\"rust\",\"order\":null}', 'oldest'), \
+                ('explicit', '{\"q\":\"rust\",\"order\":\"desc\"}', 'oldest');",
+        )
+        .unwrap();
+        migrate(&mut conn).unwrap();
+
+        let orders: Vec<(String, Option<String>)> = conn
+            .prepare(
+                "SELECT name, json_extract(query, '$.order') FROM saved_searches ORDER BY name",
+            )
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        assert_eq!(
+            orders,
+            vec![
+                (String::from("explicit"), Some(String::from("desc"))),
+                (String::from("newest"), None),
+                (String::from("oldest"), Some(String::from("asc"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn failed_script_is_rolled_back() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let migrations = [
+            "CREATE TABLE first (value INTEGER);",
+            "CREATE TABLE second (value INTEGER); INSERT INTO missing VALUES (1);",
+        ];
+        assert!(matches!(
+            run_migrations(&mut conn, &migrations),
+            Err(MigrationError::Sql(_))
+        ));
+        assert_eq!(schema_version(&conn).unwrap(), 1);
+        let second_count: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE name = 'second'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(second_count, 0);
+    }
+
+    #[test]
+    fn newer_database_is_refused() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(MigrationError::NewerVersion(version)) if version == SCHEMA_VERSION + 1
+        ));
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION + 1);
+    }
+}
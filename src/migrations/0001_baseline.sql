@@ -0,0 +1,57 @@
+-- Schema of the first release, kept IF NOT EXISTS so unversioned databases are adopted
+
+CREATE TABLE IF NOT EXISTS files (
+    file_path TEXT NOT NULL,
+    added_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s','now') AS INTEGER)),
+    scan_started_at INTEGER,
+    scan_ended_at INTEGER,
+    PRIMARY KEY (file_path)
+);
+
+CREATE TABLE IF NOT EXISTS feeds (
+    feed_id INTEGER NOT NULL,
+    user_name INTEGER NOT NULL,
+    retweet_id INTEGER NOT NULL,
+    retweet_user_name TEXT NOT NULL,
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s','now') AS INTEGER)),
+    feed_at INTEGER NOT NULL,
+    twitter_url TEXT NOT NULL,
+    contents TEXT,
+    reply_to_feed_id INTEGER,
+    reply_to_user_name TEXT,
+    UNIQUE (feed_id, user_name, retweet_id, retweet_user_name)
+    PRIMARY KEY (feed_id, user_name, retweet_id, retweet_user_name)
+);
+
+CREATE TABLE IF NOT EXISTS media (
+    feed_id INTEGER NOT NULL,
+    media_id INTEGER NOT NULL,
+    media_type TEXT NOT NULL, -- Image/Video/Audio
+    media_url TEXT NOT NULL,
+    file_path TEXT NOT NULL, -- path to zip
+    media_path TEXT NOT NULL, -- path inside zip
+    thumbnail BLOB, -- png thumbnail
+    deleted_at INTEGER,
+    FOREIGN KEY (file_path) REFERENCES files (file_path),
+    UNIQUE (feed_id, media_url),
+    PRIMARY KEY (feed_id, media_id)
+);
+
+CREATE INDEX IF NOT EXISTS feeds_ids_idx
+ON feeds(feed_id, retweet_id);
+
+CREATE INDEX IF NOT EXISTS feeds_ids_un_idx
+ON feeds(feed_id, retweet_id, user_name);
+
+CREATE INDEX IF NOT EXISTS feeds_feed_at_idx
+ON feeds(feed_at DESC);
+
+CREATE INDEX IF NOT EXISTS media_feed_id_idx
+ON media(feed_id);
+
+CREATE UNIQUE INDEX IF NOT EXISTS media_ids_idx
+ON media(feed_id, media_id);
+
+CREATE UNIQUE INDEX IF NOT EXISTS media_unique_idx
+ON media(feed_id, media_url);
+
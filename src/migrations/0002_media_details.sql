@@ -0,0 +1,48 @@
+-- Thumbnail cache, perceptual hashes, metadata and verification results
+
+CREATE TABLE IF NOT EXISTS thumbnails (
+    feed_id INTEGER NOT NULL,
+    media_id INTEGER NOT NULL,
+    thumbnail_hash TEXT NOT NULL, -- sha256 of jpeg thumbnail, file name in thumbnail cache dir
+    thumbnail_size INTEGER NOT NULL,
+    accessed_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s','now') AS INTEGER)),
+    PRIMARY KEY (feed_id, media_id)
+);
+
+CREATE INDEX IF NOT EXISTS thumbnails_hash_idx
+ON thumbnails(thumbnail_hash);
+
+CREATE INDEX IF NOT EXISTS thumbnails_accessed_at_idx
+ON thumbnails(accessed_at);
+
+CREATE TABLE IF NOT EXISTS media_hashes (
+    feed_id INTEGER NOT NULL,
+    media_id INTEGER NOT NULL,
+    phash INTEGER NOT NULL, -- 64-bit dHash of the image
+    PRIMARY KEY (feed_id, media_id)
+);
+
+CREATE INDEX IF NOT EXISTS media_hashes_phash_idx
+ON media_hashes(phash);
+
+CREATE TABLE IF NOT EXISTS media_metadata (
+    feed_id INTEGER NOT NULL,
+    media_id INTEGER NOT NULL,
+    width INTEGER,
+    height INTEGER,
+    byte_size INTEGER NOT NULL, -- size of the original file
+    file_format TEXT, -- jpeg/png/gif/webp/mp4
+    duration_ms INTEGER, -- video only
+    dominant_color TEXT, -- #rrggbb, image only
+    PRIMARY KEY (feed_id, media_id)
+);
+
+CREATE TABLE IF NOT EXISTS media_checks (
+    feed_id INTEGER NOT NULL,
+    media_id INTEGER NOT NULL,
+    checked_at INTEGER NOT NULL,
+    error_code TEXT, -- NULL when the file was readable
+    error_message TEXT,
+    PRIMARY KEY (feed_id, media_id)
+);
+
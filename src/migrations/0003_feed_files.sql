@@ -0,0 +1,21 @@
+-- Archives each feed was read from, a feed can be in several exports
+
+CREATE TABLE IF NOT EXISTS feed_files (
+    feed_id INTEGER NOT NULL,
+    user_name TEXT NOT NULL,
+    retweet_id INTEGER NOT NULL,
+    retweet_user_name TEXT NOT NULL,
+    file_path TEXT NOT NULL, -- path to zip
+    PRIMARY KEY (feed_id, user_name, retweet_id, retweet_user_name, file_path)
+);
+
+CREATE INDEX IF NOT EXISTS feed_files_file_path_idx
+ON feed_files(file_path);
+
+-- Feeds scanned before are only known by the archive of their media until rescanned
+INSERT OR IGNORE INTO feed_files
+SELECT DISTINCT f.feed_id, f.user_name, f.retweet_id, f.retweet_user_name, m.file_path
+FROM feeds f
+INNER JOIN media m
+ON m.feed_id = f.feed_id
+WHERE f.retweet_id = 0;
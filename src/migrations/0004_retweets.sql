@@ -0,0 +1,25 @@
+-- Retweets by the account of an export, linked to the original feed by its full key
+
+CREATE TABLE IF NOT EXISTS retweets (
+    user_name TEXT NOT NULL, -- retweeting account
+    feed_id INTEGER NOT NULL, -- original feed, in feeds when it was archived
+    feed_user_name TEXT NOT NULL, -- author of the original feed
+    retweet_at INTEGER NOT NULL,
+    twitter_url TEXT NOT NULL, -- url of the original feed
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s','now') AS INTEGER)),
+    PRIMARY KEY (user_name, feed_id, feed_user_name)
+);
+
+CREATE INDEX IF NOT EXISTS retweets_retweet_at_idx
+ON retweets(retweet_at DESC);
+
+CREATE INDEX IF NOT EXISTS retweets_feed_idx
+ON retweets(feed_id, feed_user_name);
+
+-- Retweets used to be feeds rows with feed_id 0, their feed_files rows keep that key
+INSERT OR IGNORE INTO retweets (user_name, feed_id, feed_user_name, retweet_at, twitter_url, created_at)
+SELECT user_name, retweet_id, retweet_user_name, feed_at, twitter_url, created_at
+FROM feeds
+WHERE retweet_id != 0;
+
+DELETE FROM feeds WHERE retweet_id != 0;
@@ -0,0 +1,21 @@
+-- Account and kind of each export, from the third line of its csv
+--
+-- Only the csv files have them, archives read before stay NULL until they are read again with
+-- `POST /a/scan` and `rescan=true`, _Read all archives again_ on Settings.
+-- The rescan is left to the user, reading every archive again can take a long time.
+
+ALTER TABLE files ADD COLUMN origin TEXT; -- @name the export was taken from
+ALTER TABLE files ADD COLUMN export_kind TEXT; -- timeline/likes/media/search
+
+CREATE INDEX IF NOT EXISTS files_origin_idx
+ON files(origin, export_kind);
+
+-- 0 when the feed is only in the archive as the original of a retweet or like
+ALTER TABLE feed_files ADD COLUMN is_listed INTEGER NOT NULL DEFAULT 1;
+
+UPDATE feed_files SET is_listed = 0
+WHERE retweet_id = 0 AND EXISTS (
+    SELECT r.file_path FROM feed_files r
+    WHERE r.file_path = feed_files.file_path AND r.feed_id = 0
+    AND r.retweet_id = feed_files.feed_id AND r.retweet_user_name = feed_files.user_name
+);
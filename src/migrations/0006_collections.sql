@@ -0,0 +1,22 @@
+-- Likes and bookmarks lists, filled from the archive they were tagged on
+
+CREATE TABLE IF NOT EXISTS collections (
+    collection_id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    kind TEXT NOT NULL, -- likes/bookmarks
+    file_path TEXT NOT NULL, -- path to zip
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s','now') AS INTEGER)),
+    UNIQUE (file_path)
+);
+
+CREATE TABLE IF NOT EXISTS collection_feeds (
+    collection_id INTEGER NOT NULL,
+    feed_id INTEGER NOT NULL,
+    user_name TEXT NOT NULL, -- author of the feed
+    twitter_url TEXT NOT NULL,
+    collected_at INTEGER, -- action time from the csv, NULL when the export has none
+    PRIMARY KEY (collection_id, feed_id, user_name)
+);
+
+CREATE INDEX IF NOT EXISTS collection_feeds_collected_at_idx
+ON collection_feeds(collection_id, collected_at DESC);
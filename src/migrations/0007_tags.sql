@@ -0,0 +1,19 @@
+-- User tags and stars, keyed by feed id so they survive rescans and purges
+
+CREATE TABLE IF NOT EXISTS tags (
+    feed_id INTEGER NOT NULL,
+    media_id INTEGER NOT NULL DEFAULT 0, -- 0 for the feed itself
+    tag TEXT NOT NULL COLLATE NOCASE,
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s','now') AS INTEGER)),
+    PRIMARY KEY (feed_id, media_id, tag)
+);
+
+CREATE INDEX IF NOT EXISTS tags_tag_idx
+ON tags(tag);
+
+CREATE TABLE IF NOT EXISTS stars (
+    feed_id INTEGER NOT NULL,
+    media_id INTEGER NOT NULL DEFAULT 0, -- 0 for the feed itself
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s','now') AS INTEGER)),
+    PRIMARY KEY (feed_id, media_id)
+);
@@ -0,0 +1,17 @@
+-- Feeds queries saved by name, run again from the feeds page
+
+CREATE TABLE IF NOT EXISTS saved_searches (
+    saved_search_id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    query TEXT NOT NULL, -- FeedsQuery as json, without page and count
+    sort TEXT NOT NULL DEFAULT 'newest', -- newest/oldest
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s','now') AS INTEGER)),
+    used_at INTEGER, -- last run, NULL when never run
+    UNIQUE (name)
+);
+
+CREATE INDEX IF NOT EXISTS feeds_created_at_idx
+ON feeds(created_at);
+
+CREATE INDEX IF NOT EXISTS retweets_created_at_idx
+ON retweets(created_at);
@@ -0,0 +1,40 @@
+-- Hashtags, mentions and urls found in the contents of feeds
+
+CREATE TABLE IF NOT EXISTS hashtags (
+    feed_id INTEGER NOT NULL,
+    hashtag TEXT NOT NULL, -- lowercase, without #
+    PRIMARY KEY (feed_id, hashtag)
+);
+
+CREATE INDEX IF NOT EXISTS hashtags_hashtag_idx
+ON hashtags(hashtag, feed_id);
+
+CREATE TABLE IF NOT EXISTS mentions (
+    feed_id INTEGER NOT NULL,
+    user_name TEXT NOT NULL, -- lowercase, with @
+    PRIMARY KEY (feed_id, user_name)
+);
+
+CREATE INDEX IF NOT EXISTS mentions_user_name_idx
+ON mentions(user_name, feed_id);
+
+CREATE TABLE IF NOT EXISTS links (
+    feed_id INTEGER NOT NULL,
+    url TEXT NOT NULL,
+    PRIMARY KEY (feed_id, url)
+);
+
+-- Picked out of the contents already read, the same way a scan does
+CREATE TEMP TABLE feed_entities AS
+SELECT feed_id, contents_entities(contents) AS entities FROM feeds;
+
+INSERT OR IGNORE INTO hashtags (feed_id, hashtag)
+SELECT fe.feed_id, e.value FROM temp.feed_entities fe, json_each(fe.entities, '$.hashtags') e;
+
+INSERT OR IGNORE INTO mentions (feed_id, user_name)
+SELECT fe.feed_id, e.value FROM temp.feed_entities fe, json_each(fe.entities, '$.mentions') e;
+
+INSERT OR IGNORE INTO links (feed_id, url)
+SELECT fe.feed_id, e.value FROM temp.feed_entities fe, json_each(fe.entities, '$.urls') e;
+
+DROP TABLE temp.feed_entities;
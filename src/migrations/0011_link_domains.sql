@@ -0,0 +1,11 @@
+-- Domain of each url, filled in from the urls already read
+
+ALTER TABLE links ADD COLUMN domain TEXT NOT NULL DEFAULT ''; -- lowercase host without www.
+
+UPDATE links SET domain = IFNULL(url_domain(url), '');
+
+-- Scans only keep urls with a host
+DELETE FROM links WHERE domain = '';
+
+CREATE INDEX IF NOT EXISTS links_domain_idx
+ON links(domain, feed_id);
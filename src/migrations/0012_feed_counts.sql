@@ -0,0 +1,9 @@
+-- Reply, retweet and like counts of the export
+--
+-- Only the csv files have the counts, feeds read before stay NULL until their archive is
+-- read again with `POST /a/scan` and `rescan=true`, _Read all archives again_ on Settings.
+-- The rescan is left to the user, reading every archive again can take a long time.
+
+ALTER TABLE feeds ADD COLUMN reply_count INTEGER;
+ALTER TABLE feeds ADD COLUMN retweet_count INTEGER;
+ALTER TABLE feeds ADD COLUMN like_count INTEGER;
@@ -0,0 +1,7 @@
+-- Accounts left out of the feeds unless asked for
+
+CREATE TABLE IF NOT EXISTS muted_users (
+    user_name TEXT NOT NULL, -- lowercase, with @
+    created_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s','now') AS INTEGER)),
+    PRIMARY KEY (user_name)
+);
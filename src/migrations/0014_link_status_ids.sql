@@ -0,0 +1,8 @@
+-- Tweet id of twitter urls, to find the tweets quoting a tweet
+
+ALTER TABLE links ADD COLUMN status_id INTEGER; -- NULL unless the url is a tweet
+
+UPDATE links SET status_id = url_status_id(url);
+
+CREATE INDEX IF NOT EXISTS links_status_id_idx
+ON links(status_id, feed_id);
@@ -0,0 +1,6 @@
+-- The order of a saved search is kept in its query like any other /a/feeds parameter
+
+UPDATE saved_searches SET query = json_set(query, '$.order', 'asc')
+WHERE sort = 'oldest' AND json_extract(query, '$.order') IS NULL;
+
+ALTER TABLE saved_searches DROP COLUMN sort;
@@ -0,0 +1,8 @@
+-- Media whose thumbnail was evicted from the thumbnail cache, generated again only on demand
+
+CREATE TABLE IF NOT EXISTS evicted_thumbnails (
+    feed_id INTEGER NOT NULL,
+    media_id INTEGER NOT NULL,
+    evicted_at INTEGER NOT NULL DEFAULT (CAST(strftime('%s','now') AS INTEGER)),
+    PRIMARY KEY (feed_id, media_id)
+);
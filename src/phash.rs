@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use image::{imageops::FilterType, DynamicImage};
+
+/// Largest distance accepted when looking for near-identical images,
+/// anything above this matches unrelated images.
+pub const MAX_DISTANCE: u32 = 10u32;
+
+/// Difference hash (dHash) of an image.
+///
+/// The image is shrunk to 9x8 grayscale and each bit is set when a pixel is
+/// brighter than its right neighbour, so re-encoded or resized copies of the
+/// same image end up within a few bits of each other.
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let small = img
+        .resize_exact(9u32, 8u32, FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8u32 {
+        for x in 0..8u32 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Group hashes that are within `max_distance` bits of each other.
+///
+/// Returns clusters of indices into `hashes`, only clusters with more than one
+/// member are returned. Two hashes within `max_distance` bits must have at
+/// least one of `max_distance + 1` bit ranges in common, so only hashes
+/// sharing a range are compared.
+pub fn clusters(hashes: &[u64], max_distance: u32) -> Vec<Vec<usize>> {
+    let max_distance = max_distance.min(MAX_DISTANCE);
+    let mut parents: Vec<usize> = (0..hashes.len()).collect();
+
+    let chunk_count = max_distance + 1;
+    for chunk in 0..chunk_count {
+        let start = 64 * chunk / chunk_count;
+        let end = 64 * (chunk + 1) / chunk_count;
+        let mask = if end - start >= 64 {
+            u64::MAX
+        } else {
+            ((1u64 << (end - start)) - 1) << start
+        };
+        let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+        for (index, hash) in hashes.iter().enumerate() {
+            buckets.entry(hash & mask).or_default().push(index);
+        }
+        for bucket in buckets.values() {
+            for (i, a) in bucket.iter().enumerate() {
+                for b in bucket[i + 1..].iter() {
+                    if hamming_distance(hashes[*a], hashes[*b]) <= max_distance {
+                        union(&mut parents, *a, *b);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for index in 0..hashes.len() {
+        let root = find(&mut parents, index);
+        groups.entry(root).or_default().push(index);
+    }
+    let mut result: Vec<Vec<usize>> = groups.into_values().filter(|g| g.len() > 1).collect();
+    // Biggest clusters first, then in input order
+    result.sort_by(|a, b| b.len().cmp(&a.len()).then(a[0].cmp(&b[0])));
+    result
+}
+
+fn find(parents: &mut [usize], index: usize) -> usize {
+    let mut root = index;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    // Path compression
+    let mut current = index;
+    while parents[current] != root {
+        let next = parents[current];
+        parents[current] = root;
+        current = next;
+    }
+    root
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let root_a = find(parents, a);
+    let root_b = find(parents, b);
+    if root_a != root_b {
+        parents[root_b.max(root_a)] = root_a.min(root_b);
+    }
+}
@@ -0,0 +1,612 @@
+use std::fmt;
+
+use rusqlite::{types::Value as SqlValue, ToSql};
+
+use crate::entities;
+
+/// Terms and operators accepted in one query, deeper or longer queries are rejected
+/// before any SQL is built
+const MAX_TOKENS: usize = 64usize;
+
+/// WHERE clauses over the timeline `f` and their parameters.
+///
+/// Values are always bound as parameters, only fixed SQL and generated
+/// parameter names go into the clauses.
+#[derive(Debug, Default)]
+pub struct SqlFilter {
+    clauses: Vec<String>,
+    params: Vec<(String, SqlValue)>,
+}
+
+impl SqlFilter {
+    /// Binds a value and returns its parameter name for a clause
+    pub fn bind<V: Into<SqlValue>>(&mut self, value: V) -> String {
+        let name = format!(":p{}", self.params.len());
+        self.params.push((name.clone(), value.into()));
+        name
+    }
+
+    /// Binds a value under a fixed name, for clauses shared with other queries
+    pub fn bind_named<V: Into<SqlValue>>(&mut self, name: &str, value: V) {
+        self.params.push((name.to_owned(), value.into()));
+    }
+
+    pub fn push(&mut self, clause: String) {
+        self.clauses.push(clause);
+    }
+
+    pub fn where_clause(&self) -> String {
+        if self.clauses.is_empty() {
+            String::from("")
+        } else {
+            format!("WHERE {}", self.clauses.join(" AND "))
+        }
+    }
+
+    pub fn params(&self) -> Vec<(&str, &dyn ToSql)> {
+        self.params
+            .iter()
+            .map(|(name, value)| (name.as_str(), value as &dyn ToSql))
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct SearchError(String);
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Parsed `q`, terms next to each other are and-ed
+#[derive(Debug, PartialEq)]
+pub enum Expr {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Term(Term),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Term {
+    /// A word or "quoted phrase" in the contents or notes of a feed
+    Text(String),
+    Hashtag(String),
+    Mention(String),
+    /// Author of the feed, also of the original of a retweet
+    From(String),
+    /// Replies, which start with the mention of the account replied to
+    To(String),
+    Since(String),
+    Until(String),
+    Has(String),
+    IsRetweet,
+    MinFaves(u32),
+    MinRetweets(u32),
+    MinReplies(u32),
+    /// Part of a linked url
+    Url(String),
+}
+
+const HAS_VALUES: [&str; 6] = ["media", "images", "videos", "links", "mentions", "hashtags"];
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Or,
+    Minus,
+    Word(String),
+    Phrase(String),
+}
+
+fn tokenize(input: &str) -> Vec<Token> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(&ch) = chars.peek() {
+        match ch {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                // An unterminated phrase runs to the end
+                let phrase: String = chars.by_ref().take_while(|c| *c != '"').collect();
+                tokens.push(Token::Phrase(phrase));
+            }
+            '-' => {
+                chars.next();
+                match chars.peek() {
+                    Some(c) if !c.is_whitespace() && *c != ')' => tokens.push(Token::Minus),
+                    _ => tokens.push(Token::Word(String::from("-"))),
+                }
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                if word == "OR" {
+                    tokens.push(Token::Or);
+                } else {
+                    tokens.push(Token::Word(word));
+                }
+            }
+        }
+    }
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, SearchError> {
+        let mut items = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            items.push(self.parse_and()?);
+        }
+        Ok(if items.len() == 1 {
+            items.remove(0)
+        } else {
+            Expr::Or(items)
+        })
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, SearchError> {
+        let mut items: Vec<Expr> = Vec::new();
+        while !matches!(self.peek(), None | Some(Token::Close) | Some(Token::Or)) {
+            items.push(self.parse_unary()?);
+        }
+        match items.len() {
+            0 => Err(SearchError(String::from(
+                "OR and ( ) need a search term on each side",
+            ))),
+            1 => Ok(items.remove(0)),
+            _ => Ok(Expr::And(items)),
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, SearchError> {
+        match self.next() {
+            Some(Token::Minus) => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Open) => {
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(expr),
+                    _ => Err(SearchError(String::from("missing )"))),
+                }
+            }
+            Some(Token::Phrase(phrase)) => Ok(Expr::Term(Term::Text(phrase.clone()))),
+            Some(Token::Word(word)) => {
+                let word = word.clone();
+                Ok(Expr::Term(parse_term(&word)?))
+            }
+            _ => Err(SearchError(String::from("unexpected OR or )"))),
+        }
+    }
+}
+
+fn parse_term(word: &str) -> Result<Term, SearchError> {
+    // A lone `#` or `@` is searched as text
+    if word.starts_with(['#', '＃']) {
+        let hashtag = entities::normalize_hashtag(word);
+        if !hashtag.is_empty() {
+            return Ok(Term::Hashtag(hashtag));
+        }
+    }
+    if word.starts_with(['@', '＠']) {
+        let user_name = entities::normalize_mention(word);
+        if user_name != "@" {
+            return Ok(Term::Mention(user_name));
+        }
+    }
+    let (operator, value) = match word.split_once(':') {
+        Some((operator, value)) => (operator.to_ascii_lowercase(), value),
+        None => return Ok(Term::Text(word.to_owned())),
+    };
+    let is_operator = [
+        "from",
+        "to",
+        "since",
+        "until",
+        "has",
+        "is",
+        "min_faves",
+        "min_retweets",
+        "min_replies",
+        "url",
+    ]
+    .contains(&operator.as_str());
+    if !is_operator {
+        // Times and urls are searched as text
+        return Ok(Term::Text(word.to_owned()));
+    }
+    if value.is_empty() {
+        return Err(SearchError(format!("{}: needs a value", operator)));
+    }
+    let count = || {
+        value
+            .parse::<u32>()
+            .map_err(|_err| SearchError(format!("{}: must be a number", operator)))
+    };
+    match operator.as_str() {
+        "from" => Ok(Term::From(entities::normalize_mention(value))),
+        "to" => Ok(Term::To(entities::normalize_mention(value))),
+        "since" => Ok(Term::Since(value.to_owned())),
+        "until" => Ok(Term::Until(value.to_owned())),
+        "has" => {
+            let value = value.to_ascii_lowercase();
+            let value = match value.as_str() {
+                "image" => String::from("images"),
+                "video" => String::from("videos"),
+                "link" => String::from("links"),
+                _ => value,
+            };
+            if HAS_VALUES.contains(&value.as_str()) {
+                Ok(Term::Has(value))
+            } else {
+                Err(SearchError(format!(
+                    "has: must be one of {}",
+                    HAS_VALUES.join(", ")
+                )))
+            }
+        }
+        "is" => match value.to_ascii_lowercase().as_str() {
+            "retweet" => Ok(Term::IsRetweet),
+            _ => Err(SearchError(String::from("is: must be retweet"))),
+        },
+        "min_faves" => Ok(Term::MinFaves(count()?)),
+        "min_retweets" => Ok(Term::MinRetweets(count()?)),
+        "min_replies" => Ok(Term::MinReplies(count()?)),
+        _ => Ok(Term::Url(value.to_owned())),
+    }
+}
+
+/// Parses a Twitter-like advanced search, `None` when there is nothing to search
+pub fn parse(input: &str) -> Result<Option<Expr>, SearchError> {
+    let tokens = tokenize(input);
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+    if tokens.len() > MAX_TOKENS {
+        return Err(SearchError(format!(
+            "search has more than {} terms",
+            MAX_TOKENS
+        )));
+    }
+    let mut parser = Parser { tokens, pos: 0 };
+    let expr = parser.parse_or()?;
+    match parser.peek() {
+        None => Ok(Some(expr)),
+        Some(_) => Err(SearchError(String::from("unexpected )"))),
+    }
+}
+
+/// Builds the clause of a parsed search, binding its values to `filter`.
+///
+/// `parse_time` reads `since:` and `until:` values, the flag is set for until.
+pub fn build_clause(
+    expr: &Expr,
+    filter: &mut SqlFilter,
+    parse_time: &dyn Fn(&str, bool) -> Option<i64>,
+) -> Result<String, SearchError> {
+    match expr {
+        Expr::And(items) | Expr::Or(items) => {
+            let clauses = items
+                .iter()
+                .map(|item| build_clause(item, filter, parse_time))
+                .collect::<Result<Vec<String>, SearchError>>()?;
+            let separator = match expr {
+                Expr::And(_) => " AND ",
+                _ => " OR ",
+            };
+            Ok(format!("({})", clauses.join(separator)))
+        }
+        // Unknown (NULL) counts or contents are not a match, so `-x` picks every row `x` does not
+        Expr::Not(item) => Ok(format!(
+            "NOT IFNULL({}, 0)",
+            build_clause(item, filter, parse_time)?
+        )),
+        Expr::Term(term) => build_term(term, filter, parse_time),
+    }
+}
+
+fn build_term(
+    term: &Term,
+    filter: &mut SqlFilter,
+    parse_time: &dyn Fn(&str, bool) -> Option<i64>,
+) -> Result<String, SearchError> {
+    let clause = match term {
+        Term::Text(text) => {
+            let p = filter.bind(like_pattern(text));
+            format!(
+                "(f.contents LIKE {p} ESCAPE '\\' OR EXISTS (SELECT n.feed_id FROM notes n \
+                WHERE n.feed_id = f.feed_id AND n.body LIKE {p} ESCAPE '\\'))",
+                p = p
+            )
+        }
+        Term::Hashtag(hashtag) => format!(
+            "EXISTS (SELECT h.feed_id FROM hashtags h WHERE h.feed_id = f.feed_id \
+            AND h.hashtag = {})",
+            filter.bind(hashtag.clone())
+        ),
+        Term::Mention(user_name) => format!(
+            "EXISTS (SELECT me.feed_id FROM mentions me WHERE me.feed_id = f.feed_id \
+            AND me.user_name = {})",
+            filter.bind(user_name.clone())
+        ),
+        Term::From(user_name) => format!(
+            "lower(f.author_name) = {}",
+            filter.bind(user_name.clone())
+        ),
+        Term::To(user_name) => format!(
+            "(lower(f.contents) = {p} OR substr(lower(f.contents), 1, length({p}) + 1) = {p} || ' ')",
+            p = filter.bind(user_name.clone())
+        ),
+        Term::Since(value) | Term::Until(value) => {
+            let is_until = matches!(term, Term::Until(_));
+            let timestamp = parse_time(value, is_until).ok_or_else(|| {
+                SearchError(format!(
+                    "{}: must be a date like 2021-03-01",
+                    if is_until { "until" } else { "since" }
+                ))
+            })?;
+            format!(
+                "f.feed_at {} {}",
+                if is_until { "<" } else { ">=" },
+                filter.bind(timestamp)
+            )
+        }
+        Term::Has(value) => String::from(match value.as_str() {
+            "media" => "EXISTS (SELECT m.feed_id FROM media m WHERE m.feed_id = f.feed_id)",
+            "images" => {
+                "EXISTS (SELECT m.feed_id FROM media m WHERE m.feed_id = f.feed_id \
+                AND m.media_type = 'Image')"
+            }
+            "videos" => {
+                "EXISTS (SELECT m.feed_id FROM media m WHERE m.feed_id = f.feed_id \
+                AND m.media_type = 'Video')"
+            }
+            "links" => "EXISTS (SELECT l.feed_id FROM links l WHERE l.feed_id = f.feed_id)",
+            "mentions" => {
+                "EXISTS (SELECT me.feed_id FROM mentions me WHERE me.feed_id = f.feed_id)"
+            }
+            _ => "EXISTS (SELECT h.feed_id FROM hashtags h WHERE h.feed_id = f.feed_id)",
+        }),
+        Term::IsRetweet => String::from("f.is_retweet = 1"),
+        Term::MinFaves(count) => format!("f.like_count >= {}", filter.bind(*count)),
+        Term::MinRetweets(count) => format!("f.retweet_count >= {}", filter.bind(*count)),
+        Term::MinReplies(count) => format!("f.reply_count >= {}", filter.bind(*count)),
+        Term::Url(value) => format!(
+            "EXISTS (SELECT l.feed_id FROM links l WHERE l.feed_id = f.feed_id \
+            AND l.url LIKE {} ESCAPE '\\')",
+            filter.bind(like_pattern(value))
+        ),
+    };
+    Ok(clause)
+}
+
+/// `%value%` with LIKE wildcards in the value escaped by `\`
+pub fn like_pattern(value: &str) -> String {
+    let mut pattern = String::with_capacity(value.len() + 2);
+    pattern.push('%');
+    for ch in value.chars() {
+        if ch == '%' || ch == '_' || ch == '\\' {
+            pattern.push('\\');
+        }
+        pattern.push(ch);
+    }
+    pattern.push('%');
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    fn text(value: &str) -> Expr {
+        Expr::Term(Term::Text(String::from(value)))
+    }
+
+    fn parse_error(input: &str) -> String {
+        parse(input).unwrap_err().to_string()
+    }
+
+    fn no_time(_value: &str, _is_until: bool) -> Option<i64> {
+        None
+    }
+
+    #[test]
+    fn tokenize_splits_words_phrases_and_operators() {
+        assert_eq!(
+            tokenize(r#"-(rust OR "release notes") a-b - x"#),
+            vec![
+                Token::Minus,
+                Token::Open,
+                Token::Word(String::from("rust")),
+                Token::Or,
+                Token::Phrase(String::from("release notes")),
+                Token::Close,
+                Token::Word(String::from("a-b")),
+                Token::Word(String::from("-")),
+                Token::Word(String::from("x")),
+            ]
+        );
+        // Only upper case OR is an operator, an unterminated phrase runs to the end
+        assert_eq!(
+            tokenize(r#"or "open phrase"#),
+            vec![
+                Token::Word(String::from("or")),
+                Token::Phrase(String::from("open phrase")),
+            ]
+        );
+        assert!(tokenize("  ").is_empty());
+    }
+
+    #[test]
+    fn parse_or_binds_looser_than_and() {
+        assert_eq!(parse("").unwrap(), None);
+        assert_eq!(
+            parse("a b OR c").unwrap(),
+            Some(Expr::Or(vec![
+                Expr::And(vec![text("a"), text("b")]),
+                text("c")
+            ]))
+        );
+        assert_eq!(
+            parse("a (b OR c)").unwrap(),
+            Some(Expr::And(vec![
+                text("a"),
+                Expr::Or(vec![text("b"), text("c")])
+            ]))
+        );
+        assert_eq!(
+            parse(r#"-"two words" --x"#).unwrap(),
+            Some(Expr::And(vec![
+                Expr::Not(Box::new(text("two words"))),
+                Expr::Not(Box::new(Expr::Not(Box::new(text("x"))))),
+            ]))
+        );
+    }
+
+    #[test]
+    fn parse_reads_operators() {
+        assert_eq!(
+            parse("#Rust ＠Alice FROM:bob has:image is:retweet min_faves:10 url:example.com")
+                .unwrap(),
+            Some(Expr::And(vec![
+                Expr::Term(Term::Hashtag(String::from("rust"))),
+                Expr::Term(Term::Mention(String::from("@alice"))),
+                Expr::Term(Term::From(String::from("@bob"))),
+                Expr::Term(Term::Has(String::from("images"))),
+                Expr::Term(Term::IsRetweet),
+                Expr::Term(Term::MinFaves(10)),
+                Expr::Term(Term::Url(String::from("example.com"))),
+            ]))
+        );
+        // Unknown operators, times and urls are text
+        assert_eq!(parse("lang:ja").unwrap(), Some(text("lang:ja")));
+        assert_eq!(parse("12:30").unwrap(), Some(text("12:30")));
+        // A lone sign is text, also full-width ones that are more than one byte
+        assert_eq!(
+            parse("＃ ＠ # @").unwrap(),
+            Some(Expr::And(vec![
+                text("＃"),
+                text("＠"),
+                text("#"),
+                text("@")
+            ]))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_bad_input() {
+        assert_eq!(parse_error("(a b"), "missing )");
+        assert_eq!(parse_error("a b)"), "unexpected )");
+        assert_eq!(
+            parse_error("a OR"),
+            "OR and ( ) need a search term on each side"
+        );
+        assert_eq!(
+            parse_error("()"),
+            "OR and ( ) need a search term on each side"
+        );
+        assert_eq!(parse_error("from:"), "from: needs a value");
+        assert_eq!(parse_error("min_faves:many"), "min_faves: must be a number");
+        assert!(parse_error("has:sound").starts_with("has: must be one of"));
+        assert_eq!(parse_error("is:reply"), "is: must be retweet");
+        assert_eq!(
+            parse_error(&"a ".repeat(MAX_TOKENS + 1)),
+            format!("search has more than {} terms", MAX_TOKENS)
+        );
+    }
+
+    #[test]
+    fn build_binds_every_value() {
+        let expr = parse(r#"a_b OR -from:Alice"#).unwrap().unwrap();
+        let mut filter = SqlFilter::default();
+        let clause = build_clause(&expr, &mut filter, &no_time).unwrap();
+        assert_eq!(
+            clause,
+            "((f.contents LIKE :p0 ESCAPE '\\' OR EXISTS (SELECT n.feed_id FROM notes n \
+            WHERE n.feed_id = f.feed_id AND n.body LIKE :p0 ESCAPE '\\')) \
+            OR NOT IFNULL(lower(f.author_name) = :p1, 0))"
+        );
+        let params: Vec<&str> = filter.params().iter().map(|(name, _)| *name).collect();
+        assert_eq!(params, vec![":p0", ":p1"]);
+        assert_eq!(like_pattern("a_b%"), "%a\\_b\\%%");
+
+        let expr = parse("since:someday").unwrap().unwrap();
+        assert_eq!(
+            build_clause(&expr, &mut filter, &no_time)
+                .unwrap_err()
+                .to_string(),
+            "since: must be a date like 2021-03-01"
+        );
+    }
+
+    #[test]
+    fn negated_terms_match_rows_with_unknown_values() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE f (feed_id INTEGER, contents TEXT, like_count INTEGER); \
+            CREATE TABLE notes (feed_id INTEGER, body TEXT); \
+            INSERT INTO f VALUES (1, 'rust', 20), (2, 'go', 5), (3, NULL, NULL);",
+        )
+        .unwrap();
+        let count = |input: &str| -> i64 {
+            let expr = parse(input).unwrap().unwrap();
+            let mut filter = SqlFilter::default();
+            let clause = build_clause(&expr, &mut filter, &no_time).unwrap();
+            conn.query_row(
+                &format!("SELECT COUNT(*) FROM f WHERE {}", clause),
+                &filter.params()[..],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        for input in [
+            "min_faves:10",
+            "rust",
+            "rust min_faves:10",
+            "rust OR min_faves:10",
+        ] {
+            assert_eq!(
+                count(input) + count(&format!("-({})", input)),
+                3,
+                "{}",
+                input
+            );
+        }
+        assert_eq!(count("-min_faves:10"), 2);
+    }
+}
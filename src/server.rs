@@ -1,22 +1,27 @@
-use std::borrow::Cow;
 use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fs;
 use std::fs::File;
 use std::io::{Cursor, Read};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{mpsc::Sender, Arc, Mutex, RwLock};
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use actix_files::file_extension_to_mime;
 use actix_web::{
-    dev::Server, get, http::header::CONTENT_TYPE, middleware, post, web, web::Bytes, App,
-    HttpResponse, HttpServer, Responder,
+    delete,
+    dev::Server,
+    error::{QueryPayloadError, UrlencodedError},
+    get,
+    http::header::CONTENT_TYPE,
+    middleware, post, put, web,
+    web::Bytes,
+    App, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use actix_web_static_files::{Resource, ResourceFiles};
 use base64::engine::Engine;
-use chrono::{offset::FixedOffset, NaiveDateTime, TimeZone};
+use chrono::{offset::FixedOffset, NaiveDate, NaiveDateTime, TimeZone, Utc};
 use csv::{Error as CsvError, ReaderBuilder as CsvReaderBuilder};
 use image::{io::Reader as ImageReader, ImageOutputFormat};
 use mime::{Mime, IMAGE_JPEG, TEXT_HTML};
@@ -24,21 +29,115 @@ use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
 use regex::Regex;
 use rusqlite::{
-    named_params, params, types::Value as SqlValue, Result as SqlResult, Statement, ToSql,
-    Transaction,
+    backup::Backup, functions::FunctionFlags, named_params, params, types::Value as SqlValue,
+    Connection, OpenFlags, Result as SqlResult, Statement, ToSql, Transaction,
 };
 use serde::{Deserialize, Serialize, Serializer};
 use serde_yaml;
 use zip::ZipArchive;
 
+use crate::connection_options::ConnectionOptions;
+use crate::entities::{self, Entities, EntityExtractor};
+use crate::error::ApiError;
+use crate::media_info;
+use crate::migration;
+use crate::phash;
+use crate::search::{self, SqlFilter};
+use crate::thumbnail_cache::ThumbnailCache;
+
 const CONFIG_FILENAME: &str = "tmd-viewer.yaml";
 const DATABASE_FILENAME: &str = "tmd-viewer.db";
+const BACKUP_FILENAME_PREFIX: &str = "tmd-viewer-backup-";
+const BACKUP_FILENAME_SUFFIX: &str = ".db";
+// Copy everything in one step, a backup in several steps restarts whenever a scan writes
+const BACKUP_PAGES_PER_STEP: i32 = i32::MAX;
 const DEFAULT_DATA_DIR: &str = ".";
 const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:8888";
 const DEFAULT_TIME_OFFSET_HOUR: f32 = 0.0f32; // UTC
 const DEFAULT_SCANNER_COUNT_LIMIT: i32 = 2i32;
+const DEFAULT_THUMBNAIL_CACHE_SIZE_LIMIT_MB: u64 = 1024u64;
+const THUMBNAIL_TOUCH_FLUSH_SECS: u64 = 60u64;
+const ONE_MB_U64: u64 = 1024u64 * 1024u64;
 const DEFAULT_PAGE: i32 = 0i32;
 const DEFAULT_PAGE_COUNT: i32 = 100i32;
+const DEFAULT_SIMILAR_DISTANCE: u32 = 4u32;
+const MEDIA_METADATA_COLUMNS: &str =
+    "mm.width, mm.height, mm.byte_size, mm.file_format, mm.duration_ms, mm.dominant_color";
+// Thumbnails are either in the database or in the thumbnail cache
+const HAS_THUMBNAIL_SQL: &str = "(m.thumbnail IS NOT NULL OR EXISTS (SELECT t.feed_id \
+    FROM thumbnails t WHERE t.feed_id = m.feed_id AND t.media_id = m.media_id))";
+const HAS_NO_THUMBNAIL_SQL: &str = "NOT (m.thumbnail IS NOT NULL OR EXISTS (SELECT t.feed_id \
+    FROM thumbnails t WHERE t.feed_id = m.feed_id AND t.media_id = m.media_id))";
+// Feeds and retweets as one timeline, `user_name` and `feed_at` are the retweeter's for retweets,
+// the original of a retweet is joined on its full key and its columns are NULL when not archived.
+// `key_*` and `user_name` are the row's key in feed_files.
+// Rows only read from archives tagged as collections are likes or bookmarks, not timeline.
+const TIMELINE_SQL: &str = "SELECT \
+    t.feed_id, t.user_name, t.feed_at, t.twitter_url, t.contents, \
+    0 AS is_retweet, t.user_name AS author_name, t.feed_at AS original_at, \
+    t.feed_id AS key_feed_id, 0 AS key_retweet_id, '' AS key_retweet_user_name, t.created_at, \
+    t.reply_count, t.retweet_count, t.like_count \
+    FROM feeds t \
+    WHERE t.retweet_id = 0 \
+    AND (NOT EXISTS (SELECT ff.file_path FROM feed_files ff \
+        INNER JOIN collections c ON c.file_path = ff.file_path \
+        WHERE ff.feed_id = t.feed_id AND ff.user_name = t.user_name \
+        AND ff.retweet_id = 0 AND ff.retweet_user_name = '') \
+    OR EXISTS (SELECT ff.file_path FROM feed_files ff \
+        WHERE ff.feed_id = t.feed_id AND ff.user_name = t.user_name \
+        AND ff.retweet_id = 0 AND ff.retweet_user_name = '' \
+        AND ff.file_path NOT IN (SELECT c.file_path FROM collections c))) \
+    UNION ALL \
+    SELECT \
+    rt.feed_id, rt.user_name, rt.retweet_at, rt.twitter_url, o.contents, \
+    1 AS is_retweet, rt.feed_user_name AS author_name, o.feed_at AS original_at, \
+    0 AS key_feed_id, rt.feed_id AS key_retweet_id, rt.feed_user_name AS key_retweet_user_name, \
+    rt.created_at, o.reply_count, o.retweet_count, o.like_count \
+    FROM retweets rt \
+    LEFT JOIN feeds o \
+    ON o.feed_id = rt.feed_id AND o.user_name = rt.feed_user_name \
+    AND o.retweet_id = 0 AND o.retweet_user_name = '' \
+    WHERE (NOT EXISTS (SELECT ff.file_path FROM feed_files ff \
+        INNER JOIN collections c ON c.file_path = ff.file_path \
+        WHERE ff.feed_id = 0 AND ff.user_name = rt.user_name \
+        AND ff.retweet_id = rt.feed_id AND ff.retweet_user_name = rt.feed_user_name) \
+    OR EXISTS (SELECT ff.file_path FROM feed_files ff \
+        WHERE ff.feed_id = 0 AND ff.user_name = rt.user_name \
+        AND ff.retweet_id = rt.feed_id AND ff.retweet_user_name = rt.feed_user_name \
+        AND ff.file_path NOT IN (SELECT c.file_path FROM collections c)))";
+// Columns read by `row_saved_search`
+const SAVED_SEARCH_SQL: &str = "SELECT \
+    saved_search_id, name, query, created_at, used_at \
+    FROM saved_searches";
+// Columns read by `row_collection`, callers add WHERE and GROUP BY
+const COLLECTION_SQL: &str = "SELECT \
+    c.collection_id, c.name, c.kind, c.file_path, c.created_at, \
+    COUNT(cf.feed_id), MAX(cf.collected_at) \
+    FROM collections c \
+    LEFT JOIN collection_feeds cf \
+    ON cf.collection_id = c.collection_id";
+// Entries of the archives tagged as collections, likes carry their action time as retweets do
+const FILL_COLLECTIONS_SQL: &str = "INSERT OR IGNORE INTO collection_feeds \
+    (collection_id, feed_id, user_name, twitter_url, collected_at) \
+    SELECT c.collection_id, rt.feed_id, rt.feed_user_name, rt.twitter_url, rt.retweet_at \
+    FROM collections c \
+    INNER JOIN feed_files ff \
+    ON ff.file_path = c.file_path AND ff.feed_id = 0 \
+    INNER JOIN retweets rt \
+    ON rt.user_name = ff.user_name AND rt.feed_id = ff.retweet_id \
+    AND rt.feed_user_name = ff.retweet_user_name \
+    WHERE c.file_path = :file_path \
+    UNION ALL \
+    SELECT c.collection_id, t.feed_id, t.user_name, t.twitter_url, NULL \
+    FROM collections c \
+    INNER JOIN feed_files ff \
+    ON ff.file_path = c.file_path AND ff.retweet_id = 0 AND ff.is_listed = 1 \
+    INNER JOIN feeds t \
+    ON t.feed_id = ff.feed_id AND t.user_name = ff.user_name \
+    AND t.retweet_id = 0 AND t.retweet_user_name = '' \
+    WHERE c.file_path = :file_path";
+const COLLECTION_KINDS: [&str; 2] = ["likes", "bookmarks"];
+const MAX_TAG_LENGTH: usize = 100usize;
 const ONE_HOUR_I32: i32 = 3600i32;
 const TWITTER_URL_REGEX: &str =
     r"^https?://(?:(?:mobile)\.)?twitter\.com/([a-zA-Z0-9_]+)/status/([0-9]+)";
@@ -52,11 +151,29 @@ struct AppState {
     config_path: RwLock<PathBuf>,
     data_dir: RwLock<String>,
     bind_address: RwLock<String>,
+    /// Writers, and readers that have to see their own writes
     pool: RwLock<Option<Pool<SqliteConnectionManager>>>,
+    /// Read-only connections for browsing, never blocked by a scan in WAL mode
+    read_pool: RwLock<Option<Pool<SqliteConnectionManager>>>,
+    connection_options: ConnectionOptions,
     is_scanning: RwLock<bool>,
+    is_verifying: RwLock<bool>,
+    is_maintaining: RwLock<bool>,
     scanner_count: RwLock<i32>,
     scanner_count_limit: i32,
     time_offset: f32,
+    thumbnail_cache: Option<ThumbnailCache>,
+    /// Bumped whenever media or their hashes change, see media_changed
+    media_version: RwLock<u64>,
+    /// Clusters of the last /a/media/duplicates request
+    duplicate_clusters: RwLock<Option<DuplicateClusters>>,
+}
+
+/// Clustering every hash is too slow to do for each page, it is kept until media change
+struct DuplicateClusters {
+    media_version: u64,
+    max_distance: u32,
+    clusters: Arc<Vec<MediaCluster>>,
 }
 
 #[derive(Serialize)]
@@ -67,12 +184,8 @@ struct AppStateExternal {
     is_scanning: bool,
     scanner_count: i32,
     scanner_count_limit: i32,
-}
-
-#[derive(Serialize)]
-struct AppError {
-    code: String,
-    message: String,
+    thumbnail_cache_dir: Option<String>,
+    thumbnail_cache_size_limit: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
@@ -81,6 +194,23 @@ struct AppConfig {
     bind_address: Option<String>,
     time_offset: Option<f32>,
     scanner_count_limit: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thumbnail_cache_dir: Option<String>,
+    /// In megabytes, 0 for unlimited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    thumbnail_cache_size_limit: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    database_journal_mode: Option<String>,
+    /// In milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    database_busy_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    database_synchronous: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    database_foreign_keys: Option<bool>,
+    /// In kilobytes per connection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    database_cache_size: Option<i64>,
 }
 
 #[derive(Deserialize)]
@@ -88,6 +218,119 @@ struct SetDataDirForm {
     data_dir: Option<String>,
 }
 
+#[derive(Serialize, Debug)]
+struct DatabaseBackup {
+    name: String,
+    size: u64,
+    created_at: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct DatabaseBackupsResponse {
+    backups: Vec<DatabaseBackup>,
+}
+
+#[derive(Serialize, Debug)]
+struct DatabaseRestoreResponse {
+    restored: String,
+    /// Copy of the database as it was before the restore
+    previous: Option<DatabaseBackup>,
+}
+
+#[derive(Deserialize)]
+struct DatabaseRestoreForm {
+    name: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PurgeForm {
+    /// Archive (zip file name) the feeds were read from
+    file_path: Option<String>,
+    user_name: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    /// Keep feeds and media, only remove their thumbnails
+    thumbnails_only: Option<bool>,
+    /// Report what would be removed without removing it
+    dry_run: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+struct PurgeResponse {
+    dry_run: bool,
+    feed_count: usize,
+    retweet_count: usize,
+    media_count: usize,
+    thumbnail_count: usize,
+    file_count: usize,
+    /// Entries of likes and bookmarks collections left without their archive
+    collection_feed_count: usize,
+    collection_count: usize,
+    /// Archives sharing purged feeds, their media is read again on the next scan
+    rescan_files: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct StatsResponse {
+    schema_version: i64,
+    feed_count: i64,
+    retweet_count: i64,
+    user_count: i64,
+    archive_count: i64,
+    archive_scanned_count: i64,
+    first_feed_at: Option<i64>,
+    last_feed_at: Option<i64>,
+    media_count_by_type: HashMap<String, i64>,
+    /// Images with a thumbnail in the database or in the thumbnail cache
+    thumbnail_count: i64,
+    /// Images that have not been through generate_thumbnails yet
+    thumbnail_missing_count: i64,
+    /// Media that could not be read, see /a/verify
+    media_deleted_count: i64,
+    /// Size of the database file and its write-ahead log in bytes
+    database_size: u64,
+    database_wal_size: u64,
+    /// Size of the pages not in use, reclaimed by VACUUM
+    database_free_size: i64,
+    is_maintaining: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct HistogramQuery {
+    /// day (default), week, month, hour or weekday
+    bucket: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct HistogramBucket {
+    /// `2021-03-10` for day and week (its monday), `2021-03` for month, `0` to `23` for hour
+    /// and `0` (sunday) to `6` for weekday
+    key: String,
+    /// Start of a day, week or month bucket
+    start_at: Option<i64>,
+    feed_count: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct HistogramResponse {
+    bucket: String,
+    query: FeedsQuery,
+    buckets: Vec<HistogramBucket>,
+}
+
+#[derive(Deserialize, Debug)]
+struct ScanForm {
+    /// Read every archive again, also the ones already scanned
+    rescan: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DatabaseMaintenanceForm {
+    vacuum: Option<bool>,
+    analyze: Option<bool>,
+    optimize: Option<bool>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct FeedCsvRecord {
     feed_date: String,
@@ -105,7 +348,26 @@ struct FeedCsvRecord {
     like_count: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Counts of a feed when it was exported, empty or unreadable counts are NULL
+#[derive(Debug)]
+struct FeedCounts {
+    reply_count: Option<i64>,
+    retweet_count: Option<i64>,
+    like_count: Option<i64>,
+}
+
+/// Primary key of a feeds or retweets row read from an archive, see `feed_files`
+#[derive(Debug)]
+struct FeedFile {
+    feed_id: i64,
+    user_name: String,
+    retweet_id: i64,
+    retweet_user_name: String,
+    /// False for the original of a retweet or like, which the export does not list by itself
+    is_listed: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 struct Media {
     #[serde(serialize_with = "format_string")]
     feed_id: i64,
@@ -122,9 +384,179 @@ struct Media {
     thumbnail: Option<Vec<u8>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     deleted_at: Option<i64>,
+    #[serde(flatten)]
+    metadata: Option<MediaMetadata>,
+}
+
+/// Properties of the media file, extracted on thumbnail generation
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct MediaMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<u32>,
+    byte_size: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dominant_color: Option<String>,
+}
+
+/// Media record for the gallery, without the thumbnail blob
+#[derive(Serialize, Deserialize, Debug)]
+struct MediaItem {
+    #[serde(serialize_with = "format_string")]
+    feed_id: i64,
+    #[serde(serialize_with = "format_string")]
+    media_id: i64,
+    media_type: String,
+    feed_at: i64,
+    user_name: String,
+    twitter_url: String,
+    file_path: String,
+    has_thumbnail: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<i64>,
+    #[serde(flatten)]
+    metadata: Option<MediaMetadata>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MediaResponse {
+    query: MediaQuery,
+    media: Vec<MediaItem>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MediaQuery {
+    user_name: Option<String>,
+    media_type: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    /// Archive (zip file name) the media is in
+    file_path: Option<String>,
+    has_thumbnail: Option<bool>,
+    /// portrait, landscape or square
+    orientation: Option<String>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    page: Option<i32>,
+    count: Option<i32>,
+}
+
+/// Why a media file could not be read, as recorded in `media_checks`
+#[derive(Debug)]
+enum MediaCheckError {
+    MissingArchive,
+    UnreadableArchive(String),
+    MissingEntry,
+    NotAFile,
+    UnreadableEntry(String),
+    Undecodable(String),
+}
+
+impl MediaCheckError {
+    fn from_entry_error(err: zip::result::ZipError) -> MediaCheckError {
+        match err {
+            zip::result::ZipError::FileNotFound => MediaCheckError::MissingEntry,
+            err => MediaCheckError::UnreadableEntry(err.to_string()),
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            MediaCheckError::MissingArchive => "missing_archive",
+            MediaCheckError::UnreadableArchive(_) => "unreadable_archive",
+            MediaCheckError::MissingEntry => "missing_entry",
+            MediaCheckError::NotAFile => "not_a_file",
+            MediaCheckError::UnreadableEntry(_) => "unreadable_entry",
+            MediaCheckError::Undecodable(_) => "undecodable",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            MediaCheckError::MissingArchive => String::from("Archive file does not exist"),
+            MediaCheckError::MissingEntry => String::from("Entry does not exist in archive"),
+            MediaCheckError::NotAFile => String::from("Entry is not a file"),
+            MediaCheckError::UnreadableArchive(message)
+            | MediaCheckError::UnreadableEntry(message)
+            | MediaCheckError::Undecodable(message) => message.clone(),
+        }
+    }
+}
+
+impl From<MediaCheckError> for ApiError {
+    fn from(err: MediaCheckError) -> ApiError {
+        match err {
+            MediaCheckError::MissingArchive
+            | MediaCheckError::MissingEntry
+            | MediaCheckError::NotAFile => ApiError::NotFound(err.code(), err.message()),
+            _ => ApiError::Unprocessable(err.code(), err.message()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct MediaCheck {
+    #[serde(serialize_with = "format_string")]
+    feed_id: i64,
+    #[serde(serialize_with = "format_string")]
+    media_id: i64,
+    file_path: String,
+    media_path: String,
+    checked_at: i64,
+    error_code: String,
+    error_message: String,
+}
+
+#[derive(Serialize, Debug)]
+struct VerifyResponse {
+    is_verifying: bool,
+    checked_count: i64,
+    ok_count: i64,
+    failed_count: i64,
+    last_checked_at: Option<i64>,
+    failed_count_by_code: HashMap<String, i64>,
+    missing_archives: Vec<String>,
+    failures: Vec<MediaCheck>,
+}
+
+#[derive(Deserialize, Debug)]
+struct VerifyQuery {
+    page: Option<i32>,
+    count: Option<i32>,
+}
+
+struct Thumbnail {
+    blob: Vec<u8>,
+    phash: u64,
+    metadata: MediaMetadata,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct MediaCluster {
+    #[serde(serialize_with = "format_hex")]
+    phash: i64,
+    media: Vec<Media>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+struct DuplicatesResponse {
+    query: DuplicatesQuery,
+    clusters: Vec<MediaCluster>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct DuplicatesQuery {
+    max_distance: Option<u32>,
+    page: Option<i32>,
+    count: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(untagged)]
 enum FeedType {
     Feed {
@@ -136,6 +568,8 @@ enum FeedType {
         contents: String,
         #[serde(skip_serializing_if = "Option::is_none")]
         media: Option<Vec<Media>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        notes: Option<Vec<Note>>,
     },
     Retweet {
         retweet_at: i64,
@@ -143,28 +577,358 @@ enum FeedType {
         #[serde(serialize_with = "format_string")]
         retweet_id: i64,
         retweet_user_name: String,
+        /// Url of the original feed
+        twitter_url: String,
         #[serde(skip_serializing_if = "Option::is_none")]
         retweet: Option<Box<FeedType>>,
     },
 }
 
+#[derive(Serialize, Debug)]
+struct Source {
+    origin: String,
+    export_kind: Option<String>,
+    file_count: i64,
+    /// Feeds, retweets and likes listed by the exports
+    feed_count: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct SourcesResponse {
+    sources: Vec<Source>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
-struct FeedsResponse {
+struct HashtagsQuery {
+    user_name: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    count: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+struct HashtagCount {
+    hashtag: String,
+    feed_count: i64,
+    last_feed_at: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct HashtagsResponse {
+    query: HashtagsQuery,
+    hashtags: Vec<HashtagCount>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct LinksQuery {
+    /// Links to this domain or its subdomains
+    domain: Option<String>,
+    user_name: Option<String>,
+    since: Option<String>,
+    until: Option<String>,
+    count: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+struct DomainCount {
+    domain: String,
+    url_count: i64,
+    feed_count: i64,
+    last_feed_at: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct LinkCount {
+    url: String,
+    domain: String,
+    feed_count: i64,
+    last_feed_at: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct LinksResponse {
+    query: LinksQuery,
+    /// Most linked domains
+    domains: Vec<DomainCount>,
+    /// Most linked urls
+    links: Vec<LinkCount>,
+}
+
+#[derive(Serialize, Debug)]
+struct SavedSearch {
+    saved_search_id: i64,
+    name: String,
+    /// Filters and order of /a/feeds, without page and count
+    query: FeedsQuery,
+    created_at: i64,
+    /// Last time the search was marked as viewed
+    used_at: Option<i64>,
+    /// Feeds added to the database since `used_at`, or since `created_at` until then
+    new_count: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct SavedSearchesResponse {
+    saved_searches: Vec<SavedSearch>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SavedSearchForm {
+    name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SavedSearchFeedsQuery {
+    page: Option<i32>,
+    count: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+struct SavedSearchFeedsResponse {
+    saved_search: SavedSearch,
     query: FeedsQuery,
     feeds: Vec<FeedType>,
 }
 
+#[derive(Serialize, Debug)]
+struct Collection {
+    collection_id: i64,
+    name: String,
+    kind: String,
+    file_path: String,
+    created_at: i64,
+    feed_count: i64,
+    last_collected_at: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+struct CollectionsResponse {
+    collections: Vec<Collection>,
+}
+
+#[derive(Deserialize, Debug)]
+struct CollectionForm {
+    /// Archive (zip file name) holding the likes or bookmarks
+    file_path: Option<String>,
+    /// likes or bookmarks
+    kind: Option<String>,
+    name: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CollectionFeedsQuery {
+    page: Option<i32>,
+    count: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+struct CollectionFeed {
+    /// When the feed was liked or bookmarked
+    collected_at: Option<i64>,
+    #[serde(serialize_with = "format_string")]
+    feed_id: i64,
+    user_name: String,
+    twitter_url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    feed: Option<FeedType>,
+}
+
+#[derive(Serialize, Debug)]
+struct CollectionFeedsResponse {
+    query: CollectionFeedsQuery,
+    collection: Collection,
+    feeds: Vec<CollectionFeed>,
+}
+
+#[derive(Deserialize, Debug)]
+struct TagForm {
+    tag: Option<String>,
+    /// Tag a media of the feed instead of the feed itself
+    media_id: Option<i64>,
+    /// Remove the tag instead of adding it
+    remove: Option<bool>,
+}
+
+#[derive(Deserialize, Debug)]
+struct StarForm {
+    media_id: Option<i64>,
+    starred: Option<bool>,
+}
+
+#[derive(Serialize, Debug)]
+struct FeedTagsResponse {
+    #[serde(serialize_with = "format_string")]
+    feed_id: i64,
+    tags: Vec<String>,
+    starred: bool,
+    /// Tags and stars of the media of the feed, by media id
+    media: Vec<MediaTags>,
+}
+
+#[derive(Serialize, Debug)]
+struct MediaTags {
+    #[serde(serialize_with = "format_string")]
+    media_id: i64,
+    tags: Vec<String>,
+    starred: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
+struct Note {
+    note_id: i64,
+    #[serde(serialize_with = "format_string")]
+    feed_id: i64,
+    /// Markdown
+    body: String,
+    created_at: i64,
+    updated_at: i64,
+}
+
+#[derive(Deserialize, Debug)]
+struct NoteForm {
+    body: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct NotesResponse {
+    #[serde(serialize_with = "format_string")]
+    feed_id: i64,
+    notes: Vec<Note>,
+}
+
+#[derive(Serialize, Debug)]
+struct MutedUser {
+    user_name: String,
+    created_at: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct MutedUsersResponse {
+    muted_users: Vec<MutedUser>,
+}
+
+#[derive(Deserialize, Debug)]
+struct MutedUserForm {
+    user_name: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct TagCount {
+    tag: String,
+    feed_count: i64,
+    media_count: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct TagsResponse {
+    tags: Vec<TagCount>,
+    starred_feed_count: i64,
+    starred_media_count: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FeedsResponse {
+    query: FeedsQuery,
+    feeds: Vec<FeedType>,
+    /// Offsets of the pages before and after, when paging by `offset`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cursors: Option<FeedsCursors>,
+}
+
+#[derive(Serialize, Debug)]
+struct FeedResponse {
+    #[serde(serialize_with = "format_string")]
+    feed_id: i64,
+    /// The tweet with its media and notes, missing when it is only archived as a retweet or in
+    /// a collection
+    feed: Option<FeedType>,
+    /// Accounts retweeting the tweet, oldest first
+    retweeters: Vec<Retweeter>,
+    /// Tweets starting with `@author` posted after the tweet, oldest first. A guess at the
+    /// replies, exports do not keep which tweet a reply is to
+    mentions_after: Vec<FeedType>,
+    /// Tweets linked from the tweet
+    quotes: Vec<QuoteLink>,
+    /// Tweets linking to the tweet
+    quoted_by: Vec<FeedType>,
+}
+
+#[derive(Serialize, Debug)]
+struct Retweeter {
+    user_name: String,
+    retweet_at: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct QuoteLink {
+    #[serde(serialize_with = "format_string")]
+    feed_id: i64,
+    url: String,
+    /// The linked tweet, when archived
+    feed: Option<FeedType>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FeedsCursors {
+    previous: Option<i64>,
+    next: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 struct FeedsQuery {
+    /// Advanced search like `from:alice #rust OR "release notes" -is:retweet`, see search.rs
+    q: Option<String>,
+    /// One or more comma separated accounts
     user_name: Option<String>,
+    /// Whose feeds `user_name` picks: `account` (default) the tweets and retweets of the
+    /// account, `author` the tweets it wrote also when retweeted by others, `any` both
+    user_match: Option<String>,
+    /// Comma separated accounts left out, as tweets or retweets
+    exclude_users: Option<String>,
+    /// Also show the accounts in /a/muted_users
+    include_muted: Option<bool>,
+    /// Export the feeds were read from, `@name` or `@name/likes`, see /a/sources
+    source: Option<String>,
     keyword: Option<String>,
     since: Option<String>,
     until: Option<String>,
     has_media_only: Option<bool>,
+    /// Feeds tagged, or having media tagged, with this tag
+    tag: Option<String>,
+    /// Feeds starred, or having starred media
+    starred: Option<bool>,
+    /// Feeds with this hashtag, `#` is optional
+    hashtag: Option<String>,
+    /// Feeds mentioning any of these comma separated accounts
+    mentions: Option<String>,
+    /// Feeds linking to this domain or its subdomains, see /a/links
+    domain: Option<String>,
+    /// `{feed_id}/{media_id}` of an image to find near-identical images of
+    similar_to: Option<String>,
+    similar_distance: Option<u32>,
+    /// portrait, landscape or square, for feeds having such media
+    orientation: Option<String>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    /// `feed_at` (default) the tweet time, `created_at` the import time or `random`
+    sort: Option<String>,
+    /// `desc` (default) or `asc`, not used by `random`
+    order: Option<String>,
+    /// Shuffle of `sort=random`, a new one is picked and returned when missing
+    seed: Option<i64>,
+    /// Timestamp, date or tweet id to center the page on, returned as `offset`
+    around: Option<String>,
+    /// Feeds to skip, instead of `page`
+    offset: Option<i64>,
     page: Option<i32>,
     count: Option<i32>,
+    /// Include the notes of each feed
+    with_notes: Option<bool>,
 }
 
+const FEEDS_SORTS: [&str; 3] = ["feed_at", "created_at", "random"];
+const FEEDS_ORDERS: [&str; 2] = ["desc", "asc"];
+
 // https://github.com/serde-rs/serde/issues/661#issuecomment-269858463
 // https://github.com/serde-rs/serde/issues/1059
 fn serialize_blob<S: Serializer>(
@@ -177,6 +941,10 @@ fn serialize_blob<S: Serializer>(
     )
 }
 
+fn format_hex<S: Serializer>(value: &i64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:016x}", *value as u64))
+}
+
 fn format_string<S: Serializer, V: core::fmt::Display>(
     value: V,
     serializer: S,
@@ -202,92 +970,338 @@ fn str_to_timestamp(value: &str, offset: i32) -> Option<i64> {
     }
 }
 
-/// Services
+/// `since` and `until` of a query, shared by every endpoint taking a date range so they all
+/// accept the same formats, see parse_query_time. A date until is the end of that day.
+fn parse_query_range(
+    code: &'static str,
+    since: &Option<String>,
+    until: &Option<String>,
+    offset: i32,
+) -> Result<(Option<i64>, Option<i64>), ApiError> {
+    let since = match since.as_ref().filter(|v| !v.is_empty()) {
+        Some(value) => Some(
+            parse_query_time(value, offset, false)
+                .ok_or_else(|| ApiError::BadRequest(code, String::from("Invalid since")))?,
+        ),
+        None => None,
+    };
+    let until = match until.as_ref().filter(|v| !v.is_empty()) {
+        Some(value) => Some(
+            parse_query_time(value, offset, true)
+                .ok_or_else(|| ApiError::BadRequest(code, String::from("Invalid until")))?,
+        ),
+        None => None,
+    };
+    Ok((since, until))
+}
 
-fn state(data: web::Data<AppState>) -> AppStateExternal {
-    AppStateExternal {
-        data_dir: data.data_dir.read().unwrap().to_string(),
-        bind_address: data.bind_address.read().unwrap().to_string(),
-        time_offset: data.time_offset,
-        is_scanning: *data.is_scanning.read().unwrap(),
-        scanner_count: *data.scanner_count.read().unwrap(),
-        scanner_count_limit: data.scanner_count_limit,
+/// Parse a date filter, as unix time or a date/datetime in the configured time offset.
+/// Date-only values cover the whole day when `end_of_day` is set.
+fn parse_query_time(value: &str, offset: i32, end_of_day: bool) -> Option<i64> {
+    let value = value.trim();
+    if let Ok(timestamp) = value.parse::<i64>() {
+        return Some(timestamp);
     }
+    for format in ["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M", "%Y/%m/%d %H:%M:%S"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(value, format) {
+            return Some(
+                FixedOffset::east_opt(offset)?
+                    .from_local_datetime(&dt)
+                    .single()?
+                    .timestamp(),
+            );
+        }
+    }
+    for format in ["%Y-%m-%d", "%Y/%m/%d"] {
+        if let Ok(date) = NaiveDate::parse_from_str(value, format) {
+            let dt = if end_of_day {
+                date.succ_opt()?.and_hms_opt(0, 0, 0)?
+            } else {
+                date.and_hms_opt(0, 0, 0)?
+            };
+            return Some(
+                FixedOffset::east_opt(offset)?
+                    .from_local_datetime(&dt)
+                    .single()?
+                    .timestamp(),
+            );
+        }
+    }
+    None
 }
 
-// https://fullstackmilk.dev/efficiently_escaping_strings_using_cow_in_rust/
-fn escape_like_char(ch: char) -> Option<&'static str> {
-    match ch {
-        '%' => Some("\\%"),
-        _ => None,
+/// Services
+
+fn state(data: web::Data<AppState>) -> AppStateExternal {
+    AppStateExternal {
+        data_dir: data.data_dir.read().unwrap().to_string(),
+        bind_address: data.bind_address.read().unwrap().to_string(),
+        time_offset: data.time_offset,
+        is_scanning: *data.is_scanning.read().unwrap(),
+        scanner_count: *data.scanner_count.read().unwrap(),
+        scanner_count_limit: data.scanner_count_limit,
+        thumbnail_cache_dir: data
+            .thumbnail_cache
+            .as_ref()
+            .map(|cache| cache.dir().to_string_lossy().to_string()),
+        thumbnail_cache_size_limit: data
+            .thumbnail_cache
+            .as_ref()
+            .map(|cache| cache.size_limit() / ONE_MB_U64),
     }
 }
 
-// https://fullstackmilk.dev/efficiently_escaping_strings_using_cow_in_rust/
-fn escape_like_str(input: &str) -> Cow<str> {
-    // Iterate through the characters, checking if each one needs escaping
-    for (i, ch) in input.chars().enumerate() {
-        if escape_like_char(ch).is_some() {
-            // At least one char needs escaping, so we need to return a brand
-            // new `String` rather than the original
+/// Filters of a feeds query as clauses over the timeline `f`, with their values bound
+fn get_feeds_filter(query: &FeedsQuery, time_offset: i32) -> Result<SqlFilter, ApiError> {
+    let mut filter = SqlFilter::default();
+    // Accounts are compared in lowercase like parse_user_names and `from:` give them, the same
+    // way for picking, excluding and muting, so that no account is both in and out
+    let user_names = parse_user_names(&query.user_name);
+    let user_clause = match query.user_match.as_deref() {
+        None | Some("") | Some("account") => "lower(f.user_name) = u.value",
+        Some("author") => "lower(f.author_name) = u.value",
+        Some("any") => "u.value IN (lower(f.user_name), lower(f.author_name))",
+        Some(_) => {
+            return Err(ApiError::BadRequest(
+                "feeds_service_06",
+                String::from("user_match must be one of account, author, any"),
+            ))
+        }
+    };
+    if !user_names.is_empty() {
+        // Lists of accounts are bound as one json array, read back with json_each
+        let p = filter.bind(serde_json::to_string(&user_names).unwrap_or_default());
+        filter.push(format!(
+            "EXISTS (SELECT u.value FROM json_each({}) u WHERE {})",
+            p, user_clause
+        ));
+    }
+    let exclude_users = parse_user_names(&query.exclude_users);
+    if !exclude_users.is_empty() {
+        let p = filter.bind(serde_json::to_string(&exclude_users).unwrap_or_default());
+        filter.push(format!(
+            "NOT EXISTS (SELECT u.value FROM json_each({}) u \
+            WHERE u.value IN (lower(f.user_name), lower(f.author_name)))",
+            p
+        ));
+    }
+    if query.include_muted != Some(true) {
+        filter.push(String::from(
+            "NOT EXISTS (SELECT mu.user_name FROM muted_users mu \
+            WHERE mu.user_name IN (lower(f.user_name), lower(f.author_name)))",
+        ));
+    }
+    if let Some((origin, kind)) = parse_source(&query.source)? {
+        let origin_p = filter.bind(origin);
+        let kind_clause = match kind {
+            Some(kind) => format!(" AND fi.export_kind = {}", filter.bind(kind)),
+            None => String::from(""),
+        };
+        filter.push(format!(
+            "EXISTS (SELECT ff.file_path FROM feed_files ff \
+            INNER JOIN files fi ON fi.file_path = ff.file_path \
+            WHERE ff.feed_id = f.key_feed_id AND ff.user_name = f.user_name \
+            AND ff.retweet_id = f.key_retweet_id \
+            AND ff.retweet_user_name = f.key_retweet_user_name \
+            AND ff.is_listed = 1 AND fi.origin = {}{})",
+            origin_p, kind_clause
+        ));
+    }
+    if let Some(keyword) = query.keyword.as_ref().filter(|v| !v.is_empty()) {
+        // Same as a word of `q`, in the contents or the notes
+        let p = filter.bind(search::like_pattern(keyword));
+        filter.push(format!(
+            "(f.contents LIKE {p} ESCAPE '\\' OR EXISTS (SELECT n.feed_id FROM notes n \
+            WHERE n.feed_id = f.feed_id AND n.body LIKE {p} ESCAPE '\\'))",
+            p = p
+        ));
+    }
+    let (since, until) =
+        parse_query_range("feeds_service_04", &query.since, &query.until, time_offset)?;
+    if let Some(since) = since {
+        let p = filter.bind(since);
+        filter.push(format!("f.feed_at >= {}", p));
+    }
+    if let Some(until) = until {
+        let p = filter.bind(until);
+        filter.push(format!("f.feed_at < {}", p));
+    }
+    if query.has_media_only == Some(true) {
+        filter.push(String::from(
+            "EXISTS (SELECT m.feed_id FROM media m WHERE f.feed_id = m.feed_id LIMIT 1)",
+        ));
+    }
+    if let Some(tag) = query.tag.as_ref().filter(|v| !v.is_empty()) {
+        let p = filter.bind(tag.clone());
+        filter.push(format!(
+            "EXISTS (SELECT tg.feed_id FROM tags tg WHERE tg.feed_id = f.feed_id AND tg.tag = {})",
+            p
+        ));
+    }
+    if query.starred == Some(true) {
+        filter.push(String::from(
+            "EXISTS (SELECT s.feed_id FROM stars s WHERE s.feed_id = f.feed_id)",
+        ));
+    }
+    if let Some(hashtag) = query.hashtag.as_ref().filter(|v| !v.is_empty()) {
+        let p = filter.bind(entities::normalize_hashtag(hashtag));
+        filter.push(format!(
+            "EXISTS (SELECT h.feed_id FROM hashtags h \
+            WHERE h.feed_id = f.feed_id AND h.hashtag = {})",
+            p
+        ));
+    }
+    if let Some(value) = query.domain.as_ref().filter(|v| !v.trim().is_empty()) {
+        let domain = entities::normalize_domain(value).ok_or_else(|| {
+            ApiError::BadRequest(
+                "feeds_service_03",
+                String::from("domain must be a host name or url"),
+            )
+        })?;
+        let p = filter.bind(domain);
+        filter.push(format!(
+            "EXISTS (SELECT l.feed_id FROM links l WHERE l.feed_id = f.feed_id \
+            AND (l.domain = {p} OR substr(l.domain, -length({p}) - 1) = '.' || {p}))",
+            p = p
+        ));
+    }
+    let mentions = parse_user_names(&query.mentions);
+    if !mentions.is_empty() {
+        let p = filter.bind(serde_json::to_string(&mentions).unwrap_or_default());
+        filter.push(format!(
+            "EXISTS (SELECT me.feed_id FROM mentions me WHERE me.feed_id = f.feed_id \
+            AND me.user_name IN (SELECT value FROM json_each({})))",
+            p
+        ));
+    }
+    if let Some(value) = query.similar_to.as_ref().filter(|v| !v.is_empty()) {
+        let (feed_id, media_id) = parse_media_key(value).ok_or_else(|| {
+            ApiError::BadRequest(
+                "feeds_service_01",
+                String::from("similar_to must be {feed_id}/{media_id}"),
+            )
+        })?;
+        let feed_id_p = filter.bind(feed_id);
+        let media_id_p = filter.bind(media_id);
+        let distance_p = filter.bind(
+            query
+                .similar_distance
+                .unwrap_or(DEFAULT_SIMILAR_DISTANCE)
+                .min(phash::MAX_DISTANCE),
+        );
+        filter.push(format!(
+            "EXISTS (SELECT h.feed_id FROM media_hashes h WHERE f.feed_id = h.feed_id \
+            AND hamming_distance(h.phash, (SELECT s.phash FROM media_hashes s \
+                WHERE s.feed_id = {} AND s.media_id = {})) <= {})",
+            feed_id_p, media_id_p, distance_p
+        ));
+    }
+    if let Some(clause) =
+        media_metadata_clause(&query.orientation, &query.min_width, &query.min_height)?
+    {
+        filter.push(format!(
+            "EXISTS (SELECT mm.feed_id FROM media_metadata mm WHERE f.feed_id = mm.feed_id \
+            AND {})",
+            clause
+        ));
+        if let Some(min_width) = query.min_width {
+            filter.bind_named(":min_width", min_width);
+        }
+        if let Some(min_height) = query.min_height {
+            filter.bind_named(":min_height", min_height);
+        }
+    }
+    let expr = match query.q.as_deref() {
+        Some(q) => search::parse(q)
+            .map_err(|err| ApiError::BadRequest("feeds_service_05", err.to_string()))?,
+        None => None,
+    };
+    if let Some(expr) = expr {
+        let clause = search::build_clause(&expr, &mut filter, &|value, is_until| {
+            parse_query_time(value, time_offset, is_until)
+        })
+        .map_err(|err| ApiError::BadRequest("feeds_service_05", err.to_string()))?;
+        filter.push(clause);
+    }
+    Ok(filter)
+}
 
-            let mut escaped_string = String::with_capacity(input.len());
-            // Calling `String::with_capacity()` instead of `String::new()` is
-            // a slight optimisation to reduce the number of allocations we
-            // need to do.
-            //
-            // We know that the escaped string is always at least as long as
-            // the unescaped version so we can preallocate at least that much
-            // space.
+fn get_feeds_query(filter: &SqlFilter, order_by: &str) -> String {
+    format!(
+        "SELECT \
+    f.feed_id, f.feed_at, f.user_name, f.is_retweet, f.author_name, f.twitter_url, f.contents, \
+    f.original_at \
+    FROM ({timeline}) f \
+    {where_clause} \
+    ORDER BY {order_by} \
+    LIMIT :limit OFFSET :offset",
+        timeline = TIMELINE_SQL,
+        where_clause = filter.where_clause(),
+        order_by = order_by
+    )
+}
 
-            // We already checked the characters up to index `i` don't need
-            // escaping so we can just copy them straight in
-            escaped_string.push_str(&input[..i]);
+/// ORDER BY of a checked query, the seed of `random` is bound to the filter
+fn get_feeds_order(query: &FeedsQuery, filter: &mut SqlFilter) -> String {
+    let order = match query.order.as_deref() {
+        Some("asc") => "ASC",
+        _ => "DESC",
+    };
+    match query.sort.as_deref() {
+        Some("created_at") => format!("f.created_at {0}, f.feed_at {0}", order),
+        Some("random") => format!(
+            "seeded_random(f.feed_id, {}), f.feed_at DESC",
+            filter.bind(query.seed.unwrap_or(0))
+        ),
+        _ => format!("f.feed_at {}", order),
+    }
+}
 
-            // Escape the remaining characters if they need it and add them to
-            // our escaped string
-            for ch in input[i..].chars() {
-                match escape_like_char(ch) {
-                    Some(escaped_char) => escaped_string.push_str(escaped_char),
-                    None => escaped_string.push(ch),
-                };
-            }
+fn get_feeds_count_query(filter: &SqlFilter) -> String {
+    format!(
+        "SELECT COUNT(*) FROM ({timeline}) f {where_clause}",
+        timeline = TIMELINE_SQL,
+        where_clause = filter.where_clause()
+    )
+}
 
-            return Cow::Owned(escaped_string);
-        }
-    }
+/// Accounts of a comma separated filter, `@` is optional
+fn parse_user_names(value: &Option<String>) -> Vec<String> {
+    value
+        .as_deref()
+        .unwrap_or("")
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.trim_start_matches('@').is_empty())
+        .map(entities::normalize_mention)
+        .collect()
+}
 
-    // We've iterated through all of `input` and didn't find any special
-    // characters, so it's safe to just return the original string
-    Cow::Borrowed(input)
+/// Parse a `{feed_id}/{media_id}` media key
+fn parse_media_key(value: &str) -> Option<(i64, i64)> {
+    let (feed_id, media_id) = value.split_once('/')?;
+    Some((feed_id.parse::<i64>().ok()?, media_id.parse::<i64>().ok()?))
 }
 
-fn get_feeds_query(query: &FeedsQuery) -> String {
-    let mut where_clauses: Vec<&str> = Vec::new();
-    if query.user_name.as_ref().is_some() && !query.user_name.as_ref().unwrap().is_empty() {
-        where_clauses.push("f.user_name LIKE :user_name");
-    }
-    if query.keyword.as_ref().is_some() && !query.keyword.as_ref().unwrap().is_empty() {
-        where_clauses.push("f.contents LIKE :keyword");
-    }
-    if query.has_media_only.as_ref().is_some() && *query.has_media_only.as_ref().unwrap() {
-        where_clauses
-            .push("EXISTS (SELECT m.feed_id FROM media m WHERE f.feed_id = m.feed_id LIMIT 1)");
-    }
-    let where_clause: String = if where_clauses.is_empty() {
-        String::from("")
-    } else {
-        format!("WHERE {}", where_clauses.join(" AND "))
+/// Parse a `@name` or `@name/kind` export source into its origin and export kind
+fn parse_source(value: &Option<String>) -> Result<Option<(String, Option<String>)>, ApiError> {
+    let value = match value.as_deref().map(str::trim) {
+        None | Some("") => return Ok(None),
+        Some(value) => value,
     };
-    format!("SELECT \
-    f.feed_id, f.feed_at, f.user_name, f.retweet_id, f.retweet_user_name, f.twitter_url, f.contents, \
-    r.feed_id, r.feed_at, r.user_name, r.retweet_id, r.retweet_user_name, r.twitter_url, r.contents \
-    FROM feeds f \
-    LEFT JOIN feeds r \
-    ON f.retweet_id = r.feed_id AND f.retweet_id != 0 \
-    {where_clause} \
-    ORDER BY f.feed_at DESC \
-    LIMIT :limit OFFSET :offset", where_clause = where_clause)
+    let (origin, kind) = match value.split_once('/') {
+        Some((origin, kind)) => (origin, Some(kind.trim().to_ascii_lowercase())),
+        None => (value, None),
+    };
+    match fix_user_name(&Some(origin.trim().to_ascii_lowercase())) {
+        Some(origin) if origin.len() > 1 && kind.as_ref().is_none_or(|v| !v.is_empty()) => {
+            Ok(Some((origin, kind)))
+        }
+        _ => Err(ApiError::BadRequest(
+            "feeds_service_02",
+            String::from("source must be @name or @name/kind"),
+        )),
+    }
 }
 
 fn fix_user_name(value: &Option<String>) -> Option<String> {
@@ -307,94 +1321,121 @@ fn fix_user_name(value: &Option<String>) -> Option<String> {
     }
 }
 
-#[get("/a/feeds")]
-async fn feeds_service(
-    web_query: web::Query<FeedsQuery>,
-    data: web::Data<AppState>,
-) -> impl Responder {
-    let mut query = web_query.into_inner();
-    query.user_name = fix_user_name(&query.user_name);
+/// Fill in the defaults of a feeds query and reject invalid filters
+fn check_feeds_query(query: &mut FeedsQuery, time_offset: i32) -> Result<(), ApiError> {
+    let user_names = parse_user_names(&query.user_name);
+    query.user_name = if user_names.is_empty() {
+        None
+    } else {
+        Some(user_names.join(","))
+    };
     query.page = Some(query.page.unwrap_or(DEFAULT_PAGE));
     query.count = Some(query.count.unwrap_or(DEFAULT_PAGE_COUNT));
-    // println!("feeds: query: {:?}", &query);
-    // println!("feeds: sql: {:?}", get_feeds_query(&query));
-    // open_db(data.clone());
-    // let conn = data.pool.read().unwrap().as_ref().unwrap().get().unwrap();
-    // conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
-    let conn = get_conn(data.clone());
-    let mut feeds_stmt = conn.prepare_cached(&get_feeds_query(&query)).unwrap();
-    let mut feeds_params: Vec<(&str, &dyn ToSql)> = Vec::new();
-
-    let page: i32 = query.page.unwrap();
-    let count: i32 = query.count.unwrap();
-    let offset = SqlValue::Integer(i64::from(page) * i64::from(count));
+    match query.similar_to.as_ref() {
+        Some(value) if !value.is_empty() => match parse_media_key(value) {
+            Some(_) => {
+                query.similar_distance = Some(
+                    query
+                        .similar_distance
+                        .unwrap_or(DEFAULT_SIMILAR_DISTANCE)
+                        .min(phash::MAX_DISTANCE),
+                );
+            }
+            None => {
+                return Err(ApiError::BadRequest(
+                    "feeds_service_01",
+                    String::from("similar_to must be {feed_id}/{media_id}"),
+                ))
+            }
+        },
+        _ => {}
+    };
+    if let Some(sort) = query.sort.as_deref() {
+        if !FEEDS_SORTS.contains(&sort) {
+            return Err(ApiError::BadRequest(
+                "feeds_service_07",
+                format!("sort must be one of {}", FEEDS_SORTS.join(", ")),
+            ));
+        }
+    }
+    if let Some(order) = query.order.as_deref() {
+        if !FEEDS_ORDERS.contains(&order) {
+            return Err(ApiError::BadRequest(
+                "feeds_service_08",
+                format!("order must be one of {}", FEEDS_ORDERS.join(", ")),
+            ));
+        }
+    }
+    if query.around.is_some() && !matches!(query.sort.as_deref(), None | Some("feed_at")) {
+        return Err(ApiError::BadRequest(
+            "feeds_service_10",
+            String::from("around needs sort=feed_at"),
+        ));
+    }
+    query.offset = query.offset.map(|offset| offset.max(0));
+    if query.sort.as_deref() == Some("random") && query.seed.is_none() {
+        query.seed = Some(Utc::now().timestamp_millis());
+    }
+    get_feeds_filter(query, time_offset)?;
+    Ok(())
+}
+
+/// A page of the feeds of a checked query, with their media and notes filled in
+fn read_feeds(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    query: &FeedsQuery,
+    mut filter: SqlFilter,
+) -> Result<Vec<FeedType>, ApiError> {
+    let order_by = get_feeds_order(query, &mut filter);
+    let mut feeds_stmt = conn.prepare_cached(&get_feeds_query(&filter, &order_by))?;
+    let mut feeds_params = filter.params();
+
+    let page: i32 = query.page.unwrap_or(DEFAULT_PAGE);
+    let count: i32 = query.count.unwrap_or(DEFAULT_PAGE_COUNT);
+    let offset = SqlValue::Integer(query.offset.unwrap_or(i64::from(page) * i64::from(count)));
     let limit = SqlValue::Integer(i64::from(count));
     feeds_params.push((":offset", &offset));
     feeds_params.push((":limit", &limit));
-    if query.user_name.is_some() {
-        feeds_params.push((":user_name", &query.user_name));
-    }
-    let mut feeds_param_keyword = None;
-    if query.keyword.is_some() && !query.keyword.as_ref().unwrap().is_empty() {
-        let keyword_original = query.keyword.as_ref().unwrap();
-        feeds_param_keyword = Some(format!(
-            "%{}%",
-            escape_like_str(&keyword_original).into_owned()
-        ));
-        feeds_params.push((":keyword", &feeds_param_keyword));
-    }
-    let mut feeds_result: SqlResult<Vec<FeedType>> = feeds_stmt
+    let feeds_result: SqlResult<Vec<FeedType>> = feeds_stmt
         .query_map(&feeds_params[..], |row| {
-            let retweet_id: i64 = row.get(3).unwrap_or(0i64);
-            if retweet_id == 0i64 {
+            let is_retweet: bool = row.get(3)?;
+            if !is_retweet {
                 // Feed
                 Ok(FeedType::Feed {
-                    feed_id: row.get(0).unwrap(),
-                    feed_at: row.get(1).unwrap(),
-                    user_name: row.get(2).unwrap(),
-                    twitter_url: row.get(5).unwrap(),
-                    contents: row.get(6).unwrap(),
+                    feed_id: row.get(0)?,
+                    feed_at: row.get(1)?,
+                    user_name: row.get(2)?,
+                    twitter_url: row.get(5)?,
+                    contents: row.get(6)?,
                     media: None,
+                    notes: None,
                 })
             } else {
-                // Retweet
-                let retweet_feed_id: i64 = row.get(7).unwrap_or(0i64);
-                if retweet_feed_id == 0i64 {
-                    // Retweet feed not in database
-                    Ok(FeedType::Retweet {
-                        retweet_at: row.get(1).unwrap(),
-                        user_name: row.get(2).unwrap(),
-                        retweet_id: row.get(3).unwrap(),
-                        retweet_user_name: row.get(4).unwrap(),
-                        retweet: None,
-                    })
-                } else {
-                    // Retweet feed in database
-                    Ok(FeedType::Retweet {
-                        retweet_at: row.get(1).unwrap(),
-                        user_name: row.get(2).unwrap(),
-                        retweet_id: row.get(3).unwrap(),
-                        retweet_user_name: row.get(4).unwrap(),
-                        retweet: Some(Box::new(FeedType::Feed {
-                            feed_id: row.get(7).unwrap(),
-                            feed_at: row.get(8).unwrap(),
-                            user_name: row.get(9).unwrap(),
-                            twitter_url: row.get(12).unwrap(),
-                            contents: row.get(13).unwrap(),
+                // Retweet, the original is only there when it was archived
+                let original_at: Option<i64> = row.get(7)?;
+                Ok(FeedType::Retweet {
+                    retweet_at: row.get(1)?,
+                    user_name: row.get(2)?,
+                    retweet_id: row.get(0)?,
+                    retweet_user_name: row.get(4)?,
+                    twitter_url: row.get(5)?,
+                    retweet: match original_at {
+                        Some(original_at) => Some(Box::new(FeedType::Feed {
+                            feed_id: row.get(0)?,
+                            feed_at: original_at,
+                            user_name: row.get(4)?,
+                            twitter_url: row.get(5)?,
+                            contents: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
                             media: None,
+                            notes: None,
                         })),
-                    })
-                }
+                        None => None,
+                    },
+                })
             }
         })
         .and_then(Iterator::collect);
-    let mut feeds = match feeds_result {
-        Ok(arr) => arr,
-        Err(err) => {
-            println!("query error: {:?}", err);
-            vec![]
-        }
-    };
+    let mut feeds = feeds_result?;
 
     // Fill in media
     // TODO: change media without copying item
@@ -415,7 +1456,11 @@ async fn feeds_service(
                     user_name: user_name.clone(),
                     twitter_url: twitter_url.clone(),
                     contents: contents.clone(),
-                    media: get_feed_media(&conn, *feed_id),
+                    media: get_feed_media(conn, *feed_id)?,
+                    notes: match query.with_notes {
+                        Some(true) => Some(get_feed_notes(conn, *feed_id)?),
+                        _ => None,
+                    },
                 };
             }
             FeedType::Retweet {
@@ -423,6 +1468,7 @@ async fn feeds_service(
                 user_name,
                 retweet_id,
                 retweet_user_name,
+                twitter_url,
                 retweet,
             } => match retweet {
                 Some(retweet_feed) => {
@@ -443,13 +1489,18 @@ async fn feeds_service(
                                 user_name: user_name.clone(),
                                 retweet_id: *retweet_id,
                                 retweet_user_name: retweet_user_name.clone(),
+                                twitter_url: twitter_url.clone(),
                                 retweet: Some(Box::new(FeedType::Feed {
                                     feed_id: *inner_feed_id,
                                     feed_at: *inner_feed_at,
                                     user_name: inner_user_name.clone(),
                                     twitter_url: inner_twitter_url.clone(),
                                     contents: inner_contents.clone(),
-                                    media: get_feed_media(&conn, *inner_feed_id),
+                                    media: get_feed_media(conn, *inner_feed_id)?,
+                                    notes: match query.with_notes {
+                                        Some(true) => Some(get_feed_notes(conn, *inner_feed_id)?),
+                                        _ => None,
+                                    },
                                 })),
                             };
                         }
@@ -460,738 +1511,3643 @@ async fn feeds_service(
         };
     }
 
-    HttpResponse::Ok().json(FeedsResponse {
-        query: query,
-        feeds: feeds,
-    })
+    Ok(feeds)
 }
 
-fn get_feed_media(
-    conn: &PooledConnection<SqliteConnectionManager>,
-    media_feed_id: i64,
-) -> Option<Vec<Media>> {
-    let mut media_stmt = conn
-        .prepare(
-            "SELECT \
-            feed_id, media_id, media_type, media_url, file_path, media_path, thumbnail, deleted_at \
-            FROM media \
-            WHERE feed_id = :media_feed_id",
-        )
-        .unwrap();
-    let media_list: rusqlite::Result<Vec<Media>> = media_stmt
-        .query_map(
-            named_params! {
-                ":media_feed_id": media_feed_id,
-            },
-            |row| {
-                Ok(Media {
-                    feed_id: row.get(0).unwrap(),
-                    media_id: row.get(1).unwrap(),
-                    media_type: row.get(2).unwrap(),
-                    media_url: row.get(3).unwrap(),
-                    file_path: row.get(4).unwrap(),
-                    media_path: row.get(5).unwrap(),
-                    thumbnail: match row.get(6) {
-                        Ok(value) => Some(value),
-                        Err(_err) => None,
-                    },
-                    deleted_at: row.get(7).unwrap(),
-                })
-            },
-        )
-        .and_then(Iterator::collect);
-    match media_list {
-        Ok(l) => {
-            if l.is_empty() {
+#[get("/a/feeds")]
+async fn feeds_service(
+    web_query: web::Query<FeedsQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut query = web_query.into_inner();
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+    check_feeds_query(&mut query, time_offset)?;
+    // println!("feeds: query: {:?}", &query);
+    // println!("feeds: sql: {:?}", get_feeds_query(&query, false));
+    let conn = get_read_conn(data.clone())?;
+    if let Some(around) = query.around.as_deref() {
+        let offset = get_around_offset(&conn, &query, around, time_offset)?;
+        query.offset = Some(offset);
+        query.page = Some((offset / i64::from(query.count.unwrap_or(DEFAULT_PAGE_COUNT))) as i32);
+    }
+    let feeds = read_feeds(&conn, &query, get_feeds_filter(&query, time_offset)?)?;
+    let cursors = query.offset.map(|offset| {
+        let count = i64::from(query.count.unwrap_or(DEFAULT_PAGE_COUNT));
+        FeedsCursors {
+            previous: if offset > 0 {
+                Some((offset - count).max(0))
+            } else {
                 None
+            },
+            next: if feeds.len() as i64 == count {
+                Some(offset + count)
             } else {
-                Some(l)
-            }
+                None
+            },
         }
-        Err(_err) => None,
-    }
+    });
+
+    Ok(HttpResponse::Ok().json(FeedsResponse {
+        query,
+        feeds,
+        cursors,
+    }))
 }
 
-#[get("/a/state")]
-async fn app_state_service(data: web::Data<AppState>) -> impl Responder {
-    HttpResponse::Ok().json(state(data.clone()))
+/// Offset of the page centered on the time of `around`, a tweet id in the database is
+/// centered on that tweet
+fn get_around_offset(
+    conn: &Connection,
+    query: &FeedsQuery,
+    around: &str,
+    time_offset: i32,
+) -> Result<i64, ApiError> {
+    let tweet_at: Option<i64> = match around.trim().parse::<i64>() {
+        Ok(feed_id) => conn
+            .prepare_cached(&format!(
+                "SELECT MAX(f.feed_at) FROM ({}) f WHERE f.feed_id = :feed_id",
+                TIMELINE_SQL
+            ))?
+            .query_row(named_params! { ":feed_id": feed_id }, |row| row.get(0))?,
+        Err(_) => None,
+    };
+    let around_at = tweet_at
+        .or_else(|| parse_query_time(around, time_offset, false))
+        .ok_or_else(|| {
+            ApiError::BadRequest(
+                "feeds_service_09",
+                String::from("around must be a timestamp, a date or a tweet id"),
+            )
+        })?;
+
+    // Feeds before the point in the order of the page
+    let mut filter = get_feeds_filter(query, time_offset)?;
+    let p = filter.bind(around_at);
+    filter.push(if query.order.as_deref() == Some("asc") {
+        format!("f.feed_at < {}", p)
+    } else {
+        format!("f.feed_at > {}", p)
+    });
+    let before_count: i64 = conn
+        .prepare_cached(&get_feeds_count_query(&filter))?
+        .query_row(&filter.params()[..], |row| row.get(0))?;
+    Ok((before_count - i64::from(query.count.unwrap_or(DEFAULT_PAGE_COUNT) / 2)).max(0))
 }
 
-#[get("/")]
-async fn home_service() -> impl Responder {
-    return HttpResponse::Ok()
-        .header(CONTENT_TYPE, TEXT_HTML)
-        .body(Bytes::from_static(include_bytes!("../static/index.html")));
+#[derive(Deserialize)]
+struct OnThisDayQuery {
+    /// `YYYY-MM-DD`, today by default
+    date: Option<String>,
 }
 
-#[get("/a/media/file/{feed_id}/{media_id}")]
-async fn media_file_service(
-    web::Path((param_feed_id, param_media_id)): web::Path<(String, String)>,
-    data: web::Data<AppState>,
-) -> impl Responder {
-    println!(
-        "media_file_service {:?} {:?}",
-        param_feed_id, param_media_id
-    );
-    let mut feed_id = 0i64;
-    let mut media_id = 0i64;
-    match param_feed_id.parse::<i64>() {
-        Ok(v) => feed_id = v,
-        Err(_err) => return HttpResponse::NotFound().body(""),
-    };
-    match param_media_id.parse::<i64>() {
-        Ok(v) => media_id = v,
-        Err(_err) => return HttpResponse::NotFound().body(""),
-    };
-    // println!("media_file_service {:?} {:?}", feed_id, media_id);
+#[derive(Serialize)]
+struct OnThisDayResponse {
+    date: String,
+    query: FeedsQuery,
+    feeds: Vec<FeedType>,
+}
 
-    let mut conn = get_conn(data.clone());
-    let mut stmt = conn
-        .prepare_cached(
-            "SELECT \
-            feed_id, media_id, media_type, media_url, file_path, media_path, thumbnail, deleted_at \
-            FROM media \
-            WHERE feed_id = :feed_id AND media_id = :media_id \
-            LIMIT 1",
-        )
-        .unwrap();
-    let mut media = None;
-    match stmt.query_row(
-        named_params! {
-            ":feed_id": feed_id,
-            ":media_id": media_id,
-        },
-        |row| {
-            // println!("media_file_service fetched {:?} {:?}", feed_id, media_id);
-            Ok(Media {
-                feed_id: row.get(0).unwrap(),
-                media_id: row.get(1).unwrap(),
-                media_type: row.get(2).unwrap(),
-                media_url: row.get(3).unwrap(),
-                file_path: row.get(4).unwrap(),
-                media_path: row.get(5).unwrap(),
-                thumbnail: None,
-                deleted_at: row.get(7).unwrap(),
-            })
-        },
-    ) {
-        Ok(value) => {
-            // println!("media_file_service queried: {:?}", value);
-            media = Some(value)
+/// Feeds of the same month and day as `date` in the years before it, in local time
+#[get("/a/feeds/on_this_day")]
+async fn on_this_day_service(
+    web_query: web::Query<FeedsQuery>,
+    day_query: web::Query<OnThisDayQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut query = web_query.into_inner();
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+    let date = match day_query.date.as_deref().map(str::trim) {
+        Some(value) if !value.is_empty() => {
+            NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+                ApiError::BadRequest(
+                    "on_this_day_service_01",
+                    String::from("date must be YYYY-MM-DD"),
+                )
+            })?
         }
-        Err(_err) => return HttpResponse::NotFound().body(""),
+        _ => Utc::now()
+            .with_timezone(&FixedOffset::east_opt(time_offset).unwrap())
+            .date_naive(),
     };
-    match extract_zip_file(
-        data.data_dir.read().unwrap().to_string(),
-        media.as_ref().unwrap().file_path.to_string(),
-        media.as_ref().unwrap().media_path.to_string(),
-    ) {
-        Some((buf, _size, mime_type)) => {
-            return HttpResponse::Ok().header(CONTENT_TYPE, mime_type).body(buf);
-        }
-        None => {
-            println!("media_file_service extract_zip_file failed");
-        }
-    }
-    HttpResponse::NotFound().body("")
+    check_feeds_query(&mut query, time_offset)?;
+
+    let mut filter = get_feeds_filter(&query, time_offset)?;
+    let local_at = format!("f.feed_at + {}", filter.bind(time_offset));
+    let month_day = filter.bind(date.format("%m-%d").to_string());
+    filter.push(format!(
+        "strftime('%m-%d', {}, 'unixepoch') = {}",
+        local_at, month_day
+    ));
+    let year = filter.bind(date.format("%Y").to_string());
+    filter.push(format!(
+        "strftime('%Y', {}, 'unixepoch') < {}",
+        local_at, year
+    ));
+    let conn = get_read_conn(data.clone())?;
+    let feeds = read_feeds(&conn, &query, filter)?;
+
+    Ok(HttpResponse::Ok().json(OnThisDayResponse {
+        date: date.format("%Y-%m-%d").to_string(),
+        query,
+        feeds,
+    }))
 }
 
-#[get("/a/media/preview/{feed_id}/{media_id}")]
-async fn media_preview_service(
-    web::Path((param_feed_id, param_media_id)): web::Path<(String, String)>,
+/// Mentions and tweets linking to a tweet listed by /a/feeds/{feed_id}
+const FEED_RELATED_COUNT: i32 = 100;
+
+/// One tweet with everything archived around it, for sharing a link to it
+#[get("/a/feeds/{feed_id}")]
+async fn feed_service(
+    web::Path(param_feed_id): web::Path<String>,
     data: web::Data<AppState>,
-) -> impl Responder {
-    println!(
-        "media_preview_service {:?} {:?}",
-        param_feed_id, param_media_id
-    );
-    let mut feed_id = 0i64;
-    let mut media_id = 0i64;
-    match param_feed_id.parse::<i64>() {
-        Ok(v) => feed_id = v,
-        Err(_err) => return HttpResponse::NotFound().body(""),
-    };
-    match param_media_id.parse::<i64>() {
-        Ok(v) => media_id = v,
-        Err(_err) => return HttpResponse::NotFound().body(""),
+) -> Result<HttpResponse, ApiError> {
+    let feed_id = parse_path_id("feed_service_01", &param_feed_id)?;
+    let conn = get_read_conn(data.clone())?;
+    check_feed_exists(&conn, "feed_service_02", feed_id, 0)?;
+    let query = FeedsQuery {
+        include_muted: Some(true),
+        with_notes: Some(true),
+        order: Some(String::from("asc")),
+        count: Some(FEED_RELATED_COUNT),
+        ..Default::default()
     };
 
-    let mut media = match get_media(data.clone(), feed_id, media_id) {
-        Ok(value) => {
-            if "Image" == &value.media_type && value.deleted_at.as_ref().is_none() {
-                match value.thumbnail {
-                    Some(buf) => {
-                        return HttpResponse::Ok()
-                            .header(CONTENT_TYPE, IMAGE_JPEG)
-                            .body(buf);
-                    }
-                    None => value,
-                }
-            } else {
-                return HttpResponse::NotFound().body("");
-            }
-        }
-        Err(_err) => return HttpResponse::NotFound().body(""),
-    };
+    let mut filter = SqlFilter::default();
+    let p = filter.bind(feed_id);
+    filter.push(format!("f.feed_id = {} AND f.is_retweet = 0", p));
+    let feed = read_feeds(&conn, &query, filter)?.into_iter().next();
 
-    let mut image_blob = match extract_zip_file(
-        data.data_dir.read().unwrap().to_string(),
-        media.file_path.to_string(),
-        media.media_path.to_string(),
-    ) {
-        Some((buf, _size, _mime_type)) => buf,
-        None => return HttpResponse::NotFound().body(""),
-    };
+    let retweeters: Vec<Retweeter> = conn
+        .prepare_cached(&format!(
+            "SELECT f.user_name, MIN(f.feed_at) FROM ({}) f \
+            WHERE f.feed_id = :feed_id AND f.is_retweet = 1 \
+            GROUP BY f.user_name ORDER BY MIN(f.feed_at)",
+            TIMELINE_SQL
+        ))?
+        .query_map(named_params! { ":feed_id": feed_id }, |row| {
+            Ok(Retweeter {
+                user_name: row.get(0)?,
+                retweet_at: row.get(1)?,
+            })
+        })?
+        .collect::<SqlResult<Vec<Retweeter>>>()?;
 
-    match generate_thumbnail_blob(&image_blob) {
-        Ok(buf) => media.thumbnail = Some(buf),
-        Err(err) => println!("generate_thumbnail_blob update failed: {:?}", err),
+    let mentions_after = match &feed {
+        Some(FeedType::Feed {
+            user_name, feed_at, ..
+        }) => {
+            let mut filter = SqlFilter::default();
+            let mention = filter.bind(user_name.to_lowercase());
+            let feed_at = filter.bind(*feed_at);
+            let p = filter.bind(feed_id);
+            filter.push(format!(
+                "f.is_retweet = 0 AND f.feed_id <> {p} AND f.feed_at >= {feed_at} \
+                AND (lower(f.contents) = {mention} \
+                OR substr(lower(f.contents), 1, length({mention}) + 1) = {mention} || ' ')",
+                p = p,
+                feed_at = feed_at,
+                mention = mention
+            ));
+            read_feeds(&conn, &query, filter)?
+        }
+        _ => Vec::new(),
     };
 
-    match update_media_thumbnail(data.clone(), &media) {
-        Ok(()) => {
-            return HttpResponse::Ok()
-                .header(CONTENT_TYPE, IMAGE_JPEG)
-                .body(media.thumbnail.unwrap())
+    let links: Vec<(String, i64)> = conn
+        .prepare_cached(
+            "SELECT url, status_id FROM links \
+            WHERE feed_id = :feed_id AND status_id IS NOT NULL AND status_id <> :feed_id \
+            ORDER BY url",
+        )?
+        .query_map(named_params! { ":feed_id": feed_id }, |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?
+        .collect::<SqlResult<Vec<(String, i64)>>>()?;
+    // Every linked feed in one query, the first one found for each id like a single read
+    let mut quoted_feeds: HashMap<i64, FeedType> = HashMap::new();
+    if !links.is_empty() {
+        let mut filter = SqlFilter::default();
+        let p = filter.bind(feed_id);
+        filter.push(format!(
+            "f.is_retweet = 0 AND f.feed_id IN (SELECT l.status_id FROM links l \
+            WHERE l.feed_id = {p} AND l.status_id IS NOT NULL AND l.status_id <> {p})",
+            p = p
+        ));
+        let quotes_query = FeedsQuery {
+            count: Some(i32::MAX),
+            ..query.clone()
+        };
+        for feed in read_feeds(&conn, &quotes_query, filter)? {
+            if let FeedType::Feed {
+                feed_id: quote_id, ..
+            } = feed
+            {
+                quoted_feeds.entry(quote_id).or_insert(feed);
+            }
         }
-        Err(err) => println!("update_media_thumbnail update failed: {:?}", err),
-    };
+    }
+    let quotes: Vec<QuoteLink> = links
+        .into_iter()
+        .map(|(url, quote_id)| QuoteLink {
+            feed_id: quote_id,
+            url,
+            feed: quoted_feeds.get(&quote_id).cloned(),
+        })
+        .collect();
+
+    let mut filter = SqlFilter::default();
+    let p = filter.bind(feed_id);
+    filter.push(format!(
+        "f.is_retweet = 0 AND f.feed_id <> {p} \
+        AND f.feed_id IN (SELECT l.feed_id FROM links l WHERE l.status_id = {p})",
+        p = p
+    ));
+    let quoted_by = read_feeds(&conn, &query, filter)?;
 
-    HttpResponse::NotFound().body("")
+    Ok(HttpResponse::Ok().json(FeedResponse {
+        feed_id,
+        feed,
+        retweeters,
+        mentions_after,
+        quotes,
+        quoted_by,
+    }))
 }
 
-fn get_media(
-    data: web::Data<AppState>,
-    feed_id: i64,
-    media_id: i64,
-) -> Result<Media, rusqlite::Error> {
-    let mut conn = get_conn(data.clone());
-    let mut stmt = conn
+#[get("/a/sources")]
+async fn sources_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let conn = get_read_conn(data.clone())?;
+    let sources: Vec<Source> = conn
+        .prepare(
+            "SELECT fi.origin, fi.export_kind, COUNT(DISTINCT fi.file_path), COUNT(ff.file_path) \
+            FROM files fi \
+            LEFT JOIN feed_files ff \
+            ON ff.file_path = fi.file_path AND ff.is_listed = 1 \
+            WHERE fi.origin IS NOT NULL \
+            GROUP BY fi.origin, fi.export_kind \
+            ORDER BY fi.origin, fi.export_kind",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok(Source {
+                    origin: row.get(0)?,
+                    export_kind: row.get(1)?,
+                    file_count: row.get(2)?,
+                    feed_count: row.get(3)?,
+                })
+            })
+            .and_then(Iterator::collect)
+        })?;
+    Ok(HttpResponse::Ok().json(SourcesResponse { sources }))
+}
+
+#[get("/a/muted_users")]
+async fn muted_users_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let conn = get_read_conn(data.clone())?;
+    let muted_users = get_muted_users(&conn)?;
+    Ok(HttpResponse::Ok().json(MutedUsersResponse { muted_users }))
+}
+
+#[post("/a/muted_users")]
+async fn muted_user_create_service(
+    form: web::Form<MutedUserForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_name = match parse_user_names(&form.into_inner().user_name)[..] {
+        [ref user_name] => user_name.clone(),
+        _ => {
+            return Err(ApiError::BadRequest(
+                "muted_user_create_service_01",
+                String::from("user_name must be one account"),
+            ))
+        }
+    };
+    let conn = get_conn(data.clone())?;
+    let muted_user = conn.query_row(
+        "INSERT INTO muted_users (user_name) VALUES (:user_name) \
+        ON CONFLICT (user_name) DO UPDATE SET user_name = excluded.user_name \
+        RETURNING user_name, created_at",
+        named_params! { ":user_name": user_name },
+        |row| {
+            Ok(MutedUser {
+                user_name: row.get(0)?,
+                created_at: row.get(1)?,
+            })
+        },
+    )?;
+    Ok(HttpResponse::Created().json(muted_user))
+}
+
+#[delete("/a/muted_users/{user_name}")]
+async fn muted_user_delete_service(
+    web::Path(param_user_name): web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let user_name = entities::normalize_mention(&param_user_name);
+    let conn = get_conn(data.clone())?;
+    let count = conn.execute(
+        "DELETE FROM muted_users WHERE user_name = :user_name",
+        named_params! { ":user_name": user_name },
+    )?;
+    if count == 0 {
+        return Err(ApiError::NotFound(
+            "muted_user_delete_service_01",
+            format!("{} is not muted", user_name),
+        ));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn get_muted_users(conn: &Connection) -> SqlResult<Vec<MutedUser>> {
+    conn.prepare_cached("SELECT user_name, created_at FROM muted_users ORDER BY user_name")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok(MutedUser {
+                    user_name: row.get(0)?,
+                    created_at: row.get(1)?,
+                })
+            })
+            .and_then(Iterator::collect)
+        })
+}
+
+#[get("/a/tags")]
+async fn tags_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let conn = get_read_conn(data.clone())?;
+    let tags: Vec<TagCount> = conn
+        .prepare(
+            "SELECT tag, SUM(media_id = 0), SUM(media_id != 0) FROM tags \
+            GROUP BY tag ORDER BY COUNT(*) DESC, tag",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| {
+                Ok(TagCount {
+                    tag: row.get(0)?,
+                    feed_count: row.get(1)?,
+                    media_count: row.get(2)?,
+                })
+            })
+            .and_then(Iterator::collect)
+        })?;
+    let (starred_feed_count, starred_media_count): (i64, i64) = conn.query_row(
+        "SELECT IFNULL(SUM(media_id = 0), 0), IFNULL(SUM(media_id != 0), 0) FROM stars",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(HttpResponse::Ok().json(TagsResponse {
+        tags,
+        starred_feed_count,
+        starred_media_count,
+    }))
+}
+
+#[get("/a/feeds/{feed_id}/tags")]
+async fn feed_tags_service(
+    web::Path(param_feed_id): web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let feed_id = parse_path_id("feed_tags_service_01", &param_feed_id)?;
+    let conn = get_read_conn(data.clone())?;
+    Ok(HttpResponse::Ok().json(get_feed_tags(&conn, feed_id)?))
+}
+
+#[post("/a/feeds/{feed_id}/tags")]
+async fn feed_tag_service(
+    web::Path(param_feed_id): web::Path<String>,
+    form: web::Form<TagForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let feed_id = parse_path_id("feed_tag_service_01", &param_feed_id)?;
+    let form = form.into_inner();
+    let tag = match form.tag.as_deref().map(str::trim) {
+        Some(tag) if !tag.is_empty() && tag.chars().count() <= MAX_TAG_LENGTH => tag.to_string(),
+        _ => {
+            return Err(ApiError::BadRequest(
+                "feed_tag_service_02",
+                format!("tag must be 1 to {} characters", MAX_TAG_LENGTH),
+            ))
+        }
+    };
+    let media_id = form.media_id.unwrap_or(0);
+    let conn = get_conn(data.clone())?;
+    check_feed_exists(&conn, "feed_tag_service_03", feed_id, media_id)?;
+    if form.remove.unwrap_or(false) {
+        conn.execute(
+            "DELETE FROM tags WHERE feed_id = :feed_id AND media_id = :media_id AND tag = :tag",
+            named_params! { ":feed_id": feed_id, ":media_id": media_id, ":tag": tag },
+        )?;
+    } else {
+        conn.execute(
+            "INSERT OR IGNORE INTO tags (feed_id, media_id, tag) VALUES (:feed_id, :media_id, :tag)",
+            named_params! { ":feed_id": feed_id, ":media_id": media_id, ":tag": tag },
+        )?;
+    }
+    Ok(HttpResponse::Ok().json(get_feed_tags(&conn, feed_id)?))
+}
+
+#[post("/a/feeds/{feed_id}/star")]
+async fn feed_star_service(
+    web::Path(param_feed_id): web::Path<String>,
+    form: web::Form<StarForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let feed_id = parse_path_id("feed_star_service_01", &param_feed_id)?;
+    let form = form.into_inner();
+    let media_id = form.media_id.unwrap_or(0);
+    let conn = get_conn(data.clone())?;
+    check_feed_exists(&conn, "feed_star_service_02", feed_id, media_id)?;
+    if form.starred.unwrap_or(true) {
+        conn.execute(
+            "INSERT OR IGNORE INTO stars (feed_id, media_id) VALUES (:feed_id, :media_id)",
+            named_params! { ":feed_id": feed_id, ":media_id": media_id },
+        )?;
+    } else {
+        conn.execute(
+            "DELETE FROM stars WHERE feed_id = :feed_id AND media_id = :media_id",
+            named_params! { ":feed_id": feed_id, ":media_id": media_id },
+        )?;
+    }
+    Ok(HttpResponse::Ok().json(get_feed_tags(&conn, feed_id)?))
+}
+
+/// Tags, stars and notes can be put on any archived feed or retweeted feed,
+/// tags and stars on its media too
+fn check_feed_exists(
+    conn: &Connection,
+    code: &'static str,
+    feed_id: i64,
+    media_id: i64,
+) -> Result<(), ApiError> {
+    let exists: bool = if media_id == 0 {
+        conn.query_row(
+            "SELECT EXISTS (SELECT feed_id FROM feeds WHERE feed_id = :feed_id) \
+            OR EXISTS (SELECT feed_id FROM retweets WHERE feed_id = :feed_id)",
+            named_params! { ":feed_id": feed_id },
+            |row| row.get(0),
+        )?
+    } else {
+        conn.query_row(
+            "SELECT EXISTS (SELECT feed_id FROM media \
+            WHERE feed_id = :feed_id AND media_id = :media_id)",
+            named_params! { ":feed_id": feed_id, ":media_id": media_id },
+            |row| row.get(0),
+        )?
+    };
+    if exists {
+        Ok(())
+    } else {
+        Err(ApiError::NotFound(
+            code,
+            format!("Feed {} media {} not found", feed_id, media_id),
+        ))
+    }
+}
+
+fn get_feed_tags(conn: &Connection, feed_id: i64) -> Result<FeedTagsResponse, ApiError> {
+    // (media_id, tag) and (media_id, NULL) for stars, media_id 0 is the feed itself
+    let rows: Vec<(i64, Option<String>)> = conn
+        .prepare_cached(
+            "SELECT media_id, tag FROM tags WHERE feed_id = :feed_id \
+            UNION ALL \
+            SELECT media_id, NULL FROM stars WHERE feed_id = :feed_id \
+            ORDER BY 1, 2",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(named_params! { ":feed_id": feed_id }, |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .and_then(Iterator::collect)
+        })?;
+    let mut response = FeedTagsResponse {
+        feed_id,
+        tags: Vec::new(),
+        starred: false,
+        media: Vec::new(),
+    };
+    for (media_id, tag) in rows {
+        let (tags, starred) = if media_id == 0 {
+            (&mut response.tags, &mut response.starred)
+        } else {
+            if response.media.last().map(|media| media.media_id) != Some(media_id) {
+                response.media.push(MediaTags {
+                    media_id,
+                    tags: Vec::new(),
+                    starred: false,
+                });
+            }
+            let media = response.media.last_mut().unwrap();
+            (&mut media.tags, &mut media.starred)
+        };
+        match tag {
+            Some(tag) => tags.push(tag),
+            None => *starred = true,
+        };
+    }
+    Ok(response)
+}
+
+#[get("/a/feeds/{feed_id}/notes")]
+async fn feed_notes_service(
+    web::Path(param_feed_id): web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let feed_id = parse_path_id("feed_notes_service_01", &param_feed_id)?;
+    let conn = get_read_conn(data.clone())?;
+    let notes = get_feed_notes(&conn, feed_id)?;
+    Ok(HttpResponse::Ok().json(NotesResponse { feed_id, notes }))
+}
+
+#[post("/a/feeds/{feed_id}/notes")]
+async fn note_create_service(
+    web::Path(param_feed_id): web::Path<String>,
+    form: web::Form<NoteForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let feed_id = parse_path_id("note_create_service_01", &param_feed_id)?;
+    let body = note_body("note_create_service_02", form.into_inner())?;
+    let conn = get_conn(data.clone())?;
+    check_feed_exists(&conn, "note_create_service_03", feed_id, 0)?;
+    let note = conn.query_row(
+        "INSERT INTO notes (feed_id, body) VALUES (:feed_id, :body) \
+        RETURNING note_id, feed_id, body, created_at, updated_at",
+        named_params! { ":feed_id": feed_id, ":body": body },
+        row_note,
+    )?;
+    Ok(HttpResponse::Created().json(note))
+}
+
+#[put("/a/feeds/{feed_id}/notes/{note_id}")]
+async fn note_update_service(
+    web::Path((param_feed_id, param_note_id)): web::Path<(String, String)>,
+    form: web::Form<NoteForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let feed_id = parse_path_id("note_update_service_01", &param_feed_id)?;
+    let note_id = parse_path_id("note_update_service_01", &param_note_id)?;
+    let body = note_body("note_update_service_02", form.into_inner())?;
+    let conn = get_conn(data.clone())?;
+    match conn.query_row(
+        "UPDATE notes SET body = :body, updated_at = CAST(strftime('%s','now') AS INTEGER) \
+        WHERE note_id = :note_id AND feed_id = :feed_id \
+        RETURNING note_id, feed_id, body, created_at, updated_at",
+        named_params! { ":note_id": note_id, ":feed_id": feed_id, ":body": body },
+        row_note,
+    ) {
+        Ok(note) => Ok(HttpResponse::Ok().json(note)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(ApiError::NotFound(
+            "note_update_service_03",
+            format!("Note {} not found", note_id),
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[delete("/a/feeds/{feed_id}/notes/{note_id}")]
+async fn note_delete_service(
+    web::Path((param_feed_id, param_note_id)): web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let feed_id = parse_path_id("note_delete_service_01", &param_feed_id)?;
+    let note_id = parse_path_id("note_delete_service_01", &param_note_id)?;
+    let conn = get_conn(data.clone())?;
+    let count = conn.execute(
+        "DELETE FROM notes WHERE note_id = :note_id AND feed_id = :feed_id",
+        named_params! { ":note_id": note_id, ":feed_id": feed_id },
+    )?;
+    if count == 0 {
+        return Err(ApiError::NotFound(
+            "note_delete_service_02",
+            format!("Note {} not found", note_id),
+        ));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+fn note_body(code: &'static str, form: NoteForm) -> Result<String, ApiError> {
+    match form.body {
+        Some(body) if !body.trim().is_empty() => Ok(body),
+        _ => Err(ApiError::BadRequest(code, String::from("body is required"))),
+    }
+}
+
+fn get_feed_notes(conn: &Connection, feed_id: i64) -> SqlResult<Vec<Note>> {
+    conn.prepare_cached(
+        "SELECT note_id, feed_id, body, created_at, updated_at FROM notes \
+        WHERE feed_id = :feed_id ORDER BY created_at, note_id",
+    )
+    .and_then(|mut stmt| {
+        stmt.query_map(named_params! { ":feed_id": feed_id }, row_note)
+            .and_then(Iterator::collect)
+    })
+}
+
+fn row_note(row: &rusqlite::Row) -> SqlResult<Note> {
+    Ok(Note {
+        note_id: row.get(0)?,
+        feed_id: row.get(1)?,
+        body: row.get(2)?,
+        created_at: row.get(3)?,
+        updated_at: row.get(4)?,
+    })
+}
+
+/// Most used hashtags of the timeline, retweets count on the time they were retweeted
+#[get("/a/hashtags")]
+async fn hashtags_service(
+    web_query: web::Query<HashtagsQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut query = web_query.into_inner();
+    query.user_name = fix_user_name(&query.user_name);
+    query.count = Some(query.count.unwrap_or(DEFAULT_PAGE_COUNT));
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+    let (since, until) = parse_query_range(
+        "hashtags_service_01",
+        &query.since,
+        &query.until,
+        time_offset,
+    )?;
+    let mut where_clauses: Vec<&str> = Vec::new();
+    let mut hashtags_params: Vec<(&str, &dyn ToSql)> = Vec::new();
+    if query.user_name.is_some() {
+        where_clauses.push("lower(f.user_name) = lower(:user_name)");
+        hashtags_params.push((":user_name", &query.user_name));
+    }
+    if since.is_some() {
+        where_clauses.push("f.feed_at >= :since");
+        hashtags_params.push((":since", &since));
+    }
+    if until.is_some() {
+        where_clauses.push("f.feed_at < :until");
+        hashtags_params.push((":until", &until));
+    }
+    hashtags_params.push((":limit", &query.count));
+    let where_clause: String = if where_clauses.is_empty() {
+        String::from("")
+    } else {
+        format!("WHERE {}", where_clauses.join(" AND "))
+    };
+
+    let conn = get_read_conn(data.clone())?;
+    let hashtags: Vec<HashtagCount> = conn
+        .prepare_cached(&format!(
+            "SELECT h.hashtag, COUNT(*), MAX(f.feed_at) \
+            FROM ({timeline}) f \
+            INNER JOIN hashtags h ON h.feed_id = f.feed_id \
+            {where_clause} \
+            GROUP BY h.hashtag \
+            ORDER BY COUNT(*) DESC, h.hashtag \
+            LIMIT :limit",
+            timeline = TIMELINE_SQL,
+            where_clause = where_clause
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map(&hashtags_params[..], |row| {
+                Ok(HashtagCount {
+                    hashtag: row.get(0)?,
+                    feed_count: row.get(1)?,
+                    last_feed_at: row.get(2)?,
+                })
+            })
+            .and_then(Iterator::collect)
+        })?;
+    Ok(HttpResponse::Ok().json(HashtagsResponse { query, hashtags }))
+}
+
+/// Most linked domains and urls of the timeline, read from the text of the tweets
+#[get("/a/links")]
+async fn links_service(
+    web_query: web::Query<LinksQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut query = web_query.into_inner();
+    query.user_name = fix_user_name(&query.user_name);
+    query.count = Some(query.count.unwrap_or(DEFAULT_PAGE_COUNT));
+    let domain = match query.domain.as_ref().filter(|v| !v.trim().is_empty()) {
+        Some(value) => Some(entities::normalize_domain(value).ok_or_else(|| {
+            ApiError::BadRequest(
+                "links_service_01",
+                String::from("domain must be a host name or url"),
+            )
+        })?),
+        None => None,
+    };
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+    let (since, until) =
+        parse_query_range("links_service_02", &query.since, &query.until, time_offset)?;
+    let mut where_clauses: Vec<&str> = Vec::new();
+    let mut links_params: Vec<(&str, &dyn ToSql)> = Vec::new();
+    if domain.is_some() {
+        where_clauses.push(
+            "(l.domain = :domain OR substr(l.domain, -length(:domain) - 1) = '.' || :domain)",
+        );
+        links_params.push((":domain", &domain));
+    }
+    if query.user_name.is_some() {
+        where_clauses.push("lower(f.user_name) = lower(:user_name)");
+        links_params.push((":user_name", &query.user_name));
+    }
+    if since.is_some() {
+        where_clauses.push("f.feed_at >= :since");
+        links_params.push((":since", &since));
+    }
+    if until.is_some() {
+        where_clauses.push("f.feed_at < :until");
+        links_params.push((":until", &until));
+    }
+    links_params.push((":limit", &query.count));
+    let from_clause = format!(
+        "FROM ({timeline}) f INNER JOIN links l ON l.feed_id = f.feed_id {where_clause}",
+        timeline = TIMELINE_SQL,
+        where_clause = if where_clauses.is_empty() {
+            String::from("")
+        } else {
+            format!("WHERE {}", where_clauses.join(" AND "))
+        }
+    );
+
+    let conn = get_read_conn(data.clone())?;
+    let domains: Vec<DomainCount> = conn
+        .prepare_cached(&format!(
+            "SELECT l.domain, COUNT(DISTINCT l.url), COUNT(*), MAX(f.feed_at) {} \
+            GROUP BY l.domain ORDER BY COUNT(*) DESC, l.domain LIMIT :limit",
+            from_clause
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map(&links_params[..], |row| {
+                Ok(DomainCount {
+                    domain: row.get(0)?,
+                    url_count: row.get(1)?,
+                    feed_count: row.get(2)?,
+                    last_feed_at: row.get(3)?,
+                })
+            })
+            .and_then(Iterator::collect)
+        })?;
+    let links: Vec<LinkCount> = conn
+        .prepare_cached(&format!(
+            "SELECT l.url, l.domain, COUNT(*), MAX(f.feed_at) {} \
+            GROUP BY l.url ORDER BY COUNT(*) DESC, MAX(f.feed_at) DESC LIMIT :limit",
+            from_clause
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map(&links_params[..], |row| {
+                Ok(LinkCount {
+                    url: row.get(0)?,
+                    domain: row.get(1)?,
+                    feed_count: row.get(2)?,
+                    last_feed_at: row.get(3)?,
+                })
+            })
+            .and_then(Iterator::collect)
+        })?;
+    Ok(HttpResponse::Ok().json(LinksResponse {
+        query,
+        domains,
+        links,
+    }))
+}
+
+#[get("/a/searches")]
+async fn saved_searches_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+    let conn = get_read_conn(data.clone())?;
+    let mut saved_searches: Vec<SavedSearch> = conn
+        .prepare(&format!("{} ORDER BY name", SAVED_SEARCH_SQL))
+        .and_then(|mut stmt| {
+            stmt.query_map([], row_saved_search)
+                .and_then(Iterator::collect)
+        })?;
+    for saved_search in saved_searches.iter_mut() {
+        saved_search.new_count = count_new_feeds(&conn, saved_search, time_offset)?;
+    }
+    Ok(HttpResponse::Ok().json(SavedSearchesResponse { saved_searches }))
+}
+
+/// Saves the filters of the query string under a name, saving a name again replaces its search
+#[post("/a/searches")]
+async fn saved_search_create_service(
+    web_query: web::Query<FeedsQuery>,
+    form: web::Form<SavedSearchForm>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let form = form.into_inner();
+    println!("saved_search_create_service {:?}", form);
+    let name = match form.name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => name.to_owned(),
+        _ => {
+            return Err(ApiError::BadRequest(
+                "saved_search_create_service_01",
+                String::from("name is required"),
+            ))
+        }
+    };
+    let mut query = web_query.into_inner();
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+    check_feeds_query(&mut query, time_offset)?;
+    query.page = None;
+    query.count = None;
+    query.around = None;
+    query.offset = None;
+    let query_json = serde_json::to_string(&query).map_err(|err| {
+        ApiError::Unprocessable("saved_search_create_service_02", err.to_string())
+    })?;
+
+    let conn = get_conn(data.clone())?;
+    // A replaced search starts over, `new_count` counts from now like a new one
+    let saved_search_id: i64 = conn.query_row(
+        "INSERT INTO saved_searches (name, query) VALUES (:name, :query) \
+        ON CONFLICT (name) DO UPDATE SET query = excluded.query, \
+        created_at = excluded.created_at, used_at = NULL \
+        RETURNING saved_search_id",
+        named_params! { ":name": name, ":query": query_json },
+        |row| row.get(0),
+    )?;
+    let mut saved_search = conn.query_row(
+        &format!(
+            "{} WHERE saved_search_id = :saved_search_id",
+            SAVED_SEARCH_SQL
+        ),
+        named_params! { ":saved_search_id": saved_search_id },
+        row_saved_search,
+    )?;
+    saved_search.new_count = count_new_feeds(&conn, &saved_search, time_offset)?;
+    Ok(HttpResponse::Created().json(saved_search))
+}
+
+#[delete("/a/searches/{saved_search_id}")]
+async fn saved_search_delete_service(
+    web::Path(param_saved_search_id): web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let saved_search_id = parse_path_id("saved_search_delete_service_01", &param_saved_search_id)?;
+    let conn = get_conn(data.clone())?;
+    let count = conn.execute(
+        "DELETE FROM saved_searches WHERE saved_search_id = :saved_search_id",
+        named_params! { ":saved_search_id": saved_search_id },
+    )?;
+    if count == 0 {
+        return Err(ApiError::NotFound(
+            "saved_search_delete_service_02",
+            format!("Saved search {} not found", saved_search_id),
+        ));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Runs a saved search, reading it does not change `new_count`, see saved_search_viewed_service
+#[get("/a/searches/{saved_search_id}/feeds")]
+async fn saved_search_feeds_service(
+    web::Path(param_saved_search_id): web::Path<String>,
+    web_query: web::Query<SavedSearchFeedsQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let saved_search_id = parse_path_id("saved_search_feeds_service_01", &param_saved_search_id)?;
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+    let conn = get_read_conn(data.clone())?;
+    let mut saved_search = match conn.query_row(
+        &format!(
+            "{} WHERE saved_search_id = :saved_search_id",
+            SAVED_SEARCH_SQL
+        ),
+        named_params! { ":saved_search_id": saved_search_id },
+        row_saved_search,
+    ) {
+        Ok(saved_search) => saved_search,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(ApiError::NotFound(
+                "saved_search_feeds_service_02",
+                format!("Saved search {} not found", saved_search_id),
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    saved_search.new_count = count_new_feeds(&conn, &saved_search, time_offset)?;
+
+    let mut query = saved_search.query.clone();
+    query.page = web_query.page;
+    query.count = web_query.count;
+    check_feeds_query(&mut query, time_offset)?;
+    let feeds = read_feeds(&conn, &query, get_feeds_filter(&query, time_offset)?)?;
+
+    Ok(HttpResponse::Ok().json(SavedSearchFeedsResponse {
+        saved_search,
+        query,
+        feeds,
+    }))
+}
+
+/// Marks a saved search as viewed, `new_count` counts from now on
+#[post("/a/searches/{saved_search_id}/viewed")]
+async fn saved_search_viewed_service(
+    web::Path(param_saved_search_id): web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let saved_search_id = parse_path_id("saved_search_viewed_service_01", &param_saved_search_id)?;
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+    let conn = get_conn(data.clone())?;
+    let count = conn.execute(
+        "UPDATE saved_searches SET used_at = CAST(strftime('%s','now') AS INTEGER) \
+        WHERE saved_search_id = :saved_search_id",
+        named_params! { ":saved_search_id": saved_search_id },
+    )?;
+    if count == 0 {
+        return Err(ApiError::NotFound(
+            "saved_search_viewed_service_02",
+            format!("Saved search {} not found", saved_search_id),
+        ));
+    }
+    let mut saved_search = conn.query_row(
+        &format!(
+            "{} WHERE saved_search_id = :saved_search_id",
+            SAVED_SEARCH_SQL
+        ),
+        named_params! { ":saved_search_id": saved_search_id },
+        row_saved_search,
+    )?;
+    saved_search.new_count = count_new_feeds(&conn, &saved_search, time_offset)?;
+    Ok(HttpResponse::Ok().json(saved_search))
+}
+
+/// Feeds of a saved search added to the database after it was last viewed, or saved when
+/// it was never viewed
+fn count_new_feeds(
+    conn: &Connection,
+    saved_search: &SavedSearch,
+    time_offset: i32,
+) -> Result<i64, ApiError> {
+    let mut filter = get_feeds_filter(&saved_search.query, time_offset)?;
+    let p = filter.bind(saved_search.used_at.unwrap_or(saved_search.created_at));
+    filter.push(format!("f.created_at > {}", p));
+    Ok(conn
+        .prepare_cached(&get_feeds_count_query(&filter))?
+        .query_row(&filter.params()[..], |row| row.get(0))?)
+}
+
+fn row_saved_search(row: &rusqlite::Row) -> SqlResult<SavedSearch> {
+    let query: String = row.get(2)?;
+    Ok(SavedSearch {
+        saved_search_id: row.get(0)?,
+        name: row.get(1)?,
+        query: serde_json::from_str(&query).map_err(|err| {
+            rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(err))
+        })?,
+        created_at: row.get(3)?,
+        used_at: row.get(4)?,
+        new_count: 0,
+    })
+}
+
+#[get("/a/collections")]
+async fn collections_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let conn = get_read_conn(data.clone())?;
+    let collections: Vec<Collection> = conn
+        .prepare(&format!(
+            "{} GROUP BY c.collection_id ORDER BY c.name",
+            COLLECTION_SQL
+        ))
+        .and_then(|mut stmt| {
+            stmt.query_map([], row_collection)
+                .and_then(Iterator::collect)
+        })?;
+    Ok(HttpResponse::Ok().json(CollectionsResponse { collections }))
+}
+
+#[post("/a/collections")]
+async fn collection_create_service(
+    (form, data): (web::Form<CollectionForm>, web::Data<AppState>),
+) -> Result<HttpResponse, ApiError> {
+    let form = form.into_inner();
+    println!("collection_create_service {:?}", form);
+    let file_path = match form.file_path.filter(|v| !v.is_empty()) {
+        Some(file_path) => file_path,
+        None => {
+            return Err(ApiError::BadRequest(
+                "collection_create_service_01",
+                String::from("file_path is required"),
+            ))
+        }
+    };
+    let kind = match form.kind.map(|v| v.to_ascii_lowercase()) {
+        Some(kind) if COLLECTION_KINDS.contains(&kind.as_str()) => kind,
+        _ => {
+            return Err(ApiError::BadRequest(
+                "collection_create_service_02",
+                format!("kind must be one of {}", COLLECTION_KINDS.join(", ")),
+            ))
+        }
+    };
+
+    let mut conn = get_conn(data.clone())?;
+    let txn = conn.transaction()?;
+    let origin: Option<String> = match txn.query_row(
+        "SELECT origin FROM files WHERE file_path = :file_path",
+        named_params! { ":file_path": file_path },
+        |row| row.get(0),
+    ) {
+        Ok(origin) => origin,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(ApiError::NotFound(
+                "collection_create_service_03",
+                format!("Archive {} not found", file_path),
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let name = form
+        .name
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| match origin {
+            Some(origin) => format!("{} {}", origin, kind),
+            None => file_path.clone(),
+        });
+    // Tagging an archive again renames it and fills in entries scanned since
+    let collection_id: i64 = txn.query_row(
+        "INSERT INTO collections (name, kind, file_path) VALUES (:name, :kind, :file_path) \
+        ON CONFLICT (file_path) DO UPDATE SET name = excluded.name, kind = excluded.kind \
+        RETURNING collection_id",
+        named_params! { ":name": name, ":kind": kind, ":file_path": file_path },
+        |row| row.get(0),
+    )?;
+    txn.execute(
+        FILL_COLLECTIONS_SQL,
+        named_params! { ":file_path": file_path },
+    )?;
+    let collection = txn.query_row(
+        &format!(
+            "{} WHERE c.collection_id = :collection_id GROUP BY c.collection_id",
+            COLLECTION_SQL
+        ),
+        named_params! { ":collection_id": collection_id },
+        row_collection,
+    )?;
+    txn.commit()?;
+
+    Ok(HttpResponse::Created().json(collection))
+}
+
+#[delete("/a/collections/{collection_id}")]
+async fn collection_delete_service(
+    web::Path(param_collection_id): web::Path<String>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let collection_id = parse_path_id("collection_delete_service_01", &param_collection_id)?;
+    println!("collection_delete_service {:?}", collection_id);
+    let mut conn = get_conn(data.clone())?;
+    let txn = conn.transaction()?;
+    txn.execute(
+        "DELETE FROM collection_feeds WHERE collection_id = :collection_id",
+        named_params! { ":collection_id": collection_id },
+    )?;
+    let count = txn.execute(
+        "DELETE FROM collections WHERE collection_id = :collection_id",
+        named_params! { ":collection_id": collection_id },
+    )?;
+    if count == 0 {
+        return Err(ApiError::NotFound(
+            "collection_delete_service_02",
+            format!("Collection {} not found", collection_id),
+        ));
+    }
+    txn.commit()?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/a/collections/{collection_id}/feeds")]
+async fn collection_feeds_service(
+    web::Path(param_collection_id): web::Path<String>,
+    web_query: web::Query<CollectionFeedsQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let collection_id = parse_path_id("collection_feeds_service_01", &param_collection_id)?;
+    let mut query = web_query.into_inner();
+    query.page = Some(query.page.unwrap_or(DEFAULT_PAGE));
+    query.count = Some(query.count.unwrap_or(DEFAULT_PAGE_COUNT));
+    let conn = get_read_conn(data.clone())?;
+    let collection = match conn.query_row(
+        &format!(
+            "{} WHERE c.collection_id = :collection_id GROUP BY c.collection_id",
+            COLLECTION_SQL
+        ),
+        named_params! { ":collection_id": collection_id },
+        row_collection,
+    ) {
+        Ok(collection) => collection,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(ApiError::NotFound(
+                "collection_feeds_service_02",
+                format!("Collection {} not found", collection_id),
+            ))
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    // Latest liked first, entries without an action time last
+    let mut feeds: Vec<CollectionFeed> = conn
         .prepare_cached(
-            "SELECT \
+            "SELECT cf.collected_at, cf.feed_id, cf.user_name, cf.twitter_url, \
+            o.feed_at, o.twitter_url, o.contents \
+            FROM collection_feeds cf \
+            LEFT JOIN feeds o \
+            ON o.feed_id = cf.feed_id AND o.user_name = cf.user_name \
+            AND o.retweet_id = 0 AND o.retweet_user_name = '' \
+            WHERE cf.collection_id = :collection_id \
+            ORDER BY cf.collected_at IS NULL, cf.collected_at DESC, cf.feed_id DESC \
+            LIMIT :limit OFFSET :offset",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(
+                named_params! {
+                    ":collection_id": collection_id,
+                    ":limit": i64::from(query.count.unwrap()),
+                    ":offset": i64::from(query.page.unwrap()) * i64::from(query.count.unwrap()),
+                },
+                |row| {
+                    let feed_at: Option<i64> = row.get(4)?;
+                    Ok(CollectionFeed {
+                        collected_at: row.get(0)?,
+                        feed_id: row.get(1)?,
+                        user_name: row.get(2)?,
+                        twitter_url: row.get(3)?,
+                        feed: match feed_at {
+                            Some(feed_at) => Some(FeedType::Feed {
+                                feed_id: row.get(1)?,
+                                feed_at,
+                                user_name: row.get(2)?,
+                                twitter_url: row.get(5)?,
+                                contents: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+                                media: None,
+                                notes: None,
+                            }),
+                            None => None,
+                        },
+                    })
+                },
+            )
+            .and_then(Iterator::collect)
+        })?;
+    for collection_feed in feeds.iter_mut() {
+        if let Some(FeedType::Feed { feed_id, media, .. }) = collection_feed.feed.as_mut() {
+            *media = get_feed_media(&conn, *feed_id)?;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(CollectionFeedsResponse {
+        query,
+        collection,
+        feeds,
+    }))
+}
+
+fn row_collection(row: &rusqlite::Row) -> SqlResult<Collection> {
+    Ok(Collection {
+        collection_id: row.get(0)?,
+        name: row.get(1)?,
+        kind: row.get(2)?,
+        file_path: row.get(3)?,
+        created_at: row.get(4)?,
+        feed_count: row.get(5)?,
+        last_collected_at: row.get(6)?,
+    })
+}
+
+fn row_media_metadata(row: &rusqlite::Row, start: usize) -> SqlResult<Option<MediaMetadata>> {
+    let byte_size: Option<i64> = row.get(start + 2)?;
+    match byte_size {
+        Some(byte_size) => Ok(Some(MediaMetadata {
+            width: row.get(start)?,
+            height: row.get(start + 1)?,
+            byte_size,
+            file_format: row.get(start + 3)?,
+            duration_ms: row.get(start + 4)?,
+            dominant_color: row.get(start + 5)?,
+        })),
+        None => Ok(None),
+    }
+}
+
+/// Filter on media metadata, for media aliased as `mm`
+fn media_metadata_clause(
+    orientation: &Option<String>,
+    min_width: &Option<u32>,
+    min_height: &Option<u32>,
+) -> Result<Option<String>, ApiError> {
+    let mut clauses: Vec<&str> = Vec::new();
+    match orientation.as_deref() {
+        None | Some("") => {}
+        Some("portrait") => clauses.push("mm.height > mm.width"),
+        Some("landscape") => clauses.push("mm.width > mm.height"),
+        Some("square") => clauses.push("mm.width = mm.height"),
+        Some(_) => {
+            return Err(ApiError::BadRequest(
+                "media_metadata_01",
+                String::from("orientation must be one of portrait, landscape, square"),
+            ))
+        }
+    };
+    if min_width.is_some() {
+        clauses.push("mm.width >= :min_width");
+    }
+    if min_height.is_some() {
+        clauses.push("mm.height >= :min_height");
+    }
+    if clauses.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(clauses.join(" AND ")))
+    }
+}
+
+fn get_feed_media(
+    conn: &PooledConnection<SqliteConnectionManager>,
+    media_feed_id: i64,
+) -> SqlResult<Option<Vec<Media>>> {
+    let mut media_stmt = conn.prepare(&format!(
+        "SELECT \
+                m.feed_id, m.media_id, m.media_type, m.media_url, m.file_path, m.media_path, \
+                m.thumbnail, m.deleted_at, {metadata_columns} \
+                FROM media m \
+                LEFT JOIN media_metadata mm \
+                ON m.feed_id = mm.feed_id AND m.media_id = mm.media_id \
+                WHERE m.feed_id = :media_feed_id",
+        metadata_columns = MEDIA_METADATA_COLUMNS
+    ))?;
+    let media_list: Vec<Media> = media_stmt
+        .query_map(
+            named_params! {
+                ":media_feed_id": media_feed_id,
+            },
+            |row| {
+                Ok(Media {
+                    feed_id: row.get(0)?,
+                    media_id: row.get(1)?,
+                    media_type: row.get(2)?,
+                    media_url: row.get(3)?,
+                    file_path: row.get(4)?,
+                    media_path: row.get(5)?,
+                    thumbnail: row.get(6)?,
+                    deleted_at: row.get(7)?,
+                    metadata: row_media_metadata(row, 8)?,
+                })
+            },
+        )
+        .and_then(Iterator::collect)?;
+    if media_list.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(media_list))
+    }
+}
+
+#[get("/a/state")]
+async fn app_state_service(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(state(data.clone()))
+}
+
+#[get("/")]
+async fn home_service() -> impl Responder {
+    return HttpResponse::Ok()
+        .header(CONTENT_TYPE, TEXT_HTML)
+        .body(Bytes::from_static(include_bytes!("../static/index.html")));
+}
+
+#[get("/a/media/file/{feed_id}/{media_id}")]
+async fn media_file_service(
+    web::Path((param_feed_id, param_media_id)): web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    println!(
+        "media_file_service {:?} {:?}",
+        param_feed_id, param_media_id
+    );
+    let (feed_id, media_id) =
+        parse_path_ids("media_file_service_01", &param_feed_id, &param_media_id)?;
+    // println!("media_file_service {:?} {:?}", feed_id, media_id);
+
+    let media = get_media(data.clone(), feed_id, media_id)?;
+    let (buf, _size, mime_type) = extract_zip_file(
+        data.data_dir.read().unwrap().to_string(),
+        media.file_path.to_string(),
+        media.media_path.to_string(),
+    )?;
+    Ok(HttpResponse::Ok().header(CONTENT_TYPE, mime_type).body(buf))
+}
+
+#[get("/a/media/preview/{feed_id}/{media_id}")]
+async fn media_preview_service(
+    web::Path((param_feed_id, param_media_id)): web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    println!(
+        "media_preview_service {:?} {:?}",
+        param_feed_id, param_media_id
+    );
+    let (feed_id, media_id) =
+        parse_path_ids("media_preview_service_01", &param_feed_id, &param_media_id)?;
+
+    let mut media = get_media(data.clone(), feed_id, media_id)?;
+    if "Image" != media.media_type || media.deleted_at.is_some() {
+        return Err(ApiError::NotFound(
+            "media_preview_service_02",
+            String::from("Preview is only available for images that are not deleted"),
+        ));
+    }
+    if let Some(buf) = media.thumbnail.take() {
+        return Ok(HttpResponse::Ok()
+            .header(CONTENT_TYPE, IMAGE_JPEG)
+            .body(buf));
+    }
+    if let Some(buf) = read_cached_thumbnail(data.clone(), feed_id, media_id) {
+        return Ok(HttpResponse::Ok()
+            .header(CONTENT_TYPE, IMAGE_JPEG)
+            .body(buf));
+    }
+
+    let (image_blob, _size, _mime_type) = extract_zip_file(
+        data.data_dir.read().unwrap().to_string(),
+        media.file_path.to_string(),
+        media.media_path.to_string(),
+    )?;
+
+    let thumbnail = match generate_thumbnail_blob(&image_blob) {
+        Ok(thumbnail) => thumbnail,
+        Err(err) => {
+            println!("generate_thumbnail_blob update failed: {:?}", err);
+            return Err(MediaCheckError::Undecodable(err.to_string()).into());
+        }
+    };
+    media.thumbnail = Some(thumbnail.blob);
+    match update_media_hash(data.clone(), &media, thumbnail.phash) {
+        Ok(()) => {}
+        Err(err) => println!("update_media_hash update failed: {:?}", err),
+    };
+    match update_media_metadata(data.clone(), &media, &thumbnail.metadata) {
+        Ok(()) => {}
+        Err(err) => println!("update_media_metadata update failed: {:?}", err),
+    };
+    update_media_thumbnail(data.clone(), &media)?;
+
+    Ok(HttpResponse::Ok()
+        .header(CONTENT_TYPE, IMAGE_JPEG)
+        .body(media.thumbnail.unwrap()))
+}
+
+fn parse_path_ids(
+    code: &'static str,
+    param_feed_id: &str,
+    param_media_id: &str,
+) -> Result<(i64, i64), ApiError> {
+    match (param_feed_id.parse::<i64>(), param_media_id.parse::<i64>()) {
+        (Ok(feed_id), Ok(media_id)) => Ok((feed_id, media_id)),
+        _ => Err(ApiError::BadRequest(
+            code,
+            String::from("feed_id and media_id must be integers"),
+        )),
+    }
+}
+
+/// Query strings that do not fit the query of a handler, answered like other bad requests
+fn query_error_handler(err: QueryPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::BadRequest("request_01", err.to_string()).into()
+}
+
+fn form_error_handler(err: UrlencodedError, _req: &HttpRequest) -> actix_web::Error {
+    ApiError::BadRequest("request_02", err.to_string()).into()
+}
+
+fn parse_path_id(code: &'static str, param_id: &str) -> Result<i64, ApiError> {
+    param_id
+        .parse::<i64>()
+        .map_err(|_err| ApiError::BadRequest(code, String::from("id must be an integer")))
+}
+
+fn get_media(data: web::Data<AppState>, feed_id: i64, media_id: i64) -> Result<Media, ApiError> {
+    let conn = get_read_conn(data.clone())?;
+    let mut stmt = conn.prepare_cached(
+        "SELECT \
             feed_id, media_id, media_type, media_url, file_path, media_path, thumbnail, deleted_at \
             FROM media \
             WHERE feed_id = :feed_id AND media_id = :media_id \
             LIMIT 1",
-        )
-        .unwrap();
-    stmt.query_row(
+    )?;
+    let media = stmt.query_row(
         named_params! {
             ":feed_id": feed_id,
             ":media_id": media_id,
         },
         |row| {
             Ok(Media {
-                feed_id: row.get(0).unwrap(),
-                media_id: row.get(1).unwrap(),
-                media_type: row.get(2).unwrap(),
-                media_url: row.get(3).unwrap(),
-                file_path: row.get(4).unwrap(),
-                media_path: row.get(5).unwrap(),
-                thumbnail: row.get(6).unwrap(),
-                deleted_at: row.get(7).unwrap(),
+                feed_id: row.get(0)?,
+                media_id: row.get(1)?,
+                media_type: row.get(2)?,
+                media_url: row.get(3)?,
+                file_path: row.get(4)?,
+                media_path: row.get(5)?,
+                thumbnail: row.get(6)?,
+                deleted_at: row.get(7)?,
+                metadata: None,
+            })
+        },
+    );
+    match media {
+        Ok(media) => Ok(media),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(ApiError::NotFound(
+            "media_01",
+            format!("Media {}/{} not found", feed_id, media_id),
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[get("/a/media")]
+async fn media_service(
+    web_query: web::Query<MediaQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut query = web_query.into_inner();
+    query.user_name = fix_user_name(&query.user_name);
+    query.page = Some(query.page.unwrap_or(DEFAULT_PAGE));
+    query.count = Some(query.count.unwrap_or(DEFAULT_PAGE_COUNT));
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+
+    let mut where_clauses: Vec<&str> = Vec::new();
+    let mut media_params: Vec<(&str, &dyn ToSql)> = Vec::new();
+    if query.user_name.is_some() {
+        where_clauses.push("lower(f.user_name) = lower(:user_name)");
+        media_params.push((":user_name", &query.user_name));
+    }
+    if query.media_type.as_ref().is_some_and(|v| !v.is_empty()) {
+        where_clauses.push("m.media_type = :media_type COLLATE NOCASE");
+        media_params.push((":media_type", &query.media_type));
+    }
+    if query.file_path.as_ref().is_some_and(|v| !v.is_empty()) {
+        where_clauses.push("m.file_path = :file_path");
+        media_params.push((":file_path", &query.file_path));
+    }
+    let (since, until) =
+        parse_query_range("media_service_01", &query.since, &query.until, time_offset)?;
+    if since.is_some() {
+        where_clauses.push("f.feed_at >= :since");
+        media_params.push((":since", &since));
+    }
+    if until.is_some() {
+        where_clauses.push("f.feed_at < :until");
+        media_params.push((":until", &until));
+    }
+    let metadata_clause =
+        media_metadata_clause(&query.orientation, &query.min_width, &query.min_height)?;
+    if let Some(clause) = metadata_clause.as_ref() {
+        where_clauses.push(clause);
+    }
+    if query.min_width.is_some() {
+        media_params.push((":min_width", &query.min_width));
+    }
+    if query.min_height.is_some() {
+        media_params.push((":min_height", &query.min_height));
+    }
+    match query.has_thumbnail {
+        Some(true) => where_clauses.push(HAS_THUMBNAIL_SQL),
+        Some(false) => where_clauses.push(HAS_NO_THUMBNAIL_SQL),
+        None => {}
+    };
+    let offset = i64::from(query.page.unwrap()) * i64::from(query.count.unwrap());
+    let limit = i64::from(query.count.unwrap());
+    media_params.push((":offset", &offset));
+    media_params.push((":limit", &limit));
+
+    let where_clause: String = if where_clauses.is_empty() {
+        String::from("")
+    } else {
+        format!("WHERE {}", where_clauses.join(" AND "))
+    };
+    let sql = format!(
+        "SELECT \
+        m.feed_id, m.media_id, m.media_type, f.feed_at, f.user_name, f.twitter_url, \
+        m.file_path, m.deleted_at, {has_thumbnail}, {metadata_columns} \
+        FROM media m \
+        INNER JOIN feeds f \
+        ON m.feed_id = f.feed_id AND f.retweet_id = 0 \
+        LEFT JOIN media_metadata mm \
+        ON m.feed_id = mm.feed_id AND m.media_id = mm.media_id \
+        {where_clause} \
+        ORDER BY f.feed_at DESC, m.feed_id DESC, m.media_id ASC \
+        LIMIT :limit OFFSET :offset",
+        has_thumbnail = HAS_THUMBNAIL_SQL,
+        metadata_columns = MEDIA_METADATA_COLUMNS,
+        where_clause = where_clause
+    );
+
+    let conn = get_read_conn(data.clone())?;
+    let mut stmt = conn.prepare_cached(&sql)?;
+    let media_result: SqlResult<Vec<MediaItem>> = stmt
+        .query_map(&media_params[..], |row| {
+            Ok(MediaItem {
+                feed_id: row.get(0)?,
+                media_id: row.get(1)?,
+                media_type: row.get(2)?,
+                feed_at: row.get(3)?,
+                user_name: row.get(4)?,
+                twitter_url: row.get(5)?,
+                file_path: row.get(6)?,
+                deleted_at: row.get(7)?,
+                has_thumbnail: row.get(8)?,
+                metadata: row_media_metadata(row, 9)?,
+            })
+        })
+        .and_then(Iterator::collect);
+    let media = media_result?;
+
+    Ok(HttpResponse::Ok().json(MediaResponse { query, media }))
+}
+
+#[get("/a/media/duplicates")]
+async fn media_duplicates_service(
+    web_query: web::Query<DuplicatesQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let mut query = web_query.into_inner();
+    query.max_distance = Some(
+        query
+            .max_distance
+            .unwrap_or(DEFAULT_SIMILAR_DISTANCE)
+            .min(phash::MAX_DISTANCE),
+    );
+    query.page = Some(query.page.unwrap_or(DEFAULT_PAGE));
+    query.count = Some(query.count.unwrap_or(DEFAULT_PAGE_COUNT));
+
+    let max_distance = query.max_distance.unwrap();
+    // Read before the hashes, a change while clustering makes the next request start over
+    let media_version = *data.media_version.read().unwrap();
+    let cached = data
+        .duplicate_clusters
+        .read()
+        .unwrap()
+        .as_ref()
+        .filter(|cached| {
+            cached.media_version == media_version && cached.max_distance == max_distance
+        })
+        .map(|cached| cached.clusters.clone());
+    let all_clusters = match cached {
+        Some(clusters) => clusters,
+        None => {
+            let conn = get_read_conn(data.clone())?;
+            let clusters = Arc::new(read_duplicate_clusters(&conn, max_distance)?);
+            *data.duplicate_clusters.write().unwrap() = Some(DuplicateClusters {
+                media_version,
+                max_distance,
+                clusters: clusters.clone(),
+            });
+            clusters
+        }
+    };
+    let clusters: Vec<MediaCluster> = all_clusters
+        .iter()
+        .skip((query.page.unwrap().max(0) * query.count.unwrap().max(0)) as usize)
+        .take(query.count.unwrap().max(0) as usize)
+        .cloned()
+        .collect();
+
+    Ok(HttpResponse::Ok().json(DuplicatesResponse { query, clusters }))
+}
+
+/// Every cluster of media with similar hashes, biggest first
+fn read_duplicate_clusters(
+    conn: &Connection,
+    max_distance: u32,
+) -> Result<Vec<MediaCluster>, ApiError> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT \
+            m.feed_id, m.media_id, m.media_type, m.media_url, m.file_path, m.media_path, h.phash \
+            FROM media_hashes h \
+            INNER JOIN media m \
+            ON h.feed_id = m.feed_id AND h.media_id = m.media_id \
+            WHERE m.deleted_at IS NULL \
+            ORDER BY m.feed_id, m.media_id",
+    )?;
+    let rows: SqlResult<Vec<(Media, i64)>> = stmt
+        .query_map([], |row| {
+            Ok((
+                Media {
+                    feed_id: row.get(0)?,
+                    media_id: row.get(1)?,
+                    media_type: row.get(2)?,
+                    media_url: row.get(3)?,
+                    file_path: row.get(4)?,
+                    media_path: row.get(5)?,
+                    thumbnail: None,
+                    deleted_at: None,
+                    metadata: None,
+                },
+                row.get(6)?,
+            ))
+        })
+        .and_then(Iterator::collect);
+    let rows = rows?;
+
+    let hashes: Vec<u64> = rows.iter().map(|(_, phash)| *phash as u64).collect();
+    let mut rows: Vec<Option<(Media, i64)>> = rows.into_iter().map(Some).collect();
+    Ok(phash::clusters(&hashes, max_distance)
+        .into_iter()
+        .map(|indices| {
+            let media: Vec<(Media, i64)> = indices
+                .iter()
+                .filter_map(|index| rows[*index].take())
+                .collect();
+            MediaCluster {
+                phash: media[0].1,
+                media: media.into_iter().map(|(m, _)| m).collect(),
+            }
+        })
+        .collect())
+}
+
+/// Media or their hashes were written, cached duplicate clusters are out of date
+fn media_changed(data: &AppState) {
+    *data.media_version.write().unwrap() += 1;
+}
+
+#[get("/a/zip/{zip_file_name}/{file_name:.*}")]
+async fn zip_service(
+    web::Path((zip_file_name, file_name)): web::Path<(String, String)>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    println!("zip_service {} {}", zip_file_name, file_name);
+    let (buf, _size, mime_type) = extract_zip_file(
+        data.data_dir.read().unwrap().to_string(),
+        zip_file_name,
+        file_name,
+    )?;
+    Ok(HttpResponse::Ok().header(CONTENT_TYPE, mime_type).body(buf))
+}
+
+fn extract_zip_file(
+    data_dir: String,
+    zip_path: String,
+    file_path: String,
+) -> Result<(Vec<u8>, usize, Mime), MediaCheckError> {
+    let zip_path = PathBuf::from(&data_dir).join(&zip_path);
+    if !zip_path.is_file() {
+        return Err(MediaCheckError::MissingArchive);
+    }
+    let zip_file =
+        File::open(zip_path).map_err(|err| MediaCheckError::UnreadableArchive(err.to_string()))?;
+    let mut zip = ZipArchive::new(zip_file)
+        .map_err(|err| MediaCheckError::UnreadableArchive(err.to_string()))?;
+    let mut f = zip
+        .by_name(&file_path)
+        .map_err(MediaCheckError::from_entry_error)?;
+    if !f.is_file() {
+        return Err(MediaCheckError::NotAFile);
+    }
+    let path = PathBuf::from(&file_path);
+    let ext = path.extension().and_then(OsStr::to_str).unwrap_or("");
+    let mut buf: Vec<u8> = Vec::new();
+    let buf_size = f
+        .read_to_end(&mut buf)
+        .map_err(|err| MediaCheckError::UnreadableEntry(err.to_string()))?;
+    Ok((buf, buf_size, file_extension_to_mime(ext)))
+}
+
+#[post("/a/set_data_dir")]
+async fn set_data_dir_service(
+    (query, data): (web::Form<SetDataDirForm>, web::Data<AppState>),
+) -> Result<HttpResponse, ApiError> {
+    println!("/a/set_data_dir");
+    let query = query.into_inner();
+    let SetDataDirForm { data_dir } = query;
+    match data_dir {
+        Some(value) => {
+            println!("data_dir={}", value);
+            *data.data_dir.write().unwrap() = value.to_string();
+
+            // Write config file
+            let config = AppConfig {
+                data_dir: Some(value.to_string()),
+                bind_address: Some(data.bind_address.read().unwrap().clone()),
+                time_offset: Some(data.time_offset),
+                scanner_count_limit: Some(data.scanner_count_limit),
+                thumbnail_cache_dir: data
+                    .thumbnail_cache
+                    .as_ref()
+                    .map(|cache| cache.dir().to_string_lossy().to_string()),
+                thumbnail_cache_size_limit: data
+                    .thumbnail_cache
+                    .as_ref()
+                    .map(|cache| cache.size_limit() / ONE_MB_U64),
+                database_journal_mode: Some(data.connection_options.journal_mode.clone()),
+                database_busy_timeout: Some(data.connection_options.busy_timeout_ms),
+                database_synchronous: Some(data.connection_options.synchronous.clone()),
+                database_foreign_keys: Some(data.connection_options.foreign_keys),
+                database_cache_size: Some(data.connection_options.cache_size_kb),
+            };
+            let config_str = serde_yaml::to_string(&config).unwrap();
+            println!("write config: {:?}", config_str);
+            let config_path = data.config_path.read().unwrap();
+            fs::write(config_path.clone(), config_str)
+                .map_err(|err| ApiError::Io("set_data_dir_service_01", err))?;
+
+            Ok(HttpResponse::Ok().json(state(data.clone())))
+        }
+        None => Ok(HttpResponse::NotModified().body("")),
+    }
+}
+
+#[post("/a/generate_thumbnails")]
+async fn generate_thumbnails_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if *data.scanner_count.read().unwrap() >= data.scanner_count_limit
+        || *data.is_maintaining.read().unwrap()
+    {
+        return Ok(HttpResponse::TooManyRequests().json(state(data.clone())));
+    }
+    println!(
+        "/a/generate_thumbnails start {} {:?}",
+        data.scanner_count.read().unwrap(),
+        data.data_dir.read().unwrap().to_string()
+    );
+    open_db(data.clone())?;
+    *data.scanner_count.write().unwrap() += 1;
+
+    // Generate all thumbnails
+    generate_thumbnails(data.clone()).await;
+
+    Ok(HttpResponse::Accepted().json(state(data.clone())))
+}
+
+async fn generate_thumbnails(data: web::Data<AppState>) {
+    println!("generate_thumbnails");
+    let thread_data = data.clone();
+    // Walk media in key order, so that media that failed or got evicted from the
+    // thumbnail cache while this job is running are not picked up again
+    let mut last_key = (0i64, 0i64);
+    thread::spawn(move || {
+        loop {
+            match pick_thumbnail_media(thread_data.clone(), last_key) {
+                Ok(Some(mut media)) => {
+                    last_key = (media.feed_id, media.media_id);
+                    generate_thumbnail(thread_data.clone(), &mut media);
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    println!("generate_thumbnails failed picking media: {:?}", err);
+                    break;
+                }
+            };
+        }
+        *data.scanner_count.write().unwrap() -= 1;
+    });
+}
+
+/// Next media after `last_key` missing a thumbnail, a hash or metadata.
+/// Thumbnails evicted from the cache are left to media_preview_service.
+fn pick_thumbnail_media(
+    data: web::Data<AppState>,
+    last_key: (i64, i64),
+) -> Result<Option<Media>, ApiError> {
+    let conn = get_conn(data)?;
+    let mut pick_media_stmt = conn.prepare_cached(
+        "SELECT \
+        feed_id, media_id, media_type, media_url, file_path, media_path \
+        FROM media m \
+        WHERE deleted_at IS NULL \
+        AND ((media_type = 'Image' \
+            AND ((thumbnail IS NULL \
+                AND NOT EXISTS (SELECT t.feed_id FROM thumbnails t \
+                    WHERE t.feed_id = m.feed_id AND t.media_id = m.media_id) \
+                AND NOT EXISTS (SELECT e.feed_id FROM evicted_thumbnails e \
+                    WHERE e.feed_id = m.feed_id AND e.media_id = m.media_id)) \
+                OR NOT EXISTS (SELECT h.feed_id FROM media_hashes h \
+                    WHERE h.feed_id = m.feed_id AND h.media_id = m.media_id))) \
+            OR NOT EXISTS (SELECT mm.feed_id FROM media_metadata mm \
+                WHERE mm.feed_id = m.feed_id AND mm.media_id = m.media_id)) \
+        AND (feed_id, media_id) > (:last_feed_id, :last_media_id) \
+        ORDER BY feed_id, media_id \
+        LIMIT 1",
+    )?;
+    match pick_media_stmt.query_row(
+        named_params! {
+            ":last_feed_id": last_key.0,
+            ":last_media_id": last_key.1,
+        },
+        |row| {
+            Ok(Media {
+                feed_id: row.get(0)?,
+                media_id: row.get(1)?,
+                media_type: row.get(2)?,
+                media_url: row.get(3)?,
+                file_path: row.get(4)?,
+                media_path: row.get(5)?,
+                thumbnail: None,  // Filtered out
+                deleted_at: None, // Filtered out
+                metadata: None,
             })
         },
+    ) {
+        Ok(media) => Ok(Some(media)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn generate_thumbnail(data: web::Data<AppState>, media: &mut Media) {
+    println!(
+        "generate_thumbnail for {:?} {:?}",
+        &media.feed_id, &media.media_id
+    );
+    let mut start = SystemTime::now();
+    let media_blob = match extract_zip_file(
+        data.data_dir.read().unwrap().to_string(),
+        media.file_path.to_string(),
+        media.media_path.to_string(),
+    ) {
+        Ok((buf, _size, _mime_type)) => buf,
+        Err(err) => {
+            println!("generate_thumbnail read failed: {:?}", err);
+            match soft_delete_media_thumbnail(data.clone(), media, &err) {
+                Ok(()) => {}
+                Err(err) => {
+                    println!("soft_delete_media_thumbnail failed: {:?}", err);
+                }
+            };
+            return;
+        }
+    };
+
+    if "Image" != media.media_type {
+        // No thumbnails for video, only metadata
+        let metadata = get_video_metadata(&media.media_path, &media_blob);
+        match update_media_metadata(data.clone(), media, &metadata) {
+            Ok(()) => {}
+            Err(err) => {
+                println!("update_media_metadata update failed: {:?}", err);
+            }
+        };
+        return;
+    }
+
+    match generate_thumbnail_blob(&media_blob) {
+        Ok(thumbnail) => {
+            media.thumbnail = Some(thumbnail.blob);
+            match update_media_hash(data.clone(), media, thumbnail.phash) {
+                Ok(()) => {}
+                Err(err) => {
+                    println!("update_media_hash update failed: {:?}", err);
+                }
+            };
+            match update_media_metadata(data.clone(), media, &thumbnail.metadata) {
+                Ok(()) => {}
+                Err(err) => {
+                    println!("update_media_metadata update failed: {:?}", err);
+                }
+            };
+        }
+        Err(err) => {
+            println!("generate_thumbnail_blob update failed: {:?}", err);
+        }
+    };
+
+    match update_media_thumbnail(data.clone(), &media) {
+        Ok(()) => {}
+        Err(err) => {
+            println!("update_media_thumbnail update failed: {:?}", err);
+        }
+    };
+}
+
+fn generate_thumbnail_blob(blob: &Vec<u8>) -> Result<Thumbnail, image::ImageError> {
+    let last_time = SystemTime::now();
+
+    let img_reader = ImageReader::new(Cursor::new(blob))
+        .with_guessed_format()
+        .expect("std::io::Cursor never fails");
+    let file_format = img_reader
+        .format()
+        .map(|format| format!("{:?}", format).to_ascii_lowercase());
+    let mut img = img_reader.decode()?;
+    let (width, height) = (img.width(), img.height());
+
+    // image.thumbnail average 9sec!
+    img = img.thumbnail(128u32, 128u32);
+
+    // Hashing the thumbnail is good enough for finding duplicates, and much cheaper
+    let phash = phash::dhash(&img);
+    let metadata = MediaMetadata {
+        width: Some(width),
+        height: Some(height),
+        byte_size: blob.len() as i64,
+        file_format,
+        duration_ms: None,
+        dominant_color: Some(media_info::dominant_color(&img)),
+    };
+
+    // crop and resize
+    // let cropped_size = std::cmp::min(img.width(), img.height());
+    // if img.width() >= img.height() {
+    //     let offset = img.width() - cropped_size / 2;
+    //     img = img.crop(offset, offset + cropped_size, 0u32, cropped_size);
+    // } else {
+    //     let offset = img.height() - cropped_size / 2;
+    //     img = img.crop(0u32, cropped_size, offset, offset + cropped_size);
+    // }
+    // img = img.resize(128u32, 128u32, image::imageops::FilterType::Lanczos3);
+
+    let mut img_blob: Vec<u8> = Vec::new();
+    match img.write_to(
+        &mut Cursor::new(&mut img_blob),
+        ImageOutputFormat::Jpeg(85u8),
+    ) {
+        Ok(_) => {
+            let last_time_duration = SystemTime::now()
+                .duration_since(last_time)
+                .unwrap()
+                .as_millis();
+            // println!(
+            //     "generate_thumbnail_bytes took {:?} [ms]",
+            //     last_time_duration
+            // );
+            Ok(Thumbnail {
+                blob: img_blob,
+                phash,
+                metadata,
+            })
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Metadata of media other than images, from what can be read without decoding
+fn get_video_metadata(media_path: &str, blob: &[u8]) -> MediaMetadata {
+    let info = media_info::video_info(blob).unwrap_or_default();
+    MediaMetadata {
+        width: info.width,
+        height: info.height,
+        byte_size: blob.len() as i64,
+        file_format: PathBuf::from(media_path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase()),
+        duration_ms: info.duration_ms,
+        dominant_color: None,
+    }
+}
+
+fn update_media_metadata(
+    data: web::Data<AppState>,
+    media: &Media,
+    metadata: &MediaMetadata,
+) -> Result<(), ApiError> {
+    let conn = get_conn(data.clone())?;
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR REPLACE INTO media_metadata \
+        (feed_id, media_id, width, height, byte_size, file_format, duration_ms, dominant_color) \
+        VALUES (:feed_id, :media_id, :width, :height, :byte_size, :file_format, :duration_ms, \
+        :dominant_color)",
+    )?;
+    stmt.execute(named_params! {
+        ":feed_id": media.feed_id,
+        ":media_id": media.media_id,
+        ":width": metadata.width,
+        ":height": metadata.height,
+        ":byte_size": metadata.byte_size,
+        ":file_format": metadata.file_format,
+        ":duration_ms": metadata.duration_ms,
+        ":dominant_color": metadata.dominant_color,
+    })?;
+    Ok(())
+}
+
+fn update_media_hash(data: web::Data<AppState>, media: &Media, phash: u64) -> Result<(), ApiError> {
+    let conn = get_conn(data.clone())?;
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR REPLACE INTO media_hashes (feed_id, media_id, phash) \
+        VALUES (:feed_id, :media_id, :phash)",
+    )?;
+    stmt.execute(named_params! {
+        ":feed_id": media.feed_id,
+        ":media_id": media.media_id,
+        ":phash": phash as i64,
+    })?;
+    media_changed(&data);
+    Ok(())
+}
+
+fn update_media_thumbnail(data: web::Data<AppState>, media: &Media) -> Result<(), ApiError> {
+    if data.thumbnail_cache.is_some() && media.thumbnail.is_some() {
+        return store_cached_thumbnail(data.clone(), media);
+    }
+    let conn = &mut get_conn(data.clone())?;
+    let txn = conn.transaction()?;
+    {
+        let update_thumbnail_stmt = &mut txn.prepare_cached(
+            "UPDATE media SET thumbnail = :thumbnail \
+                WHERE feed_id = :feed_id AND media_id = :media_id",
+        )?;
+        match update_thumbnail_stmt.execute(named_params! {
+            ":feed_id":  media.feed_id,
+            ":media_id":  media.media_id,
+            ":thumbnail":  media.thumbnail,
+        }) {
+            Ok(_row_count) => {
+                // println!(
+                //     "update_media_thumbnail update success for: {:?} {:?}",
+                //     media.feed_id, media.media_id
+                // );
+            }
+            Err(err) => {
+                println!(
+                    "update_media_thumbnail update failed for: {:?} {:?}",
+                    media.feed_id, media.media_id
+                );
+                return Err(err.into());
+            }
+        }
+    }
+    match txn.commit() {
+        Ok(_) => {
+            // println!(
+            //     "update_media_thumbnail committed for: {:?} {:?}",
+            //     media.feed_id, media.media_id
+            // );
+            Ok(())
+        }
+        Err(err) => {
+            println!(
+                "update_media_thumbnail commit failed for: {:?} {:?}",
+                media.feed_id, media.media_id
+            );
+            Err(err.into())
+        }
+    }
+}
+
+fn soft_delete_media_thumbnail(
+    data: web::Data<AppState>,
+    media: &Media,
+    reason: &MediaCheckError,
+) -> Result<(), ApiError> {
+    println!(
+        "soft_delete_media_thumbnail {:?} {:?} {:?}",
+        media.feed_id, media.media_id, reason
+    );
+    let conn = &mut get_conn(data.clone())?;
+    let txn = conn.transaction()?;
+    {
+        update_media_check(&txn, media.feed_id, media.media_id, Some(reason))?;
+        let soft_delete_thumbnail_stmt = &mut txn.prepare_cached(
+            "UPDATE media SET deleted_at = CAST(strftime('%s','now') AS INTEGER) \
+                WHERE feed_id = :feed_id AND media_id = :media_id",
+        )?;
+        match soft_delete_thumbnail_stmt.execute(named_params! {
+            ":feed_id":  media.feed_id,
+            ":media_id":  media.media_id,
+        }) {
+            Ok(_row_count) => {
+                // println!(
+                //     "soft_delete_media_thumbnail update success for: {:?} {:?}",
+                //     media.feed_id, media.media_id
+                // );
+            }
+            Err(err) => {
+                println!(
+                    "soft_delete_media_thumbnail update failed for: {:?} {:?}",
+                    media.feed_id, media.media_id
+                );
+                return Err(err.into());
+            }
+        };
+    }
+    let committed = txn.commit();
+    media_changed(&data);
+    match committed {
+        Ok(_) => {
+            // println!(
+            //     "soft_delete_media_thumbnail committed for: {:?} {:?}",
+            //     media.feed_id, media.media_id
+            // );
+            Ok(())
+        }
+        Err(err) => {
+            println!(
+                "soft_delete_media_thumbnail commit failed for: {:?} {:?}",
+                media.feed_id, media.media_id
+            );
+            Err(err.into())
+        }
+    }
+}
+
+fn read_cached_thumbnail(
+    data: web::Data<AppState>,
+    feed_id: i64,
+    media_id: i64,
+) -> Option<Vec<u8>> {
+    let cache = data.thumbnail_cache.as_ref()?;
+    let conn = get_read_conn(data.clone()).ok()?;
+    let hash: String = match conn
+        .prepare_cached(
+            "SELECT thumbnail_hash FROM thumbnails \
+            WHERE feed_id = :feed_id AND media_id = :media_id",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_row(
+                named_params! {
+                    ":feed_id": feed_id,
+                    ":media_id": media_id,
+                },
+                |row| row.get(0),
+            )
+        }) {
+        Ok(value) => value,
+        Err(_err) => return None,
+    };
+    match cache.read(&hash) {
+        Ok(buf) => {
+            // Bump for LRU eviction, written later by flush_thumbnail_touches
+            cache.touch(&hash, Utc::now().timestamp());
+            Some(buf)
+        }
+        Err(err) => {
+            // File was removed from under us, forget it so it gets generated again
+            println!("read_cached_thumbnail read failed: {:?} {:?}", hash, err);
+            drop(conn);
+            match get_conn(data.clone()).and_then(|conn| {
+                conn.prepare_cached(
+                    "DELETE FROM thumbnails WHERE thumbnail_hash = :thumbnail_hash",
+                )?
+                .execute(named_params! { ":thumbnail_hash": hash })?;
+                Ok(())
+            }) {
+                Ok(()) => {}
+                Err(err) => println!("read_cached_thumbnail delete failed: {:?}", err),
+            };
+            None
+        }
+    }
+}
+
+/// Write thumbnail reads recorded in memory to `thumbnails.accessed_at`, in one transaction
+fn flush_thumbnail_touches(cache: &ThumbnailCache, conn: &Connection) {
+    let touched = cache.take_touched();
+    if touched.is_empty() {
+        return;
+    }
+    let result = conn.unchecked_transaction().and_then(|txn| {
+        {
+            let mut stmt = txn.prepare_cached(
+                "UPDATE thumbnails SET accessed_at = MAX(accessed_at, :accessed_at) \
+                WHERE thumbnail_hash = :thumbnail_hash",
+            )?;
+            for (hash, accessed_at) in touched.iter() {
+                stmt.execute(named_params! {
+                    ":accessed_at": accessed_at,
+                    ":thumbnail_hash": hash,
+                })?;
+            }
+        }
+        txn.commit()
+    });
+    if let Err(err) = result {
+        println!("flush_thumbnail_touches failed: {:?}", err);
+        cache.restore_touched(touched);
+    }
+}
+
+/// Write thumbnail reads to the database every THUMBNAIL_TOUCH_FLUSH_SECS seconds.
+/// Only this thread waits when the database is busy, reads lost on exit only make
+/// eviction a little less accurate.
+fn flush_thumbnail_touches_periodically(data: web::Data<AppState>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(THUMBNAIL_TOUCH_FLUSH_SECS));
+        let cache = data.thumbnail_cache.as_ref().unwrap();
+        if !cache.has_touched() {
+            continue;
+        }
+        match get_conn(data.clone()) {
+            Ok(conn) => flush_thumbnail_touches(cache, &conn),
+            Err(err) => println!("flush_thumbnail_touches_periodically failed: {:?}", err),
+        };
+    });
+}
+
+fn store_cached_thumbnail(data: web::Data<AppState>, media: &Media) -> Result<(), ApiError> {
+    let cache = data.thumbnail_cache.as_ref().unwrap();
+    let blob = media.thumbnail.as_ref().unwrap();
+    let (hash, is_new) = match cache.write(blob) {
+        Ok(value) => value,
+        Err(err) => {
+            println!(
+                "store_cached_thumbnail write failed for: {:?} {:?} {:?}",
+                media.feed_id, media.media_id, err
+            );
+            return Err(ApiError::Io("thumbnail_cache_01", err));
+        }
+    };
+    let conn = &mut get_conn(data.clone())?;
+    let result = conn.transaction().and_then(|txn| {
+        txn.prepare_cached(
+            "INSERT OR REPLACE INTO thumbnails \
+            (feed_id, media_id, thumbnail_hash, thumbnail_size) \
+            VALUES (:feed_id, :media_id, :thumbnail_hash, :thumbnail_size)",
+        )?
+        .execute(named_params! {
+            ":feed_id": media.feed_id,
+            ":media_id": media.media_id,
+            ":thumbnail_hash": hash,
+            ":thumbnail_size": blob.len() as i64,
+        })?;
+        // Blob now lives in the cache
+        txn.prepare_cached(
+            "UPDATE media SET thumbnail = NULL \
+            WHERE feed_id = :feed_id AND media_id = :media_id",
+        )?
+        .execute(named_params! {
+            ":feed_id": media.feed_id,
+            ":media_id": media.media_id,
+        })?;
+        txn.prepare_cached(
+            "DELETE FROM evicted_thumbnails \
+            WHERE feed_id = :feed_id AND media_id = :media_id",
+        )?
+        .execute(named_params! {
+            ":feed_id": media.feed_id,
+            ":media_id": media.media_id,
+        })?;
+        txn.commit()
+    });
+    if let Err(err) = result {
+        // Nothing points to a file created here, unless another writer stored the same one since
+        if is_new && !is_thumbnail_hash_used(conn, &hash) {
+            match cache.remove(&hash) {
+                Ok(()) => {}
+                Err(err) => println!("store_cached_thumbnail remove failed: {:?} {:?}", hash, err),
+            };
+        }
+        return Err(err.into());
+    }
+    if is_new {
+        cache.add_used(blob.len() as u64);
+    }
+    evict_thumbnails(data.clone(), conn);
+    Ok(())
+}
+
+fn is_thumbnail_hash_used(conn: &Connection, hash: &str) -> bool {
+    conn.query_row(
+        "SELECT COUNT(*) FROM thumbnails WHERE thumbnail_hash = :thumbnail_hash",
+        named_params! { ":thumbnail_hash": hash },
+        |row| row.get::<_, i64>(0),
     )
+    .map_or(true, |count| count > 0)
+}
+
+fn evict_thumbnails(data: web::Data<AppState>, conn: &PooledConnection<SqliteConnectionManager>) {
+    let cache = data.thumbnail_cache.as_ref().unwrap();
+    if cache.size_limit() == 0 {
+        return;
+    }
+    let used = cache.used(|| {
+        conn.query_row(
+            "SELECT IFNULL(SUM(thumbnail_size), 0) FROM ( \
+                SELECT MAX(thumbnail_size) AS thumbnail_size FROM thumbnails GROUP BY thumbnail_hash \
+            )",
+            [],
+            |row| row.get::<_, i64>(0),
+        )
+        .unwrap_or(0) as u64
+    });
+    if used <= cache.size_limit() {
+        return;
+    }
+    flush_thumbnail_touches(cache, conn);
+
+    // Least recently used first, files shared by several media go together
+    let mut stmt = match conn.prepare_cached(
+        "SELECT thumbnail_hash, MAX(thumbnail_size), MAX(accessed_at) AS last_accessed_at \
+        FROM thumbnails \
+        GROUP BY thumbnail_hash \
+        ORDER BY last_accessed_at ASC",
+    ) {
+        Ok(stmt) => stmt,
+        Err(err) => {
+            println!("evict_thumbnails query failed: {:?}", err);
+            return;
+        }
+    };
+    let mut evict: Vec<(String, u64)> = Vec::new();
+    let mut remaining = used;
+    match stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+    }) {
+        Ok(rows) => {
+            for (hash, size) in rows.flatten() {
+                if remaining <= cache.size_limit() {
+                    break;
+                }
+                remaining = remaining.saturating_sub(size as u64);
+                evict.push((hash, size as u64));
+            }
+        }
+        Err(err) => println!("evict_thumbnails query failed: {:?}", err),
+    };
+
+    for (hash, size) in evict.iter() {
+        // Marked as evicted, so generate_thumbnails does not put it back
+        match conn.unchecked_transaction().and_then(|txn| {
+            txn.prepare_cached(
+                "INSERT OR REPLACE INTO evicted_thumbnails (feed_id, media_id) \
+                SELECT feed_id, media_id FROM thumbnails WHERE thumbnail_hash = :thumbnail_hash",
+            )?
+            .execute(named_params! { ":thumbnail_hash": hash })?;
+            txn.prepare_cached("DELETE FROM thumbnails WHERE thumbnail_hash = :thumbnail_hash")?
+                .execute(named_params! { ":thumbnail_hash": hash })?;
+            txn.commit()
+        }) {
+            Ok(()) => {
+                match cache.remove(hash) {
+                    Ok(()) => {}
+                    Err(err) => println!("evict_thumbnails remove failed: {:?} {:?}", hash, err),
+                };
+                cache.sub_used(*size);
+            }
+            Err(err) => println!("evict_thumbnails delete failed: {:?} {:?}", hash, err),
+        };
+    }
+    if !evict.is_empty() {
+        println!("evict_thumbnails evicted {} thumbnails", evict.len());
+    }
+}
+
+#[post("/a/migrate_thumbnails")]
+async fn migrate_thumbnails_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if data.thumbnail_cache.is_none() {
+        return Err(ApiError::BadRequest(
+            "migrate_thumbnails_service_01",
+            String::from("Thumbnail cache directory is not configured"),
+        ));
+    }
+    if *data.scanner_count.read().unwrap() >= data.scanner_count_limit
+        || *data.is_maintaining.read().unwrap()
+    {
+        return Ok(HttpResponse::TooManyRequests().json(state(data.clone())));
+    }
+    println!("/a/migrate_thumbnails start");
+    open_db(data.clone())?;
+    *data.scanner_count.write().unwrap() += 1;
+
+    // Move thumbnail blobs from the database to the thumbnail cache
+    migrate_thumbnails(data.clone()).await;
+
+    Ok(HttpResponse::Accepted().json(state(data.clone())))
 }
 
-#[get("/a/zip/{zip_file_name}/{file_name:.*}")]
-async fn zip_service(
-    web::Path((zip_file_name, file_name)): web::Path<(String, String)>,
-    data: web::Data<AppState>,
-) -> impl Responder {
-    println!("zip_service {} {}", zip_file_name, file_name);
-    let zip_path = PathBuf::from(data.data_dir.read().unwrap().to_string()).join(zip_file_name);
-    if zip_path.is_file() {
-        let zip_file = File::open(zip_path).unwrap();
-        match ZipArchive::new(zip_file) {
-            Ok(mut zip) => match zip.by_name(&file_name) {
-                Ok(mut f) => {
-                    if f.is_file() {
-                        let path = PathBuf::from(&file_name);
-                        let ext = path.extension().unwrap_or(OsStr::new("")).to_str().unwrap();
-                        let mut buf: Vec<u8> = Vec::new();
-                        let _buf_size = f.read_to_end(&mut buf).unwrap();
-                        return HttpResponse::Ok()
-                            .header(CONTENT_TYPE, file_extension_to_mime(ext))
-                            .body(buf);
-                    }
+async fn migrate_thumbnails(data: web::Data<AppState>) {
+    println!("migrate_thumbnails");
+    let thread_data = data.clone();
+    let mut migrated_count = 0usize;
+    thread::spawn(move || loop {
+        let media = match pick_thumbnail_blob(thread_data.clone()) {
+            Ok(media) => media,
+            Err(err) => {
+                println!("migrate_thumbnails failed picking media: {:?}", err);
+                None
+            }
+        };
+
+        let done = match media {
+            // Stop on the first failure, otherwise the same row is picked forever
+            Some(value) => match store_cached_thumbnail(thread_data.clone(), &value) {
+                Ok(()) => {
+                    migrated_count += 1;
+                    false
+                }
+                Err(err) => {
+                    println!("migrate_thumbnails store failed: {:?}", err);
+                    true
                 }
-                Err(_err) => {}
             },
-            Err(_err) => {}
+            None => true,
+        };
+        if done {
+            println!("migrate_thumbnails moved {} thumbnails", migrated_count);
+            *data.scanner_count.write().unwrap() -= 1;
+            break;
         }
-    }
-    HttpResponse::NotFound().body("")
+    });
 }
 
-fn extract_zip_file(
-    data_dir: String,
-    zip_path: String,
-    file_path: String,
-) -> Option<(Vec<u8>, usize, Mime)> {
-    let mut zip_path = PathBuf::from(&data_dir).join(&zip_path);
-    if zip_path.is_file() {
-        let zip_file = File::open(zip_path).unwrap();
-        match ZipArchive::new(zip_file) {
-            Ok(mut zip) => match zip.by_name(&file_path) {
-                Ok(mut f) => {
-                    if f.is_file() {
-                        let path = PathBuf::from(&file_path);
-                        let ext = path.extension().unwrap_or(OsStr::new("")).to_str().unwrap();
-                        let mut buf: Vec<u8> = Vec::new();
-                        let mut buf_size = f.read_to_end(&mut buf).unwrap();
-                        return Some((buf, buf_size, file_extension_to_mime(ext)));
-                    }
-                }
-                Err(_err) => {}
-            },
-            Err(_err) => {}
-        }
+/// Any media still having its thumbnail in the database
+fn pick_thumbnail_blob(data: web::Data<AppState>) -> Result<Option<Media>, ApiError> {
+    let conn = get_conn(data)?;
+    let mut pick_media_stmt = conn.prepare_cached(
+        "SELECT feed_id, media_id, thumbnail \
+        FROM media \
+        WHERE thumbnail IS NOT NULL \
+        LIMIT 1",
+    )?;
+    match pick_media_stmt.query_row([], |row| {
+        Ok(Media {
+            feed_id: row.get(0)?,
+            media_id: row.get(1)?,
+            media_type: String::from(""),
+            media_url: String::from(""),
+            file_path: String::from(""),
+            media_path: String::from(""),
+            thumbnail: row.get(2)?,
+            deleted_at: None,
+            metadata: None,
+        })
+    }) {
+        Ok(media) => Ok(Some(media)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(err) => Err(err.into()),
     }
-    None
 }
 
-#[post("/a/set_data_dir")]
-async fn set_data_dir_service(
-    (query, data): (web::Form<SetDataDirForm>, web::Data<AppState>),
-) -> impl Responder {
-    println!("/a/set_data_dir");
-    let query = query.into_inner();
-    let SetDataDirForm { data_dir } = query;
-    match data_dir {
-        Some(value) => {
-            println!("data_dir={}", value);
-            *data.data_dir.write().unwrap() = value.to_string();
-
-            // Write config file
-            let config = AppConfig {
-                data_dir: Some(value.to_string()),
-                bind_address: Some(data.bind_address.read().unwrap().clone()),
-                time_offset: Some(data.time_offset),
-                scanner_count_limit: Some(data.scanner_count_limit),
-            };
-            let config_str = serde_yaml::to_string(&config).unwrap();
-            println!("write config: {:?}", config_str);
-            let config_path = data.config_path.read().unwrap();
-            fs::write(config_path.clone(), config_str).unwrap();
+fn update_media_check(
+    txn: &Transaction<'_>,
+    feed_id: i64,
+    media_id: i64,
+    error: Option<&MediaCheckError>,
+) -> Result<(), rusqlite::Error> {
+    txn.prepare_cached(
+        "INSERT OR REPLACE INTO media_checks \
+        (feed_id, media_id, checked_at, error_code, error_message) \
+        VALUES (:feed_id, :media_id, CAST(strftime('%s','now') AS INTEGER), \
+        :error_code, :error_message)",
+    )?
+    .execute(named_params! {
+        ":feed_id": feed_id,
+        ":media_id": media_id,
+        ":error_code": error.map(|err| err.code()),
+        ":error_message": error.map(|err| err.message()),
+    })?;
+    Ok(())
+}
 
-            HttpResponse::Ok().json(state(data.clone()))
+/// Check that the media file can be decoded, only headers are checked for video
+fn check_media_blob(media_type: &str, blob: &[u8]) -> Result<(), MediaCheckError> {
+    if "Image" == media_type {
+        match ImageReader::new(Cursor::new(blob))
+            .with_guessed_format()
+            .expect("std::io::Cursor never fails")
+            .decode()
+        {
+            Ok(_img) => Ok(()),
+            Err(err) => Err(MediaCheckError::Undecodable(err.to_string())),
+        }
+    } else {
+        match media_info::video_info(blob) {
+            Some(_info) => Ok(()),
+            None => Err(MediaCheckError::Undecodable(String::from(
+                "Not an MP4 container",
+            ))),
         }
-        None => HttpResponse::NotModified().body(""),
     }
 }
 
-#[post("/a/generate_thumbnails")]
-async fn generate_thumbnails_service(data: web::Data<AppState>) -> impl Responder {
-    if *data.scanner_count.read().unwrap() >= data.scanner_count_limit {
-        return HttpResponse::TooManyRequests().json(state(data.clone()));
+#[post("/a/verify")]
+async fn verify_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    if *data.scanner_count.read().unwrap() >= data.scanner_count_limit
+        || *data.is_verifying.read().unwrap()
+        || *data.is_maintaining.read().unwrap()
+    {
+        return Ok(HttpResponse::TooManyRequests().json(state(data.clone())));
     }
-    println!(
-        "/a/generate_thumbnails start {} {:?}",
-        data.scanner_count.read().unwrap(),
-        data.data_dir.read().unwrap().to_string()
-    );
+    println!("/a/verify start");
+    open_db(data.clone())?;
     *data.scanner_count.write().unwrap() += 1;
+    *data.is_verifying.write().unwrap() = true;
 
-    open_db(data.clone());
+    // Check all media, one archive at a time
+    verify_files(data.clone()).await;
 
-    // Generate all thumbnails
-    generate_thumbnails(data.clone()).await;
-
-    HttpResponse::Accepted().json(state(data.clone()))
+    Ok(HttpResponse::Accepted().json(state(data.clone())))
 }
 
-async fn generate_thumbnails(data: web::Data<AppState>) {
-    println!("generate_thumbnails");
+async fn verify_files(data: web::Data<AppState>) {
+    println!("verify_files");
     let thread_data = data.clone();
-    thread::spawn(move || loop {
-        let mut conn = get_conn(thread_data.clone());
-        let mut pick_media_stmt = conn
-            .prepare_cached(
-                "SELECT \
-                feed_id, media_id, media_type, media_url, file_path, media_path \
-                FROM media \
-                WHERE media_type = 'Image' \
-                AND deleted_at IS NULL \
-                AND thumbnail IS NULL \
-                LIMIT 1",
-            )
-            .unwrap();
-        let media = &mut match pick_media_stmt.query_row([], |row| {
-            Ok(Media {
-                feed_id: row.get(0).unwrap(),
-                media_id: row.get(1).unwrap(),
-                media_type: row.get(2).unwrap(),
-                media_url: row.get(3).unwrap(),
-                file_path: row.get(4).unwrap(),
-                media_path: row.get(5).unwrap(),
-                thumbnail: None,  // Filtered out
-                deleted_at: None, // Filtered out
-            })
-        }) {
-            Ok(media) => Some(media),
-            Err(err) => {
-                println!("generate_thumbnails failed picking file: {:?}", err);
-                None
-            }
-        };
-
-        match media {
-            Some(value) => {
-                generate_thumbnail(thread_data.clone(), value);
-            }
-            None => {
-                *data.scanner_count.write().unwrap() -= 1;
-                break;
+    thread::spawn(move || {
+        let file_paths: Result<Vec<String>, ApiError> =
+            get_conn(thread_data.clone()).and_then(|conn| {
+                conn.prepare("SELECT DISTINCT file_path FROM media ORDER BY file_path")
+                    .and_then(|mut stmt| {
+                        stmt.query_map([], |row| row.get(0))
+                            .and_then(Iterator::collect)
+                    })
+                    .map_err(ApiError::from)
+            });
+        match file_paths {
+            Ok(file_paths) => {
+                for file_path in file_paths.iter() {
+                    verify_file(thread_data.clone(), file_path);
+                }
+                println!("verify_files checked {} archives", file_paths.len());
             }
+            Err(err) => println!("verify_files failed listing archives: {:?}", err),
         };
+        *data.is_verifying.write().unwrap() = false;
+        *data.scanner_count.write().unwrap() -= 1;
     });
 }
 
-fn generate_thumbnail(data: web::Data<AppState>, media: &mut Media) {
-    println!(
-        "generate_thumbnail for {:?} {:?}",
-        &media.feed_id, &media.media_id
-    );
-    let mut start = SystemTime::now();
-    let zip_path =
-        PathBuf::from(data.data_dir.read().as_ref().unwrap().to_string()).join(&media.file_path);
-    if !zip_path.is_file() {
-        println!("generate_thumbnail trying to read a non-file");
-        match soft_delete_media_thumbnail(data.clone(), &media) {
-            Ok(_) => {}
-            Err(err) => {
-                println!("soft_delete_media_thumbnail failed(0): {:?}", err);
-            }
-        };
-        return;
-    }
-    let zip_file = File::open(zip_path).unwrap();
-    let mut image_blob = Vec::new();
-    match ZipArchive::new(zip_file) {
-        Ok(mut zip) => match zip.by_name(&media.media_path) {
-            Ok(mut f) => {
-                if f.is_file() {
-                    let _buf_size = f.read_to_end(&mut image_blob).unwrap();
-                } else {
-                    println!("generate_thumbnail trying to read a non-file");
-                    match soft_delete_media_thumbnail(data.clone(), &media) {
-                        Ok(_) => {}
-                        Err(err) => {
-                            println!("soft_delete_media_thumbnail failed(1): {:?}", err);
-                        }
-                    };
-                    return;
-                }
-            }
-            Err(err) => {
-                println!("generate_thumbnail read failed: {:?}", err);
-                match soft_delete_media_thumbnail(data.clone(), &media) {
-                    Ok(_) => {}
-                    Err(err) => {
-                        println!("soft_delete_media_thumbnail failed(2): {:?}", err);
-                    }
-                };
-                return;
-            }
-        },
+fn verify_file(data: web::Data<AppState>, file_path: &str) {
+    println!("verify_file {:?}", file_path);
+    let conn = &mut match get_conn(data.clone()) {
+        Ok(conn) => conn,
         Err(err) => {
-            println!("generate_thumbnail read failed: {:?}", err);
-            match soft_delete_media_thumbnail(data.clone(), &media) {
-                Ok(_) => {}
-                Err(err) => {
-                    println!("soft_delete_media_thumbnail failed(3): {:?}", err);
-                    return;
-                }
-            };
+            println!("verify_file failed: {:?}", err);
+            return;
+        }
+    };
+    let media_list: SqlResult<Vec<(i64, i64, String, String)>> = conn
+        .prepare_cached(
+            "SELECT feed_id, media_id, media_type, media_path FROM media \
+            WHERE file_path = :file_path",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(named_params! { ":file_path": file_path }, |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .and_then(Iterator::collect)
+        });
+    let media_list = match media_list {
+        Ok(value) => value,
+        Err(err) => {
+            println!("verify_file failed listing media: {:?}", err);
             return;
         }
     };
 
-    match generate_thumbnail_blob(&image_blob) {
-        Ok(buf) => {
-            media.thumbnail = Some(buf);
+    let zip_path = PathBuf::from(data.data_dir.read().unwrap().to_string()).join(file_path);
+    let mut zip = if zip_path.is_file() {
+        match File::open(&zip_path)
+            .map_err(|err| err.to_string())
+            .and_then(|zip_file| ZipArchive::new(zip_file).map_err(|err| err.to_string()))
+        {
+            Ok(zip) => Ok(zip),
+            Err(message) => Err(MediaCheckError::UnreadableArchive(message)),
         }
+    } else {
+        Err(MediaCheckError::MissingArchive)
+    };
+
+    let results: Vec<(i64, i64, Result<(), MediaCheckError>)> = media_list
+        .into_iter()
+        .map(|(feed_id, media_id, media_type, media_path)| {
+            let result = match zip.as_mut() {
+                Ok(zip) => match zip.by_name(&media_path) {
+                    Ok(mut f) => {
+                        if f.is_file() {
+                            let mut blob: Vec<u8> = Vec::new();
+                            match f.read_to_end(&mut blob) {
+                                Ok(_size) => check_media_blob(&media_type, &blob),
+                                Err(err) => Err(MediaCheckError::UnreadableEntry(err.to_string())),
+                            }
+                        } else {
+                            Err(MediaCheckError::NotAFile)
+                        }
+                    }
+                    Err(err) => Err(MediaCheckError::from_entry_error(err)),
+                },
+                Err(MediaCheckError::MissingArchive) => Err(MediaCheckError::MissingArchive),
+                Err(err) => Err(MediaCheckError::UnreadableArchive(err.message())),
+            };
+            (feed_id, media_id, result)
+        })
+        .collect();
+
+    let txn = match conn.transaction() {
+        Ok(txn) => txn,
         Err(err) => {
-            println!("generate_thumbnail_blob update failed: {:?}", err);
+            println!("verify_file transaction failed: {:?}", err);
+            return;
         }
     };
+    let mut failed_count = 0usize;
+    for (feed_id, media_id, result) in results.iter() {
+        let updated = update_media_check(&txn, *feed_id, *media_id, result.as_ref().err())
+            .and_then(|()| {
+                // Soft delete like thumbnail generation does, and bring back files that reappeared
+                txn.prepare_cached(if result.is_ok() {
+                    "UPDATE media SET deleted_at = NULL \
+                    WHERE feed_id = :feed_id AND media_id = :media_id"
+                } else {
+                    "UPDATE media SET deleted_at = CAST(strftime('%s','now') AS INTEGER) \
+                    WHERE feed_id = :feed_id AND media_id = :media_id AND deleted_at IS NULL"
+                })?
+                .execute(named_params! {
+                    ":feed_id": feed_id,
+                    ":media_id": media_id,
+                })
+            });
+        match updated {
+            Ok(_row_count) => {}
+            Err(err) => println!(
+                "verify_file update failed: {:?} {:?} {:?}",
+                feed_id, media_id, err
+            ),
+        };
+        if result.is_err() {
+            failed_count += 1;
+        }
+    }
+    let committed = txn.commit();
+    media_changed(&data);
+    match committed {
+        Ok(_) => println!(
+            "verify_file {:?} checked {} media, {} failed",
+            file_path,
+            results.len(),
+            failed_count
+        ),
+        Err(err) => println!("verify_file commit failed: {:?}", err),
+    };
+}
 
-    match update_media_thumbnail(data.clone(), &media) {
-        Ok(()) => {}
-        Err(err) => {
-            println!("update_media_thumbnail update failed: {:?}", err);
+#[get("/a/verify")]
+async fn verify_result_service(
+    web_query: web::Query<VerifyQuery>,
+    data: web::Data<AppState>,
+) -> Result<HttpResponse, ApiError> {
+    let query = web_query.into_inner();
+    let page = query.page.unwrap_or(DEFAULT_PAGE);
+    let count = query.count.unwrap_or(DEFAULT_PAGE_COUNT);
+    let conn = get_read_conn(data.clone())?;
+
+    let (checked_count, ok_count, last_checked_at): (i64, i64, Option<i64>) = conn.query_row(
+        "SELECT COUNT(*), IFNULL(SUM(error_code IS NULL), 0), MAX(checked_at) \
+            FROM media_checks c \
+            INNER JOIN media m \
+            ON c.feed_id = m.feed_id AND c.media_id = m.media_id",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+    )?;
+    let failed_count_by_code: HashMap<String, i64> = conn
+        .prepare(
+            "SELECT c.error_code, COUNT(*) FROM media_checks c \
+            INNER JOIN media m \
+            ON c.feed_id = m.feed_id AND c.media_id = m.media_id \
+            WHERE c.error_code IS NOT NULL \
+            GROUP BY c.error_code",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .and_then(Iterator::collect)
+        })?;
+    let missing_archives: Vec<String> = conn
+        .prepare(
+            "SELECT DISTINCT m.file_path FROM media_checks c \
+            INNER JOIN media m \
+            ON c.feed_id = m.feed_id AND c.media_id = m.media_id \
+            WHERE c.error_code = 'missing_archive' \
+            ORDER BY m.file_path",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| row.get(0))
+                .and_then(Iterator::collect)
+        })?;
+    let failures: Vec<MediaCheck> = conn
+        .prepare(
+            "SELECT c.feed_id, c.media_id, m.file_path, m.media_path, c.checked_at, \
+            c.error_code, c.error_message \
+            FROM media_checks c \
+            INNER JOIN media m \
+            ON c.feed_id = m.feed_id AND c.media_id = m.media_id \
+            WHERE c.error_code IS NOT NULL AND c.error_code != 'missing_archive' \
+            ORDER BY m.file_path, m.media_path \
+            LIMIT :limit OFFSET :offset",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(
+                named_params! {
+                    ":limit": i64::from(count),
+                    ":offset": i64::from(page) * i64::from(count),
+                },
+                |row| {
+                    Ok(MediaCheck {
+                        feed_id: row.get(0)?,
+                        media_id: row.get(1)?,
+                        file_path: row.get(2)?,
+                        media_path: row.get(3)?,
+                        checked_at: row.get(4)?,
+                        error_code: row.get(5)?,
+                        error_message: row.get(6)?,
+                    })
+                },
+            )
+            .and_then(Iterator::collect)
+        })?;
+
+    Ok(HttpResponse::Ok().json(VerifyResponse {
+        is_verifying: *data.is_verifying.read().unwrap(),
+        checked_count,
+        ok_count,
+        failed_count: checked_count - ok_count,
+        last_checked_at,
+        failed_count_by_code,
+        missing_archives,
+        failures,
+    }))
+}
+
+#[post("/a/clean")]
+async fn clean_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    println!("clean_service");
+    if *data.scanner_count.read().unwrap() > 0
+        || is_pool_in_use(&data.pool)
+        || is_pool_in_use(&data.read_pool)
+    {
+        return Err(ApiError::Unavailable(
+            "clean_service_01",
+            String::from("Database is in use"),
+        ));
+    }
+
+    let mut conn = get_conn(data.clone())?;
+    // All or nothing, a failure halfway would leave rows of media that is gone
+    let txn = conn.transaction()?;
+    txn.execute("DELETE FROM thumbnails;", [])?;
+    txn.execute("DELETE FROM evicted_thumbnails;", [])?;
+    txn.execute("DELETE FROM media_hashes;", [])?;
+    txn.execute("DELETE FROM media_metadata;", [])?;
+    txn.execute("DELETE FROM media_checks;", [])?;
+    txn.execute("DELETE FROM media;", [])?;
+    txn.execute("DELETE FROM feed_files;", [])?;
+    txn.execute("DELETE FROM hashtags;", [])?;
+    txn.execute("DELETE FROM mentions;", [])?;
+    txn.execute("DELETE FROM links;", [])?;
+    txn.execute("DELETE FROM collection_feeds;", [])?;
+    txn.execute("DELETE FROM collections;", [])?;
+    txn.execute("DELETE FROM retweets;", [])?;
+    txn.execute("DELETE FROM feeds;", [])?;
+    txn.execute("DELETE FROM files;", [])?;
+    txn.commit()?;
+    media_changed(&data);
+    conn.execute("VACUUM;", [])?;
+    if let Some(cache) = data.thumbnail_cache.as_ref() {
+        match cache.clear() {
+            Ok(()) => {}
+            Err(err) => println!("clean_service thumbnail cache clear failed: {:?}", err),
+        };
+    }
+
+    Ok(HttpResponse::Ok().json(state(data.clone())))
+}
+
+#[post("/a/purge")]
+async fn purge_service(
+    (form, data): (web::Form<PurgeForm>, web::Data<AppState>),
+) -> Result<HttpResponse, ApiError> {
+    let form = form.into_inner();
+    println!("purge_service {:?}", form);
+    let dry_run = form.dry_run.unwrap_or(false);
+    let thumbnails_only = form.thumbnails_only.unwrap_or(false);
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+    let file_path = form.file_path.filter(|v| !v.is_empty());
+    let user_name = fix_user_name(&form.user_name).map(|v| v.to_ascii_lowercase());
+    let (since, until) =
+        parse_query_range("purge_service_02", &form.since, &form.until, time_offset)?;
+    if file_path.is_none() && user_name.is_none() && since.is_none() && until.is_none() {
+        return Err(ApiError::BadRequest(
+            "purge_service_01",
+            String::from("file_path, user_name, since or until is required, use /a/clean to delete everything"),
+        ));
+    }
+    if !dry_run && *data.scanner_count.read().unwrap() > 0 {
+        return Err(ApiError::Unavailable(
+            "purge_service_03",
+            String::from("Database is in use"),
+        ));
+    }
+
+    let mut where_clauses: Vec<&str> = Vec::new();
+    let mut purge_params: Vec<(&str, &dyn ToSql)> = Vec::new();
+    if file_path.is_some() {
+        where_clauses.push(
+            "EXISTS (SELECT ff.file_path FROM feed_files ff \
+            WHERE ff.feed_id = f.feed_id AND ff.user_name = f.user_name \
+            AND ff.retweet_id = f.retweet_id AND ff.retweet_user_name = f.retweet_user_name \
+            AND ff.file_path = :file_path)",
+        );
+        purge_params.push((":file_path", &file_path));
+    }
+    if user_name.is_some() {
+        where_clauses.push("f.user_name = :user_name COLLATE NOCASE");
+        purge_params.push((":user_name", &user_name));
+    }
+    if since.is_some() {
+        where_clauses.push("f.feed_at >= :since");
+        purge_params.push((":since", &since));
+    }
+    if until.is_some() {
+        where_clauses.push("f.feed_at < :until");
+        purge_params.push((":until", &until));
+    }
+    let media_file_clause = if file_path.is_some() {
+        "AND m.file_path = :file_path"
+    } else {
+        ""
+    };
+
+    // Everything is removed in one transaction, a dry run rolls it back
+    let mut conn = get_conn(data.clone())?;
+    let txn = conn.transaction()?;
+    txn.execute(
+        &format!(
+            "CREATE TEMP TABLE purge_feeds AS \
+            SELECT f.feed_id, f.user_name, f.retweet_id, f.retweet_user_name FROM ( \
+                SELECT feed_id, user_name, retweet_id, retweet_user_name, feed_at FROM feeds \
+                UNION ALL \
+                SELECT 0, user_name, feed_id, feed_user_name, retweet_at FROM retweets \
+            ) f WHERE {}",
+            where_clauses.join(" AND ")
+        ),
+        &purge_params[..],
+    )?;
+    let media_params: Vec<(&str, &dyn ToSql)> = match file_path {
+        Some(_) => vec![(":file_path", &file_path)],
+        None => Vec::new(),
+    };
+    txn.execute(
+        &format!(
+            "CREATE TEMP TABLE purge_media AS \
+            SELECT m.feed_id, m.media_id, m.thumbnail IS NOT NULL AS has_blob FROM media m \
+            WHERE m.feed_id IN (SELECT feed_id FROM temp.purge_feeds WHERE retweet_id = 0) {}",
+            media_file_clause
+        ),
+        &media_params[..],
+    )?;
+    // Cache files can be shared by several media, only files left unused are removed
+    let thumbnail_files: Vec<(String, i64)> = txn
+        .prepare(
+            "SELECT t.thumbnail_hash, MAX(t.thumbnail_size) FROM thumbnails t \
+            INNER JOIN temp.purge_media p \
+            ON t.feed_id = p.feed_id AND t.media_id = p.media_id \
+            GROUP BY t.thumbnail_hash",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .and_then(Iterator::collect)
+        })?;
+    let blob_count: usize = txn.query_row(
+        "SELECT IFNULL(SUM(has_blob), 0) FROM temp.purge_media",
+        [],
+        |row| row.get(0),
+    )?;
+    let thumbnail_count = blob_count
+        + txn.execute(
+            "DELETE FROM thumbnails WHERE (feed_id, media_id) IN \
+            (SELECT feed_id, media_id FROM temp.purge_media)",
+            [],
+        )?;
+    txn.execute(
+        "DELETE FROM evicted_thumbnails WHERE (feed_id, media_id) IN \
+        (SELECT feed_id, media_id FROM temp.purge_media)",
+        [],
+    )?;
+
+    let mut feed_count = 0usize;
+    let mut retweet_count = 0usize;
+    let mut media_count = 0usize;
+    let mut file_count = 0usize;
+    let mut collection_feed_count = 0usize;
+    let mut collection_count = 0usize;
+    let mut rescan_files: Vec<String> = Vec::new();
+    if thumbnails_only {
+        txn.execute(
+            "UPDATE media SET thumbnail = NULL WHERE thumbnail IS NOT NULL \
+            AND (feed_id, media_id) IN (SELECT feed_id, media_id FROM temp.purge_media)",
+            [],
+        )?;
+    } else {
+        for table in ["media_hashes", "media_metadata", "media_checks", "media"] {
+            let count = txn.execute(
+                &format!(
+                    "DELETE FROM {} WHERE (feed_id, media_id) IN \
+                    (SELECT feed_id, media_id FROM temp.purge_media)",
+                    table
+                ),
+                [],
+            )?;
+            if table == "media" {
+                media_count = count;
+            }
+        }
+        let feed_key = "(feed_id, user_name, retweet_id, retweet_user_name) IN \
+            (SELECT feed_id, user_name, retweet_id, retweet_user_name FROM temp.purge_feeds)";
+        // Retweets are in purge_feeds by their feed_files key
+        let retweet_key = "(0, user_name, feed_id, feed_user_name) IN \
+            (SELECT feed_id, user_name, retweet_id, retweet_user_name FROM temp.purge_feeds)";
+        match file_path {
+            Some(_) => {
+                // Feeds also read from other archives are kept
+                txn.execute(
+                    &format!(
+                        "DELETE FROM feed_files WHERE file_path = :file_path AND {}",
+                        feed_key
+                    ),
+                    &media_params[..],
+                )?;
+                feed_count = txn.execute(
+                    &format!(
+                        "DELETE FROM feeds WHERE {} AND NOT EXISTS (SELECT ff.file_path \
+                        FROM feed_files ff WHERE ff.feed_id = feeds.feed_id \
+                        AND ff.user_name = feeds.user_name AND ff.retweet_id = feeds.retweet_id \
+                        AND ff.retweet_user_name = feeds.retweet_user_name)",
+                        feed_key
+                    ),
+                    [],
+                )?;
+                retweet_count = txn.execute(
+                    &format!(
+                        "DELETE FROM retweets WHERE {} AND NOT EXISTS (SELECT ff.file_path \
+                        FROM feed_files ff WHERE ff.feed_id = 0 \
+                        AND ff.user_name = retweets.user_name AND ff.retweet_id = retweets.feed_id \
+                        AND ff.retweet_user_name = retweets.feed_user_name)",
+                        retweet_key
+                    ),
+                    [],
+                )?;
+                // Scanned again when the archive is still in the data dir
+                if user_name.is_none() && since.is_none() && until.is_none() {
+                    file_count = txn.execute(
+                        "DELETE FROM files WHERE file_path = :file_path \
+                        AND NOT EXISTS (SELECT m.file_path FROM media m \
+                        WHERE m.file_path = files.file_path)",
+                        &media_params[..],
+                    )?;
+                    // Tagging the archive again after the scan brings its collection back
+                    collection_feed_count = txn.execute(
+                        "DELETE FROM collection_feeds WHERE collection_id IN \
+                        (SELECT collection_id FROM collections WHERE file_path = :file_path)",
+                        &media_params[..],
+                    )?;
+                    collection_count = txn.execute(
+                        "DELETE FROM collections WHERE file_path = :file_path",
+                        &media_params[..],
+                    )?;
+                }
+            }
+            None => {
+                txn.execute(&format!("DELETE FROM feed_files WHERE {}", feed_key), [])?;
+                feed_count = txn.execute(&format!("DELETE FROM feeds WHERE {}", feed_key), [])?;
+                retweet_count =
+                    txn.execute(&format!("DELETE FROM retweets WHERE {}", retweet_key), [])?;
+            }
+        };
+        // Collection entries go with the last row of their archive that has them
+        collection_feed_count += txn.execute(
+            "DELETE FROM collection_feeds WHERE (feed_id, user_name) IN ( \
+                SELECT feed_id, user_name FROM temp.purge_feeds WHERE retweet_id = 0 \
+                UNION SELECT retweet_id, retweet_user_name FROM temp.purge_feeds \
+                WHERE feed_id = 0 \
+            ) AND NOT EXISTS (SELECT ff.file_path FROM feed_files ff \
+                INNER JOIN collections c ON c.file_path = ff.file_path \
+                WHERE c.collection_id = collection_feeds.collection_id \
+                AND ((ff.retweet_id = 0 AND ff.feed_id = collection_feeds.feed_id \
+                    AND ff.user_name = collection_feeds.user_name) \
+                OR (ff.feed_id = 0 AND ff.retweet_id = collection_feeds.feed_id \
+                    AND ff.retweet_user_name = collection_feeds.user_name)))",
+            [],
+        )?;
+        // Contents of a feed id kept under another user name still have them
+        for table in ["hashtags", "mentions", "links"] {
+            txn.execute(
+                &format!(
+                    "DELETE FROM {table} WHERE feed_id IN (SELECT feed_id FROM temp.purge_feeds) \
+                    AND NOT EXISTS (SELECT fe.feed_id FROM feeds fe \
+                    WHERE fe.feed_id = {table}.feed_id)",
+                    table = table
+                ),
+                [],
+            )?;
+        }
+        rescan_files = txn
+            .prepare(
+                "SELECT DISTINCT ff.file_path FROM feed_files ff \
+                INNER JOIN temp.purge_media p ON ff.feed_id = p.feed_id \
+                WHERE ff.retweet_id = 0 ORDER BY ff.file_path",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map([], |row| row.get(0))
+                    .and_then(Iterator::collect)
+            })?;
+        for rescan_file in rescan_files.iter() {
+            txn.execute(
+                "UPDATE files SET scan_started_at = NULL, scan_ended_at = NULL \
+                WHERE file_path = :file_path",
+                named_params! { ":file_path": rescan_file },
+            )?;
+        }
+    }
+    let unused_thumbnail_files: Vec<(String, i64)> = thumbnail_files
+        .into_iter()
+        .filter(|(hash, _size)| {
+            txn.query_row(
+                "SELECT COUNT(*) FROM thumbnails WHERE thumbnail_hash = :thumbnail_hash",
+                named_params! { ":thumbnail_hash": hash },
+                |row| row.get::<_, i64>(0),
+            )
+            .is_ok_and(|count| count == 0)
+        })
+        .collect();
+    txn.execute_batch("DROP TABLE temp.purge_feeds; DROP TABLE temp.purge_media;")?;
+    if dry_run {
+        txn.rollback()?;
+    } else {
+        txn.commit()?;
+        media_changed(&data);
+        if let Some(cache) = data.thumbnail_cache.as_ref() {
+            for (hash, size) in unused_thumbnail_files.iter() {
+                match cache.remove(hash) {
+                    Ok(()) => {}
+                    Err(err) => println!("purge_service remove failed: {:?} {:?}", hash, err),
+                };
+                cache.sub_used(*size as u64);
+            }
         }
+    }
+    let response = PurgeResponse {
+        dry_run,
+        feed_count,
+        retweet_count,
+        media_count,
+        thumbnail_count,
+        file_count,
+        collection_feed_count,
+        collection_count,
+        rescan_files,
     };
+    println!("purge_service {:?}", response);
+
+    Ok(HttpResponse::Ok().json(response))
 }
 
-fn generate_thumbnail_blob(blob: &Vec<u8>) -> Result<Vec<u8>, image::ImageError> {
-    let last_time = SystemTime::now();
+#[post("/a/db/backup")]
+async fn db_backup_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    println!("db_backup_service");
+    let data_dir = PathBuf::from(data.data_dir.read().unwrap().to_string());
+    // Read connections see a consistent snapshot and never block a running scan
+    let conn = get_read_conn(data.clone())?;
+    let backup = backup_database(&conn, &data_dir)?;
+    Ok(HttpResponse::Created().json(backup))
+}
 
-    let img_reader = ImageReader::new(Cursor::new(blob))
-        .with_guessed_format()
-        .expect("std::io::Cursor never fails");
-    let mut img = img_reader.decode().unwrap();
+#[get("/a/db/backups")]
+async fn db_backups_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let data_dir = PathBuf::from(data.data_dir.read().unwrap().to_string());
+    let backups =
+        list_backups(&data_dir).map_err(|err| ApiError::Io("db_backups_service_01", err))?;
+    Ok(HttpResponse::Ok().json(DatabaseBackupsResponse { backups }))
+}
 
-    // image.thumbnail average 9sec!
-    img = img.thumbnail(128u32, 128u32);
+#[post("/a/db/restore")]
+async fn db_restore_service(
+    (form, data): (web::Form<DatabaseRestoreForm>, web::Data<AppState>),
+) -> Result<HttpResponse, ApiError> {
+    let name = match form.into_inner().name {
+        Some(name) if is_backup_name(&name) => name,
+        _ => {
+            return Err(ApiError::BadRequest(
+                "db_restore_service_01",
+                String::from("name must be one of /a/db/backups"),
+            ))
+        }
+    };
+    println!("db_restore_service {:?}", name);
+    let data_dir = PathBuf::from(data.data_dir.read().unwrap().to_string());
+    let backup_path = data_dir.join(&name);
+    if !backup_path.is_file() {
+        return Err(ApiError::NotFound(
+            "db_restore_service_02",
+            format!("Backup {} not found", name),
+        ));
+    }
 
-    // crop and resize
-    // let cropped_size = std::cmp::min(img.width(), img.height());
-    // if img.width() >= img.height() {
-    //     let offset = img.width() - cropped_size / 2;
-    //     img = img.crop(offset, offset + cropped_size, 0u32, cropped_size);
-    // } else {
-    //     let offset = img.height() - cropped_size / 2;
-    //     img = img.crop(0u32, cropped_size, offset, offset + cropped_size);
-    // }
-    // img = img.resize(128u32, 128u32, image::imageops::FilterType::Lanczos3);
+    // Hold both pools so no request can check out a connection while the file is replaced
+    let mut pool = data.pool.write().unwrap();
+    let mut read_pool = data.read_pool.write().unwrap();
+    let pool_in_use = |pool: &Option<Pool<SqliteConnectionManager>>| {
+        pool.as_ref()
+            .is_some_and(|pool| pool.state().connections != pool.state().idle_connections)
+    };
+    if *data.scanner_count.read().unwrap() > 0 || pool_in_use(&pool) || pool_in_use(&read_pool) {
+        return Err(ApiError::Unavailable(
+            "db_restore_service_03",
+            String::from("Database is in use"),
+        ));
+    }
+    *pool = None;
+    *read_pool = None;
 
-    let mut img_blob: Vec<u8> = Vec::new();
-    match img.write_to(
-        &mut Cursor::new(&mut img_blob),
-        ImageOutputFormat::Jpeg(85u8),
-    ) {
-        Ok(_) => {
-            let last_time_duration = SystemTime::now()
-                .duration_since(last_time)
-                .unwrap()
-                .as_millis();
-            // println!(
-            //     "generate_thumbnail_bytes took {:?} [ms]",
-            //     last_time_duration
-            // );
-            Ok(img_blob)
+    let data_file = data_dir.join(DATABASE_FILENAME);
+    let previous = if data_file.is_file() {
+        Some(backup_database(&Connection::open(&data_file)?, &data_dir)?)
+    } else {
+        None
+    };
+    let source = Connection::open_with_flags(&backup_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    let mut target = Connection::open(&data_file)?;
+    Backup::new(&source, &mut target)?.run_to_completion(
+        BACKUP_PAGES_PER_STEP,
+        Duration::from_millis(250),
+        None,
+    )?;
+    drop(target);
+    if let Some(cache) = data.thumbnail_cache.as_ref() {
+        cache.reset_used();
+    }
+    media_changed(&data);
+    println!("db_restore_service restored {:?}", name);
+
+    // Pools are opened again, and the restored schema migrated, on the next request
+    Ok(HttpResponse::Ok().json(DatabaseRestoreResponse {
+        restored: name,
+        previous,
+    }))
+}
+
+#[post("/a/db/maintenance")]
+async fn db_maintenance_service(
+    (form, data): (web::Form<DatabaseMaintenanceForm>, web::Data<AppState>),
+) -> Result<HttpResponse, ApiError> {
+    let form = form.into_inner();
+    open_db(data.clone())?;
+    // VACUUM needs the database to itself, wait for every other job like clean does.
+    // Both are set under the scanner_count lock so no job starts in between.
+    let is_started = {
+        let mut scanner_count = data.scanner_count.write().unwrap();
+        let mut is_maintaining = data.is_maintaining.write().unwrap();
+        if *scanner_count > 0 || *is_maintaining {
+            false
+        } else {
+            *scanner_count += 1;
+            *is_maintaining = true;
+            true
         }
-        Err(err) => Err(err),
+    };
+    if !is_started {
+        return Ok(HttpResponse::TooManyRequests().json(state(data.clone())));
     }
+    println!("/a/db/maintenance start {:?}", form);
+
+    // VACUUM rewrites the whole file, never hold a request for it
+    maintain_database(data.clone(), form).await;
+
+    Ok(HttpResponse::Accepted().json(state(data.clone())))
 }
 
-fn update_media_thumbnail(data: web::Data<AppState>, media: &Media) -> Result<(), rusqlite::Error> {
-    let conn = &mut get_conn(data.clone());
-    let txn = conn.transaction().unwrap();
-    {
-        let update_thumbnail_stmt = &mut txn
-            .prepare_cached(
-                "UPDATE media SET thumbnail = :thumbnail \
-                WHERE feed_id = :feed_id AND media_id = :media_id",
-            )
-            .unwrap();
-        match update_thumbnail_stmt.execute(named_params! {
-            ":feed_id":  media.feed_id,
-            ":media_id":  media.media_id,
-            ":thumbnail":  media.thumbnail,
-        }) {
-            Ok(_row_count) => {
-                // println!(
-                //     "update_media_thumbnail update success for: {:?} {:?}",
-                //     media.feed_id, media.media_id
-                // );
-            }
-            Err(err) => {
-                println!(
-                    "update_media_thumbnail update failed for: {:?} {:?}",
-                    media.feed_id, media.media_id
-                );
-                return Err(err);
+async fn maintain_database(data: web::Data<AppState>, form: DatabaseMaintenanceForm) {
+    println!("maintain_database");
+    let thread_data = data.clone();
+    thread::spawn(move || {
+        let tasks = [
+            // Statistics first, so optimize has something to work with
+            (form.analyze.unwrap_or(true), "ANALYZE", "ANALYZE;"),
+            (
+                form.optimize.unwrap_or(true),
+                "PRAGMA optimize",
+                "PRAGMA optimize;",
+            ),
+            // VACUUM goes through the write-ahead log, truncate it afterwards to free the space
+            (
+                form.vacuum.unwrap_or(true),
+                "VACUUM",
+                "VACUUM; PRAGMA wal_checkpoint(TRUNCATE);",
+            ),
+        ];
+        match get_conn(thread_data.clone()) {
+            Ok(conn) => {
+                for (enabled, name, sql) in tasks.iter() {
+                    if !enabled {
+                        continue;
+                    }
+                    let started_at = SystemTime::now();
+                    match conn.execute_batch(sql) {
+                        Ok(()) => println!(
+                            "maintain_database {} done in {:?}",
+                            name,
+                            started_at.elapsed().unwrap_or_default()
+                        ),
+                        Err(err) => println!("maintain_database {} failed: {:?}", name, err),
+                    };
+                }
             }
-        }
-    }
-    match txn.commit() {
-        Ok(_) => {
-            // println!(
-            //     "update_media_thumbnail committed for: {:?} {:?}",
-            //     media.feed_id, media.media_id
-            // );
-            Ok(())
-        }
-        Err(err) => {
-            println!(
-                "update_media_thumbnail commit failed for: {:?} {:?}",
-                media.feed_id, media.media_id
-            );
-            Err(err)
-        }
-    }
+            Err(err) => println!("maintain_database failed: {:?}", err),
+        };
+        *data.is_maintaining.write().unwrap() = false;
+        *data.scanner_count.write().unwrap() -= 1;
+    });
 }
 
-fn soft_delete_media_thumbnail(
+#[get("/a/stats")]
+async fn stats_service(data: web::Data<AppState>) -> Result<HttpResponse, ApiError> {
+    let data_dir = PathBuf::from(data.data_dir.read().unwrap().to_string());
+    let conn = get_read_conn(data.clone())?;
+
+    let schema_version = migration::schema_version(&conn)?;
+    let (feed_count, retweet_count, user_count, first_feed_at, last_feed_at): (
+        i64,
+        i64,
+        i64,
+        Option<i64>,
+        Option<i64>,
+    ) = conn.query_row(
+        "SELECT (SELECT COUNT(*) FROM feeds), (SELECT COUNT(*) FROM retweets), \
+            (SELECT COUNT(*) FROM (SELECT user_name FROM feeds UNION SELECT user_name FROM retweets)), \
+            MIN(feed_at), MAX(feed_at) \
+            FROM feeds",
+        [],
+        |row| {
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        },
+    )?;
+    let (archive_count, archive_scanned_count): (i64, i64) = conn.query_row(
+        "SELECT COUNT(*), IFNULL(SUM(scan_ended_at IS NOT NULL), 0) FROM files",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    let media_count_by_type: HashMap<String, i64> = conn
+        .prepare("SELECT media_type, COUNT(*) FROM media GROUP BY media_type")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .and_then(Iterator::collect)
+        })?;
+    let (thumbnail_count, thumbnail_missing_count, media_deleted_count): (i64, i64, i64) = conn
+        .query_row(
+            &format!(
+                "SELECT IFNULL(SUM({has_thumbnail}), 0), \
+                IFNULL(SUM(m.media_type = 'Image' AND m.deleted_at IS NULL \
+                AND {has_no_thumbnail}), 0), \
+                IFNULL(SUM(m.deleted_at IS NOT NULL), 0) \
+                FROM media m",
+                has_thumbnail = HAS_THUMBNAIL_SQL,
+                has_no_thumbnail = HAS_NO_THUMBNAIL_SQL,
+            ),
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+    let database_free_size: i64 = conn.query_row(
+        "SELECT f.freelist_count * s.page_size FROM pragma_freelist_count() f, pragma_page_size() s",
+        [],
+        |row| row.get(0),
+    )?;
+    let file_size = |name: String| {
+        fs::metadata(data_dir.join(name))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0)
+    };
+
+    Ok(HttpResponse::Ok().json(StatsResponse {
+        schema_version,
+        feed_count,
+        retweet_count,
+        user_count,
+        archive_count,
+        archive_scanned_count,
+        first_feed_at,
+        last_feed_at,
+        media_count_by_type,
+        thumbnail_count,
+        thumbnail_missing_count,
+        media_deleted_count,
+        database_size: file_size(String::from(DATABASE_FILENAME)),
+        database_wal_size: file_size(format!("{}-wal", DATABASE_FILENAME)),
+        database_free_size,
+        is_maintaining: *data.is_maintaining.read().unwrap(),
+    }))
+}
+
+/// Bucket keys of a local time, hours and weekdays have every bucket even when empty
+const HISTOGRAM_BUCKETS: [(&str, &str, usize); 5] = [
+    ("day", "strftime('%Y-%m-%d', {at}, 'unixepoch')", 0),
+    ("week", "date({at}, 'unixepoch', '-6 days', 'weekday 1')", 0),
+    ("month", "strftime('%Y-%m', {at}, 'unixepoch')", 0),
+    (
+        "hour",
+        "CAST(strftime('%H', {at}, 'unixepoch') AS INTEGER)",
+        24,
+    ),
+    (
+        "weekday",
+        "CAST(strftime('%w', {at}, 'unixepoch') AS INTEGER)",
+        7,
+    ),
+];
+
+/// Feed counts of a /a/feeds query over time, for activity heatmaps and date scrubbers
+#[get("/a/stats/histogram")]
+async fn histogram_service(
+    web_query: web::Query<FeedsQuery>,
+    histogram_query: web::Query<HistogramQuery>,
     data: web::Data<AppState>,
-    media: &Media,
-) -> Result<(), rusqlite::Error> {
-    println!(
-        "soft_delete_media_thumbnail {:?} {:?}",
-        media.feed_id, media.media_id
-    );
-    let conn = &mut get_conn(data.clone());
-    let txn = conn.transaction().unwrap();
-    {
-        let soft_delete_thumbnail_stmt = &mut txn
-            .prepare_cached(
-                "UPDATE media SET deleted_at = CAST(strftime('%s','now') AS INTEGER) \
-                WHERE feed_id = :feed_id AND media_id = :media_id",
+) -> Result<HttpResponse, ApiError> {
+    let mut query = web_query.into_inner();
+    let time_offset: i32 = data.time_offset.round() as i32 * ONE_HOUR_I32;
+    let bucket = histogram_query.bucket.as_deref().unwrap_or("day");
+    let (bucket, key_sql, fixed_count) = *HISTOGRAM_BUCKETS
+        .iter()
+        .find(|(name, _, _)| *name == bucket)
+        .ok_or_else(|| {
+            ApiError::BadRequest(
+                "histogram_service_01",
+                format!(
+                    "bucket must be one of {}",
+                    HISTOGRAM_BUCKETS
+                        .iter()
+                        .map(|(name, _, _)| *name)
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                ),
             )
-            .unwrap();
-        match soft_delete_thumbnail_stmt.execute(named_params! {
-            ":feed_id":  media.feed_id,
-            ":media_id":  media.media_id,
-        }) {
-            Ok(_row_count) => {
-                // println!(
-                //     "soft_delete_media_thumbnail update success for: {:?} {:?}",
-                //     media.feed_id, media.media_id
-                // );
-            }
-            Err(err) => {
-                println!(
-                    "soft_delete_media_thumbnail update failed for: {:?} {:?}",
-                    media.feed_id, media.media_id
-                );
-                return Err(err);
+        })?;
+    check_feeds_query(&mut query, time_offset)?;
+
+    let mut filter = get_feeds_filter(&query, time_offset)?;
+    let local_at = format!("f.feed_at + {}", filter.bind(time_offset));
+    let conn = get_read_conn(data.clone())?;
+    let mut stmt = conn.prepare_cached(&format!(
+        "SELECT {key} AS bucket_key, COUNT(*) FROM ({timeline}) f {where_clause} \
+        GROUP BY bucket_key ORDER BY bucket_key",
+        key = key_sql.replace("{at}", &local_at),
+        timeline = TIMELINE_SQL,
+        where_clause = filter.where_clause()
+    ))?;
+    let counts: Vec<(SqlValue, i64)> = stmt
+        .query_map(&filter.params()[..], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<SqlResult<Vec<(SqlValue, i64)>>>()?;
+
+    let buckets: Vec<HistogramBucket> = if fixed_count > 0 {
+        let mut feed_counts = vec![0; fixed_count];
+        for (key, feed_count) in counts {
+            if let SqlValue::Integer(key) = key {
+                if let Some(value) = feed_counts.get_mut(key as usize) {
+                    *value = feed_count;
+                }
             }
-        };
-    }
-    match txn.commit() {
-        Ok(_) => {
-            // println!(
-            //     "soft_delete_media_thumbnail committed for: {:?} {:?}",
-            //     media.feed_id, media.media_id
-            // );
-            Ok(())
-        }
-        Err(err) => {
-            println!(
-                "soft_delete_media_thumbnail commit failed for: {:?} {:?}",
-                media.feed_id, media.media_id
-            );
-            Err(err)
         }
-    }
+        feed_counts
+            .into_iter()
+            .enumerate()
+            .map(|(key, feed_count)| HistogramBucket {
+                key: key.to_string(),
+                start_at: None,
+                feed_count,
+            })
+            .collect()
+    } else {
+        counts
+            .into_iter()
+            .filter_map(|(key, feed_count)| match key {
+                SqlValue::Text(key) => Some(HistogramBucket {
+                    start_at: NaiveDate::parse_from_str(&key, "%Y-%m-%d")
+                        .or_else(|_| NaiveDate::parse_from_str(&format!("{}-01", key), "%Y-%m-%d"))
+                        .ok()
+                        .and_then(|date| date.and_hms_opt(0, 0, 0))
+                        .map(|dt| dt.and_utc().timestamp() - i64::from(time_offset)),
+                    key,
+                    feed_count,
+                }),
+                _ => None,
+            })
+            .collect()
+    };
+
+    Ok(HttpResponse::Ok().json(HistogramResponse {
+        bucket: String::from(bucket),
+        query,
+        buckets,
+    }))
 }
 
-#[post("/a/clean")]
-async fn clean_service(data: web::Data<AppState>) -> impl Responder {
-    println!("clean_service");
-    if *data.scanner_count.read().unwrap() > 0
-        || (data.pool.read().unwrap().as_ref().is_some()
-            && data
-                .pool
-                .read()
-                .unwrap()
-                .as_ref()
-                .unwrap()
-                .state()
-                .connections
-                != data
-                    .pool
-                    .read()
-                    .unwrap()
-                    .as_ref()
-                    .unwrap()
-                    .state()
-                    .idle_connections)
+/// Copy the database to a timestamped file next to it with the online backup API
+fn backup_database(conn: &Connection, data_dir: &Path) -> Result<DatabaseBackup, ApiError> {
+    let name = format!(
+        "{}{}{}",
+        BACKUP_FILENAME_PREFIX,
+        Utc::now().format("%Y%m%dT%H%M%SZ"),
+        BACKUP_FILENAME_SUFFIX
+    );
+    let path = data_dir.join(&name);
+    if path.exists() {
+        return Err(ApiError::Unavailable(
+            "db_backup_01",
+            format!("Backup {} already exists", name),
+        ));
+    }
+    // Write to a temporary file first so a failed backup is never listed
+    let tmp_path = path.with_extension("tmp");
     {
-        return HttpResponse::ServiceUnavailable().json(AppError {
-            code: String::from("clean_service_01"),
-            message: String::from("Database is in use"),
-        });
+        let mut target = Connection::open(&tmp_path)?;
+        Backup::new(conn, &mut target)?.run_to_completion(
+            BACKUP_PAGES_PER_STEP,
+            Duration::from_millis(250),
+            None,
+        )?;
+        // Journal mode is copied from the source, keep backups a single file
+        target.pragma_update_and_check(None, "journal_mode", "DELETE", |_row| Ok(()))?;
     }
+    fs::rename(&tmp_path, &path).map_err(|err| ApiError::Io("db_backup_02", err))?;
+    let backup = backup_info(&path, name).map_err(|err| ApiError::Io("db_backup_02", err))?;
+    println!("backup_database wrote {:?}", backup);
+    Ok(backup)
+}
 
-    open_db(data.clone());
+fn list_backups(data_dir: &Path) -> std::io::Result<Vec<DatabaseBackup>> {
+    let mut backups: Vec<DatabaseBackup> = Vec::new();
+    for entry in fs::read_dir(data_dir)? {
+        let entry = entry?;
+        if let Some(name) = entry
+            .file_name()
+            .to_str()
+            .filter(|name| is_backup_name(name))
+        {
+            if entry.path().is_file() {
+                backups.push(backup_info(&entry.path(), name.to_string())?);
+            }
+        }
+    }
+    // Newest first, names sort by time
+    backups.sort_by(|a, b| b.name.cmp(&a.name));
+    Ok(backups)
+}
 
-    let conn = data.pool.read().unwrap().as_ref().unwrap().get().unwrap();
-    conn.execute("DELETE FROM media;", []).unwrap();
-    conn.execute("DELETE FROM feeds;", []).unwrap();
-    conn.execute("DELETE FROM files;", []).unwrap();
-    conn.execute("VACUUM;", []).unwrap();
+fn backup_info(path: &Path, name: String) -> std::io::Result<DatabaseBackup> {
+    let metadata = fs::metadata(path)?;
+    Ok(DatabaseBackup {
+        name,
+        size: metadata.len(),
+        created_at: metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or(0),
+    })
+}
 
-    HttpResponse::Ok().json(state(data.clone()))
+/// Only names written by `backup_database`, so a restore can never read outside the data dir
+fn is_backup_name(name: &str) -> bool {
+    match name
+        .strip_prefix(BACKUP_FILENAME_PREFIX)
+        .and_then(|name| name.strip_suffix(BACKUP_FILENAME_SUFFIX))
+    {
+        Some(timestamp) => {
+            !timestamp.is_empty()
+                && timestamp
+                    .chars()
+                    .all(|ch| ch.is_ascii_digit() || ch == 'T' || ch == 'Z')
+        }
+        None => false,
+    }
 }
 
 #[post("/a/scan")]
-async fn scan_service(data: web::Data<AppState>) -> impl Responder {
-    if *data.scanner_count.read().unwrap() >= data.scanner_count_limit {
-        return HttpResponse::TooManyRequests().json(state(data.clone()));
+async fn scan_service(
+    (form, data): (Option<web::Form<ScanForm>>, web::Data<AppState>),
+) -> Result<HttpResponse, ApiError> {
+    if *data.scanner_count.read().unwrap() >= data.scanner_count_limit
+        || *data.is_maintaining.read().unwrap()
+    {
+        return Ok(HttpResponse::TooManyRequests().json(state(data.clone())));
     }
     println!(
         "/a/scan start {} {:?}",
         data.scanner_count.read().unwrap(),
         data.data_dir.read().unwrap().to_string()
     );
+    open_db(data.clone())?;
+    if form.and_then(|form| form.into_inner().rescan) == Some(true) {
+        // Fills in what older versions did not read, migrations leave this to the user
+        let count = get_conn(data.clone())?.execute(
+            "UPDATE files SET scan_started_at = NULL, scan_ended_at = NULL",
+            [],
+        )?;
+        println!("/a/scan rescan {} files", count);
+    }
     *data.scanner_count.write().unwrap() += 1;
 
-    open_db(data.clone());
-
     // List all zip
-    list_all_zip(data.clone());
+    if let Err(err) = list_all_zip(data.clone()) {
+        *data.scanner_count.write().unwrap() -= 1;
+        return Err(err);
+    }
 
     // Scan oldest unscanned file until all are scanned
     scan_files(data.clone()).await;
 
-    HttpResponse::Accepted().json(state(data.clone()))
+    Ok(HttpResponse::Accepted().json(state(data.clone())))
 }
 
 async fn scan_files(data: web::Data<AppState>) {
     println!("scan_files");
     let thread_data = data.clone();
-    thread::spawn(move || loop {
-        let conn = get_conn(thread_data.clone());
-        let mut pick_file_stmt = conn
-            .prepare_cached("SELECT file_path FROM files WHERE scan_started_at IS NULL LIMIT 1")
-            .unwrap();
-        let mut file_name: Option<String> = None;
-        match pick_file_stmt.query_row(&[] as &[&dyn rusqlite::types::ToSql], |row| {
-            row.get::<_, String>(0)
-        }) {
-            Ok(f) => file_name = Some(f),
-            Err(err) => println!("scan_files failed picking file: {:?}", err),
-        };
-        let mut start_scan_stmt = conn.prepare_cached("UPDATE files SET scan_started_at = CAST(strftime('%s','now') AS INTEGER) WHERE file_path = $1").unwrap();
-        let mut end_scan_stmt = conn.prepare_cached("UPDATE files SET scan_ended_at = CAST(strftime('%s','now') AS INTEGER) WHERE file_path = $1").unwrap();
-        match file_name {
-            Some(value) => {
-                match start_scan_stmt.execute(&[&value]) {
-                    Ok(_row_count) => println!("scan_files set scan_started_at"),
-                    Err(err) => println!("scan_files set scan_started_at failed: {:?}", err),
-                };
-                scan_file(data.clone(), value.clone());
-                match end_scan_stmt.execute(&[&value]) {
-                    Ok(_row_count) => println!("scan_files set scan_ended_at"),
-                    Err(err) => println!("scan_files set scan_ended_at failed: {:?}", err),
-                };
-            }
-            None => {
-                *data.scanner_count.write().unwrap() -= 1;
-                break;
-            }
-        };
+    thread::spawn(move || {
+        loop {
+            match pick_unscanned_file(thread_data.clone()) {
+                Ok(Some(value)) => {
+                    match set_file_scan_time(thread_data.clone(), "scan_started_at", &value) {
+                        Ok(()) => println!("scan_files set scan_started_at"),
+                        Err(err) => println!("scan_files set scan_started_at failed: {:?}", err),
+                    };
+                    match scan_file(thread_data.clone(), value.clone()) {
+                        Ok(()) => {}
+                        Err(err) => println!("scan_file failed: {:?} {:?}", value, err),
+                    };
+                    match set_file_scan_time(thread_data.clone(), "scan_ended_at", &value) {
+                        Ok(()) => println!("scan_files set scan_ended_at"),
+                        Err(err) => println!("scan_files set scan_ended_at failed: {:?}", err),
+                    };
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    println!("scan_files failed picking file: {:?}", err);
+                    break;
+                }
+            };
+        }
+        *data.scanner_count.write().unwrap() -= 1;
     });
 }
 
-fn scan_file(data: web::Data<AppState>, file_name: String) {
+fn pick_unscanned_file(data: web::Data<AppState>) -> Result<Option<String>, ApiError> {
+    let conn = get_conn(data)?;
+    match conn.query_row(
+        "SELECT file_path FROM files WHERE scan_started_at IS NULL LIMIT 1",
+        [],
+        |row| row.get::<_, String>(0),
+    ) {
+        Ok(file_path) => Ok(Some(file_path)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Set `scan_started_at` or `scan_ended_at` of an archive to now
+fn set_file_scan_time(
+    data: web::Data<AppState>,
+    column: &str,
+    file_path: &str,
+) -> Result<(), ApiError> {
+    get_conn(data)?.execute(
+        &format!(
+            "UPDATE files SET {} = CAST(strftime('%s','now') AS INTEGER) WHERE file_path = $1",
+            column
+        ),
+        params![file_path],
+    )?;
+    Ok(())
+}
+
+fn scan_file(data: web::Data<AppState>, file_name: String) -> Result<(), ApiError> {
     println!("scan_file {:?}", file_name);
-    let conn = &mut get_conn(data.clone());
+    let conn = &mut get_conn(data.clone())?;
     let zip_file_name = file_name.clone();
     let zip_path = PathBuf::from(data.data_dir.read().unwrap().to_string()).join(file_name);
-    let zip_file = File::open(zip_path).unwrap();
-    let mut zip = ZipArchive::new(zip_file).unwrap();
+    let zip_file =
+        File::open(zip_path).map_err(|err| MediaCheckError::UnreadableArchive(err.to_string()))?;
+    let mut zip = ZipArchive::new(zip_file)
+        .map_err(|err| MediaCheckError::UnreadableArchive(err.to_string()))?;
+    // Links are read again from the csv, an archive that was replaced may list other feeds
+    conn.execute(
+        "DELETE FROM feed_files WHERE file_path = $1",
+        params![zip_file_name],
+    )?;
     for zip_index in 0..zip.len() {
-        let file = zip.by_index(zip_index).unwrap();
-        if file.is_file() {
+        let file = zip
+            .by_index(zip_index)
+            .map_err(|err| MediaCheckError::UnreadableEntry(err.to_string()))?;
+        if file.is_file() && file.enclosed_name().is_some() {
             let path = PathBuf::from(file.enclosed_name().unwrap());
             if "csv".eq(path.extension().unwrap_or(OsStr::new(""))) {
                 println!("scan_file {:?}", file.enclosed_name().unwrap());
                 let csv = &mut CsvReaderBuilder::new().has_headers(false).from_reader(file);
-                let txn = conn.transaction().unwrap();
-                let record_count = process_csv(data.clone(), &txn, csv, zip_file_name.clone());
+                let txn = conn.transaction()?;
+                let record_count = process_csv(data.clone(), &txn, csv, zip_file_name.clone())?;
                 match txn.commit() {
                     Ok(_) => println!("process_csv returned records: {:?}", record_count),
                     Err(err) => println!("process_csv commit errir: {:?}", err),
                 };
+                media_changed(&data);
             }
         }
     }
+    // Collections keep their entries, new ones are added when the archive is scanned again
+    match conn.execute(
+        FILL_COLLECTIONS_SQL,
+        named_params! { ":file_path": zip_file_name },
+    ) {
+        Ok(count) if count > 0 => println!("scan_file added {} collection feeds", count),
+        Ok(_count) => {}
+        Err(err) => println!("scan_file fill collections failed: {:?}", err),
+    };
+    Ok(())
 }
 
 fn process_csv(
@@ -1199,23 +5155,42 @@ fn process_csv(
     txn: &Transaction<'_>,
     csv: &mut csv::Reader<zip::read::ZipFile>,
     zip_file_name: String,
-) -> usize {
+) -> Result<usize, ApiError> {
     let mut origin = String::from("");
     let mut record_count = 0usize;
-    let mut insert_feed_stmt = &mut txn
-        .prepare_cached(
-            "INSERT OR IGNORE INTO feeds \
-            (feed_id, user_name, retweet_id, retweet_user_name, feed_at, twitter_url, contents) \
-            VALUES ($1, $2, $3, $4, $5, $6, $7)",
-        )
-        .unwrap();
-    let mut insert_retweet_stmt = &mut txn
-        .prepare_cached(
-            "INSERT OR IGNORE INTO feeds \
-            (feed_id, user_name, retweet_id, retweet_user_name, feed_at, twitter_url) \
-            VALUES ($1, $2, $3, $4, $5, $6)",
-        )
-        .unwrap();
+    let mut insert_feed_stmt = &mut txn.prepare_cached(
+        "INSERT INTO feeds \
+            (feed_id, user_name, retweet_id, retweet_user_name, feed_at, twitter_url, contents, \
+            reply_count, retweet_count, like_count) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) \
+            ON CONFLICT (feed_id, user_name, retweet_id, retweet_user_name) DO UPDATE SET \
+            reply_count = COALESCE(MAX(reply_count, excluded.reply_count), \
+                reply_count, excluded.reply_count), \
+            retweet_count = COALESCE(MAX(retweet_count, excluded.retweet_count), \
+                retweet_count, excluded.retweet_count), \
+            like_count = COALESCE(MAX(like_count, excluded.like_count), \
+                like_count, excluded.like_count)",
+    )?;
+    let mut insert_retweet_stmt = &mut txn.prepare_cached(
+        "INSERT OR IGNORE INTO retweets \
+            (user_name, feed_id, feed_user_name, retweet_at, twitter_url) \
+            VALUES ($1, $2, $3, $4, $5)",
+    )?;
+    let insert_hashtag_stmt = &mut txn
+        .prepare_cached("INSERT OR IGNORE INTO hashtags (feed_id, hashtag) VALUES ($1, $2)")?;
+    let insert_mention_stmt = &mut txn
+        .prepare_cached("INSERT OR IGNORE INTO mentions (feed_id, user_name) VALUES ($1, $2)")?;
+    let insert_link_stmt = &mut txn.prepare_cached(
+        "INSERT OR IGNORE INTO links (feed_id, url, domain, status_id) VALUES ($1, $2, $3, $4)",
+    )?;
+    let entity_extractor = EntityExtractor::new();
+    let insert_feed_file_stmt = &mut txn.prepare_cached(
+        "INSERT INTO feed_files \
+            (feed_id, user_name, retweet_id, retweet_user_name, file_path, is_listed) \
+            VALUES ($1, $2, $3, $4, $5, $6) \
+            ON CONFLICT (feed_id, user_name, retweet_id, retweet_user_name, file_path) \
+            DO UPDATE SET is_listed = MAX(is_listed, excluded.is_listed)",
+    )?;
     // let mut insert_media_stmt = &mut txn
     //     .prepare_cached(
     //         "INSERT OR IGNORE INTO media \
@@ -1223,9 +5198,8 @@ fn process_csv(
     //         VALUES ($1, $2, $3, $4, $5)",
     //     )
     //     .unwrap();
-    let mut insert_media_stmt = &mut txn
-        .prepare_cached(
-            "INSERT OR IGNORE INTO media \
+    let mut insert_media_stmt = &mut txn.prepare_cached(
+        "INSERT OR IGNORE INTO media \
                 (feed_id, media_id, media_type, media_url, file_path, media_path) WITH \
                 media_row AS ( \
                     SELECT \
@@ -1253,8 +5227,7 @@ fn process_csv(
                 FROM vals v \
                 LEFT JOIN media_row r \
                 ON v.feed_id = r.feed_id",
-        )
-        .unwrap();
+    )?;
 
     // NOTE: This may set off Inf or NaN which is why
     // data.time_offset must be sanitized on config read
@@ -1265,9 +5238,21 @@ fn process_csv(
             Ok::<FeedCsvRecord, CsvError>(rec) => {
                 if record_count == 3 {
                     origin = rec.action_date.to_ascii_lowercase().clone();
+                    let export_kind = rec.feed_date.trim().to_ascii_lowercase();
+                    match txn.execute(
+                        "UPDATE files SET origin = $1, export_kind = $2 WHERE file_path = $3",
+                        params![origin, export_kind, zip_file_name],
+                    ) {
+                        Ok(_row_count) => println!(
+                            "process_csv origin {:?} export_kind {:?}",
+                            origin, export_kind
+                        ),
+                        Err(err) => println!("process_csv update origin failed: {:?}", err),
+                    };
                 }
                 if record_count > 6 {
-                    process_csv_record(
+                    let entities = entity_extractor.extract(&rec.content);
+                    let feed_files = process_csv_record(
                         &mut insert_feed_stmt,
                         &mut insert_retweet_stmt,
                         &mut insert_media_stmt,
@@ -1276,6 +5261,17 @@ fn process_csv(
                         zip_file_name.clone(),
                         time_offset_ms,
                     );
+                    for feed_file in feed_files.iter() {
+                        insert_feed_file(insert_feed_file_stmt, feed_file, zip_file_name.clone());
+                    }
+                    // The contents of a record are those of the original feed, also for retweets
+                    if let Some(feed_file) = feed_files.iter().find(|v| v.retweet_id == 0) {
+                        insert_entities(
+                            [insert_hashtag_stmt, insert_mention_stmt, insert_link_stmt],
+                            feed_file.feed_id,
+                            &entities,
+                        );
+                    }
                 }
             }
             Err(err) => {
@@ -1284,7 +5280,7 @@ fn process_csv(
         }
     }
     println!("process_csv inserted {:?} rows", record_count);
-    record_count
+    Ok(record_count)
 }
 
 fn process_csv_record(
@@ -1296,7 +5292,9 @@ fn process_csv_record(
     origin: String,
     zip_path: String,
     time_offset_ms: i32,
-) {
+) -> Vec<FeedFile> {
+    // Feeds in this record, linked to the archive by the caller
+    let mut feed_files: Vec<FeedFile> = Vec::new();
     // println!("process_csv_record for {}", origin);
     let url_re = Regex::new(TWITTER_URL_REGEX).unwrap();
     let url_cap = url_re.captures(&record.twitter_url);
@@ -1318,36 +5316,70 @@ fn process_csv_record(
     // println!("  feed_id {:?}", feed_id);
     match feed_id {
         Some(id) => {
-            let feed_at = str_to_timestamp(&record.feed_date, time_offset_ms);
-            let action_at = str_to_timestamp(&record.action_date, time_offset_ms);
-            if action_at.is_some() {
+            // Exports of different times may count differently, the highest counts are kept
+            let counts = FeedCounts {
+                reply_count: parse_feed_count(&record.reply_count),
+                retweet_count: parse_feed_count(&record.retweet_count),
+                like_count: parse_feed_count(&record.like_count),
+            };
+            let feed_at = match str_to_timestamp(&record.feed_date, time_offset_ms) {
+                Some(value) => value,
+                None => {
+                    println!("process_csv_record invalid date {:?}", record.feed_date);
+                    return feed_files;
+                }
+            };
+            if let Some(action_at) = str_to_timestamp(&record.action_date, time_offset_ms) {
                 // Insert retweet feed
                 insert_feed(
                     insert_feed_stmt,
                     id,
                     record.user_name.to_ascii_lowercase(),
-                    feed_at.unwrap(),
+                    feed_at,
                     record.twitter_url.clone(),
                     record.content.clone(),
+                    &counts,
                 );
+                feed_files.push(FeedFile {
+                    feed_id: id,
+                    user_name: record.user_name.to_ascii_lowercase(),
+                    retweet_id: 0i64,
+                    retweet_user_name: String::from(""),
+                    is_listed: false,
+                });
                 insert_retweet(
                     insert_retweet_stmt,
                     id,
                     record.user_name.to_ascii_lowercase(),
                     origin.clone(),
-                    action_at.unwrap(),
+                    action_at,
                     record.twitter_url.clone(),
                 );
+                feed_files.push(FeedFile {
+                    feed_id: 0i64,
+                    user_name: origin.clone(),
+                    retweet_id: id,
+                    retweet_user_name: record.user_name.to_ascii_lowercase(),
+                    is_listed: true,
+                });
             } else {
                 // Insert feed
                 insert_feed(
                     insert_feed_stmt,
                     id,
                     record.user_name.to_ascii_lowercase(),
-                    feed_at.unwrap(),
+                    feed_at,
                     record.twitter_url.clone(),
                     record.content.clone(),
+                    &counts,
                 );
+                feed_files.push(FeedFile {
+                    feed_id: id,
+                    user_name: record.user_name.to_ascii_lowercase(),
+                    retweet_id: 0i64,
+                    retweet_user_name: String::from(""),
+                    is_listed: true,
+                });
             }
             if !record.media_url.is_empty() && !record.media_file_path.is_empty() {
                 // Insert media
@@ -1363,6 +5395,7 @@ fn process_csv_record(
         }
         None => {}
     }
+    feed_files
 }
 
 fn insert_feed(
@@ -1372,6 +5405,7 @@ fn insert_feed(
     feed_at: i64,
     twitter_url: String,
     contents: String,
+    counts: &FeedCounts,
 ) {
     match stmt.execute(params![
         feed_id,
@@ -1380,7 +5414,10 @@ fn insert_feed(
         "",
         feed_at,
         twitter_url,
-        contents
+        contents,
+        counts.reply_count,
+        counts.retweet_count,
+        counts.like_count
     ]) {
         Ok(count) => {
             if count > 0 {
@@ -1393,6 +5430,11 @@ fn insert_feed(
     };
 }
 
+/// Counts are plain numbers in the csv, `1,234` is also read
+fn parse_feed_count(value: &str) -> Option<i64> {
+    value.trim().replace(',', "").parse::<i64>().ok()
+}
+
 fn insert_retweet(
     stmt: &mut Statement<'_>,
     retweet_id: i64,
@@ -1402,7 +5444,6 @@ fn insert_retweet(
     twitter_url: String,
 ) {
     match stmt.execute(params![
-        0i32,
         user_name,
         retweet_id,
         retweet_user_name,
@@ -1420,6 +5461,48 @@ fn insert_retweet(
     };
 }
 
+fn insert_feed_file(stmt: &mut Statement<'_>, feed_file: &FeedFile, file_path: String) {
+    match stmt.execute(params![
+        feed_file.feed_id,
+        feed_file.user_name,
+        feed_file.retweet_id,
+        feed_file.retweet_user_name,
+        file_path,
+        feed_file.is_listed
+    ]) {
+        Ok(_count) => {}
+        Err(err) => {
+            println!("insert_feed_file error: {:?}", err);
+        }
+    };
+}
+
+/// Inserts hashtags, mentions and urls with their statements in that order
+fn insert_entities(stmts: [&mut Statement<'_>; 3], feed_id: i64, entities: &Entities) {
+    let [hashtag_stmt, mention_stmt, link_stmt] = stmts;
+    let mut results: Vec<SqlResult<usize>> = Vec::new();
+    for hashtag in entities.hashtags.iter() {
+        results.push(hashtag_stmt.execute(params![feed_id, hashtag]));
+    }
+    for mention in entities.mentions.iter() {
+        results.push(mention_stmt.execute(params![feed_id, mention]));
+    }
+    for url in entities.urls.iter() {
+        if let Some(domain) = entities::url_domain(url) {
+            let status_id = entities::url_status_id(url);
+            results.push(link_stmt.execute(params![feed_id, url, domain, status_id]));
+        }
+    }
+    for result in results.into_iter() {
+        match result {
+            Ok(_count) => {}
+            Err(err) => {
+                println!("insert_entities error: {:?}", err);
+            }
+        };
+    }
+}
+
 fn insert_media(
     stmt: &mut Statement,
     feed_id: i64,
@@ -1448,16 +5531,16 @@ fn insert_media(
     };
 }
 
-fn list_all_zip(data: web::Data<AppState>) {
+fn list_all_zip(data: web::Data<AppState>) -> Result<(), ApiError> {
     println!("list_all_zip");
     let mut insert_count: usize = 0;
     // let conn = data.pool.read().unwrap().as_ref().unwrap().get().unwrap();
-    let mut conn = get_conn(data.clone());
-    let mut txn = conn.transaction().unwrap();
+    let mut conn = get_conn(data.clone())?;
+    let txn = conn.transaction()?;
     fs::read_dir(PathBuf::from(data.data_dir.read().unwrap().to_string()))
-        .unwrap()
-        .into_iter()
-        .map(|x| x.unwrap().path())
+        .map_err(|err| ApiError::Io("scan_service_01", err))?
+        .filter_map(|x| x.ok())
+        .map(|x| x.path())
         .filter(|x| x.is_file() && "zip".eq(x.extension().unwrap_or(OsStr::new(""))))
         .for_each(|x| {
             let mut stmt = txn
@@ -1482,25 +5565,36 @@ fn list_all_zip(data: web::Data<AppState>) {
     match txn.commit() {
         Ok(_) => {
             println!("list_all_zip added {} new files", insert_count);
+            Ok(())
         }
         Err(err) => {
             println!("list_all_zip error: {:?}", err);
+            Err(err.into())
         }
     }
 }
 
-fn open_db(data: web::Data<AppState>) {
+fn open_db(data: web::Data<AppState>) -> Result<(), ApiError> {
     // println!("open_db");
-    let mut pool = None;
+    let mut pools = None;
     if data.pool.read().unwrap().as_ref().is_none() {
-        pool = Some(init_pool(PathBuf::from(data.data_dir.read().unwrap().to_string())).unwrap());
+        pools = Some(init_pool(
+            PathBuf::from(data.data_dir.read().unwrap().to_string()),
+            &data.connection_options,
+        )?);
     };
-    if pool.is_some() {
-        *data.pool.write().unwrap() = pool;
+    if let Some((pool, read_pool)) = pools {
+        *data.read_pool.write().unwrap() = Some(read_pool);
+        *data.pool.write().unwrap() = Some(pool);
     }
+    Ok(())
 }
 
-fn init_pool(data_dir: PathBuf) -> Option<Pool<SqliteConnectionManager>> {
+/// Open the write pool and migrate the schema, then open the read pool
+fn init_pool(
+    data_dir: PathBuf,
+    options: &ConnectionOptions,
+) -> Result<(Pool<SqliteConnectionManager>, Pool<SqliteConnectionManager>), ApiError> {
     println!("init_pool");
     let data_file = data_dir.join(DATABASE_FILENAME);
     if data_file.exists()
@@ -1508,45 +5602,112 @@ fn init_pool(data_dir: PathBuf) -> Option<Pool<SqliteConnectionManager>> {
             || data_file.metadata().unwrap().permissions().readonly())
     {
         println!("init_pool failed, file not accessible");
-        return None;
+        return Err(ApiError::DatabaseUnavailable(format!(
+            "{} is not a writable file",
+            data_file.to_string_lossy()
+        )));
     }
 
-    let manager = SqliteConnectionManager::file(data_file);
-    let pool = Pool::new(manager).unwrap();
-    let conn = pool.get().unwrap();
+    let manager = SqliteConnectionManager::file(&data_file).with_init(register_functions);
+    let pool = Pool::builder()
+        .connection_customizer(Box::new(options.clone()))
+        .build(manager)
+        .map_err(|err| ApiError::DatabaseUnavailable(err.to_string()))?;
+    let mut conn = pool.get()?;
+    match migration::migrate(&mut conn) {
+        Ok(from_version) if from_version < migration::SCHEMA_VERSION => println!(
+            "init_pool migrated schema version {} to {}",
+            from_version,
+            migration::SCHEMA_VERSION
+        ),
+        Ok(_) => {}
+        Err(err) => {
+            println!("init_pool failed, {}", err);
+            return Err(ApiError::DatabaseUnavailable(err.to_string()));
+        }
+    };
 
-    let create_tbl_data_files_sql = include_str!("create_table_files.sql");
-    let create_tbl_feeds_sql = include_str!("create_table_feeds.sql");
-    let create_tbl_media_sql = include_str!("create_table_media.sql");
+    drop(conn);
 
-    conn.execute(create_tbl_data_files_sql, []).unwrap();
-    conn.execute(create_tbl_feeds_sql, []).unwrap();
-    conn.execute(create_tbl_media_sql, []).unwrap();
+    let read_manager = SqliteConnectionManager::file(&data_file)
+        .with_flags(
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .with_init(register_functions);
+    let read_pool = Pool::builder()
+        .connection_customizer(Box::new(options.read_only()))
+        .build(read_manager)
+        .map_err(|err| ApiError::DatabaseUnavailable(err.to_string()))?;
 
-    let create_idx_feeds_ids_sql = include_str!("create_index_feeds_ids.sql");
-    let create_idx_feeds_ids_un_sql = include_str!("create_index_feeds_ids_un.sql");
-    let create_idx_feeds_feed_at_sql = include_str!("create_index_feeds_feeds_at.sql");
-    let create_idx_media_feed_id_sql = include_str!("create_index_media_feed_id.sql");
-    let create_idx_media_ids_sql = include_str!("create_index_media_ids.sql");
-    let create_idx_media_unique_sql = include_str!("create_index_media_unique.sql");
+    println!("init_pool return pool");
+    Ok((pool, read_pool))
+}
 
-    conn.execute(create_idx_feeds_ids_sql, []).unwrap();
-    conn.execute(create_idx_feeds_ids_un_sql, []).unwrap();
-    conn.execute(create_idx_feeds_feed_at_sql, []).unwrap();
-    conn.execute(create_idx_media_feed_id_sql, []).unwrap();
-    conn.execute(create_idx_media_ids_sql, []).unwrap();
-    conn.execute(create_idx_media_unique_sql, []).unwrap();
+/// Custom SQL functions available on every connection
+fn register_functions(conn: &mut Connection) -> SqlResult<()> {
+    conn.create_scalar_function(
+        "hamming_distance",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let a: Option<i64> = ctx.get(0)?;
+            let b: Option<i64> = ctx.get(1)?;
+            Ok(match (a, b) {
+                (Some(a), Some(b)) => Some(i64::from(phash::hamming_distance(a as u64, b as u64))),
+                _ => None,
+            })
+        },
+    )?;
+    conn.create_scalar_function(
+        "seeded_random",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |ctx| {
+            let value: i64 = ctx.get(0)?;
+            let seed: i64 = ctx.get(1)?;
+            Ok(seeded_random(value, seed))
+        },
+    )
+}
 
-    println!("init_pool return pool");
-    Some(pool)
+/// The same value and seed always give the same number, so pages of a shuffle do not overlap
+fn seeded_random(value: i64, seed: i64) -> i64 {
+    // splitmix64
+    let mut x = (value as u64)
+        .wrapping_add((seed as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+        .wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (x ^ (x >> 31)) as i64
 }
 
-fn get_conn(data: web::Data<AppState>) -> PooledConnection<SqliteConnectionManager> {
-    open_db(data.clone());
+fn get_conn(
+    data: web::Data<AppState>,
+) -> Result<PooledConnection<SqliteConnectionManager>, ApiError> {
+    open_db(data.clone())?;
+    let conn: PooledConnection<SqliteConnectionManager> =
+        data.pool.read().unwrap().as_ref().unwrap().get()?;
+    conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
+    Ok(conn)
+}
+
+fn get_read_conn(
+    data: web::Data<AppState>,
+) -> Result<PooledConnection<SqliteConnectionManager>, ApiError> {
+    open_db(data.clone())?;
     let conn: PooledConnection<SqliteConnectionManager> =
-        data.pool.read().unwrap().as_ref().unwrap().get().unwrap();
+        data.read_pool.read().unwrap().as_ref().unwrap().get()?;
     conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
-    conn
+    Ok(conn)
+}
+
+fn is_pool_in_use(pool: &RwLock<Option<Pool<SqliteConnectionManager>>>) -> bool {
+    match pool.read().unwrap().as_ref() {
+        Some(pool) => pool.state().connections != pool.state().idle_connections,
+        None => false,
+    }
 }
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
@@ -1566,6 +5727,8 @@ pub async fn serve(cwd: Box<String>, server_tx: Arc<Mutex<Sender<Server>>>) -> s
     let mut bind_address = DEFAULT_BIND_ADDRESS.to_string();
     let mut time_offset = DEFAULT_TIME_OFFSET_HOUR;
     let mut scanner_count_limit = DEFAULT_SCANNER_COUNT_LIMIT;
+    let mut thumbnail_cache = None;
+    let mut connection_options = ConnectionOptions::default();
 
     // Read config file if exists
     let config_path = std::env::current_dir().unwrap().join(CONFIG_FILENAME);
@@ -1582,6 +5745,36 @@ pub async fn serve(cwd: Box<String>, server_tx: Arc<Mutex<Sender<Server>>>) -> s
             .unwrap_or(&bind_address)
             .clone();
         scanner_count_limit = config.scanner_count_limit.unwrap_or(scanner_count_limit);
+        // Thumbnails are stored in the database unless a cache directory is set
+        thumbnail_cache = config.thumbnail_cache_dir.as_ref().map(|dir| {
+            ThumbnailCache::new(
+                PathBuf::from(dir),
+                config
+                    .thumbnail_cache_size_limit
+                    .unwrap_or(DEFAULT_THUMBNAIL_CACHE_SIZE_LIMIT_MB)
+                    * ONE_MB_U64,
+            )
+        });
+        connection_options = ConnectionOptions {
+            journal_mode: config
+                .database_journal_mode
+                .clone()
+                .unwrap_or(connection_options.journal_mode),
+            busy_timeout_ms: config
+                .database_busy_timeout
+                .unwrap_or(connection_options.busy_timeout_ms),
+            synchronous: config
+                .database_synchronous
+                .clone()
+                .unwrap_or(connection_options.synchronous),
+            foreign_keys: config
+                .database_foreign_keys
+                .unwrap_or(connection_options.foreign_keys),
+            cache_size_kb: config
+                .database_cache_size
+                .unwrap_or(connection_options.cache_size_kb),
+            read_only: false,
+        };
         let time_offset_hour = config.time_offset.unwrap_or(DEFAULT_TIME_OFFSET_HOUR);
         if time_offset_hour < -24f32 || time_offset_hour > 24f32 {
             panic!("time_offset out of range {:?}", config.time_offset.unwrap());
@@ -1595,6 +5788,13 @@ pub async fn serve(cwd: Box<String>, server_tx: Arc<Mutex<Sender<Server>>>) -> s
             bind_address: Some(bind_address.to_string()),
             time_offset: Some(time_offset),
             scanner_count_limit: Some(scanner_count_limit),
+            thumbnail_cache_dir: None,
+            thumbnail_cache_size_limit: None,
+            database_journal_mode: Some(connection_options.journal_mode.clone()),
+            database_busy_timeout: Some(connection_options.busy_timeout_ms),
+            database_synchronous: Some(connection_options.synchronous.clone()),
+            database_foreign_keys: Some(connection_options.foreign_keys),
+            database_cache_size: Some(connection_options.cache_size_kb),
         };
         let config_str = serde_yaml::to_string(&config).unwrap();
         println!("write config");
@@ -1607,12 +5807,26 @@ pub async fn serve(cwd: Box<String>, server_tx: Arc<Mutex<Sender<Server>>>) -> s
         data_dir: RwLock::new(data_dir.to_string()),
         bind_address: RwLock::new(bind_address.to_string()),
         pool: RwLock::new(None),
+        read_pool: RwLock::new(None),
+        connection_options, // readonly
         is_scanning: RwLock::new(false),
+        is_verifying: RwLock::new(false),
+        is_maintaining: RwLock::new(false),
         scanner_count: RwLock::new(0),
         scanner_count_limit: scanner_count_limit, // readonly
         time_offset: time_offset,                 // readonly
+        thumbnail_cache,                          // readonly
+        media_version: RwLock::new(0),
+        duplicate_clusters: RwLock::new(None),
     });
 
+    if app_state.thumbnail_cache.is_some() {
+        flush_thumbnail_touches_periodically(app_state.clone());
+        // Move thumbnails generated before the cache directory was set
+        *app_state.scanner_count.write().unwrap() += 1;
+        migrate_thumbnails(app_state.clone()).await;
+    }
+
     // Start HTTP server
     println!("starting http://{}", bind_address);
 
@@ -1621,16 +5835,55 @@ pub async fn serve(cwd: Box<String>, server_tx: Arc<Mutex<Sender<Server>>>) -> s
 
         App::new()
             .app_data(web::Data::clone(&app_state))
+            .app_data(web::QueryConfig::default().error_handler(query_error_handler))
+            .app_data(web::FormConfig::default().error_handler(form_error_handler))
             .wrap(middleware::Compress::default())
             .service(ResourceFiles::new("/static", static_files))
             .service(feeds_service)
+            .service(on_this_day_service)
+            .service(feed_service)
+            .service(sources_service)
+            .service(muted_users_service)
+            .service(muted_user_create_service)
+            .service(muted_user_delete_service)
+            .service(tags_service)
+            .service(feed_tags_service)
+            .service(feed_tag_service)
+            .service(feed_star_service)
+            .service(feed_notes_service)
+            .service(note_create_service)
+            .service(note_update_service)
+            .service(note_delete_service)
+            .service(hashtags_service)
+            .service(links_service)
+            .service(saved_searches_service)
+            .service(saved_search_create_service)
+            .service(saved_search_delete_service)
+            .service(saved_search_feeds_service)
+            .service(saved_search_viewed_service)
+            .service(collections_service)
+            .service(collection_create_service)
+            .service(collection_delete_service)
+            .service(collection_feeds_service)
             .service(media_file_service)
             .service(media_preview_service)
+            .service(media_service)
+            .service(media_duplicates_service)
             .service(zip_service)
             .service(app_state_service)
             .service(generate_thumbnails_service)
+            .service(migrate_thumbnails_service)
             .service(scan_service)
+            .service(verify_service)
+            .service(verify_result_service)
             .service(clean_service)
+            .service(purge_service)
+            .service(db_backup_service)
+            .service(db_backups_service)
+            .service(db_restore_service)
+            .service(db_maintenance_service)
+            .service(stats_service)
+            .service(histogram_service)
             .service(set_data_dir_service)
             .service(home_service)
     })
@@ -1642,3 +5895,164 @@ pub async fn serve(cwd: Box<String>, server_tx: Arc<Mutex<Sender<Server>>>) -> s
     server_tx.lock().unwrap().send(server.clone()).unwrap();
     server.await
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test::TestRequest};
+
+    use super::*;
+    use crate::error::tests::error_response_json;
+
+    #[test]
+    fn path_ids_must_be_integers() {
+        assert_eq!(parse_path_id("feed_service_01", "42").unwrap(), 42);
+        let (status, json) =
+            error_response_json(&parse_path_id("feed_service_01", "x").unwrap_err());
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json["code"], "feed_service_01");
+        assert_eq!(json["message"], "id must be an integer");
+
+        assert_eq!(parse_path_ids("media_01", "1", "2").unwrap(), (1, 2));
+        let (status, json) = error_response_json(&parse_path_ids("media_01", "1", "").unwrap_err());
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json["code"], "media_01");
+    }
+
+    #[test]
+    fn query_ranges_accept_every_date_format() {
+        let range = |since: &str, until: &str| {
+            parse_query_range(
+                "media_service_01",
+                &Some(String::from(since)),
+                &Some(String::from(until)),
+                ONE_HOUR_I32 * 9,
+            )
+        };
+        // 2021-03-01 00:00 to 2021-03-02 00:00 at +09:00
+        let day = (Some(1614524400), Some(1614610800));
+        assert_eq!(range("2021-03-01", "2021-03-01").unwrap(), day);
+        assert_eq!(range("2021/03/01", "2021/03/01").unwrap(), day);
+        assert_eq!(
+            range("2021-03-01T00:00", "2021-03-02T00:00:00").unwrap(),
+            day
+        );
+        assert_eq!(range("1614524400", "1614610800").unwrap(), day);
+        assert_eq!(range("", "").unwrap(), (None, None));
+
+        let (status, json) = error_response_json(&range("2021-03-01", "March").unwrap_err());
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json["code"], "media_service_01");
+        assert_eq!(json["message"], "Invalid until");
+    }
+
+    #[test]
+    fn missing_feed_is_not_found() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migration::migrate(&mut conn).unwrap();
+        let err = check_feed_exists(&conn, "note_create_service_03", 1, 0).unwrap_err();
+        let (status, json) = error_response_json(&err);
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(json["code"], "note_create_service_03");
+        assert_eq!(json["message"], "Feed 1 media 0 not found");
+    }
+
+    #[test]
+    fn user_filters_are_exact_and_complementary() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migration::migrate(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO feeds (feed_id, user_name, retweet_id, retweet_user_name, feed_at, \
+                twitter_url, contents) \
+            VALUES (1, '@A_b', 0, '', 1000, 'https://twitter.com/A_b/status/1', 'one'), \
+                (2, '@axb', 0, '', 2000, 'https://twitter.com/axb/status/2', 'two');",
+        )
+        .unwrap();
+        let count = |query: FeedsQuery| -> i64 {
+            let filter = get_feeds_filter(&query, 0).unwrap();
+            conn.query_row(
+                &get_feeds_count_query(&filter),
+                &filter.params()[..],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        let user_query = |user_name: &str| FeedsQuery {
+            user_name: Some(String::from(user_name)),
+            include_muted: Some(true),
+            ..Default::default()
+        };
+        let exclude_query = |user_name: &str| FeedsQuery {
+            exclude_users: Some(String::from(user_name)),
+            include_muted: Some(true),
+            ..Default::default()
+        };
+        let q_query = |q: &str| FeedsQuery {
+            q: Some(String::from(q)),
+            include_muted: Some(true),
+            ..Default::default()
+        };
+
+        // `_` is not a wildcard, case does not matter
+        assert_eq!(count(user_query("a_b")), 1);
+        assert_eq!(count(exclude_query("a_b")), 1);
+        assert_eq!(count(q_query("from:A_B")), 1);
+        assert_eq!(count(q_query("-from:a_b")), 1);
+
+        conn.execute("INSERT INTO muted_users (user_name) VALUES ('@a_b')", [])
+            .unwrap();
+        assert_eq!(count(FeedsQuery::default()), 1);
+    }
+
+    #[test]
+    fn thumbnail_touches_are_flushed_in_one_go() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migration::migrate(&mut conn).unwrap();
+        conn.execute_batch(
+            "INSERT INTO thumbnails (feed_id, media_id, thumbnail_hash, thumbnail_size, accessed_at) \
+            VALUES (1, 0, 'aa', 10, 100), (2, 0, 'aa', 10, 100), (3, 0, 'bb', 10, 500);",
+        )
+        .unwrap();
+        let cache = ThumbnailCache::new(std::env::temp_dir(), 0);
+        cache.touch("aa", 200);
+        cache.touch("aa", 300);
+        cache.touch("bb", 400);
+        assert!(cache.has_touched());
+
+        flush_thumbnail_touches(&cache, &conn);
+        assert!(!cache.has_touched());
+        let accessed: Vec<i64> = conn
+            .prepare("SELECT accessed_at FROM thumbnails ORDER BY feed_id")
+            .unwrap()
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect();
+        // Never moved back in time
+        assert_eq!(accessed, vec![300, 300, 500]);
+
+        // Kept for the next flush when the database can not be written
+        cache.touch("bb", 600);
+        conn.execute_batch("DROP TABLE thumbnails").unwrap();
+        flush_thumbnail_touches(&cache, &conn);
+        assert_eq!(cache.take_touched().get("bb"), Some(&600));
+    }
+
+    #[test]
+    fn bad_query_and_form_are_bad_requests() {
+        let req = TestRequest::default().to_http_request();
+        let err = web::Query::<FeedsQuery>::from_query("page=first").unwrap_err();
+        let message = err.to_string();
+        let (status, json) =
+            error_response_json(query_error_handler(err, &req).as_response_error());
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json["code"], "request_01");
+        assert_eq!(json["message"], message);
+
+        let err = UrlencodedError::ContentType;
+        let message = err.to_string();
+        let (status, json) = error_response_json(form_error_handler(err, &req).as_response_error());
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(json["code"], "request_02");
+        assert_eq!(json["message"], message);
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use sha2::{Digest, Sha256};
+
+const THUMBNAIL_EXTENSION: &str = "jpg";
+
+/// Numbers temporary files, so concurrent writers of the same thumbnail never share one
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Content-addressed thumbnail store on disk.
+///
+/// Thumbnails are keyed by the SHA-256 of their contents, and spread over
+/// sub-directories named after the first byte of the hash. Which media points
+/// to which hash, and when it was last read, is kept in the `thumbnails` table.
+pub struct ThumbnailCache {
+    dir: PathBuf,
+    /// Size limit in bytes, `0` means unlimited
+    size_limit: u64,
+    /// Total size of cached files in bytes, lazily loaded from the database
+    used: Mutex<Option<u64>>,
+    /// Last read time by hash, not yet written to `thumbnails.accessed_at`
+    touched: Mutex<HashMap<String, i64>>,
+}
+
+impl ThumbnailCache {
+    pub fn new(dir: PathBuf, size_limit: u64) -> ThumbnailCache {
+        ThumbnailCache {
+            dir,
+            size_limit,
+            used: Mutex::new(None),
+            touched: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn dir(&self) -> &PathBuf {
+        &self.dir
+    }
+
+    pub fn size_limit(&self) -> u64 {
+        self.size_limit
+    }
+
+    pub fn hash(blob: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(blob))
+    }
+
+    fn path(&self, hash: &str) -> Option<PathBuf> {
+        // Hashes are read back from the database, make sure they never escape the cache dir
+        if hash.len() < 2 || !hash.chars().all(|ch| ch.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(
+            self.dir
+                .join(&hash[..2])
+                .join(format!("{}.{}", hash, THUMBNAIL_EXTENSION)),
+        )
+    }
+
+    /// Write a thumbnail, returning its hash and whether a new file was created.
+    pub fn write(&self, blob: &[u8]) -> io::Result<(String, bool)> {
+        let hash = ThumbnailCache::hash(blob);
+        let path = self.path(&hash).unwrap();
+        if path.is_file() {
+            return Ok((hash, false));
+        }
+        fs::create_dir_all(path.parent().unwrap())?;
+        // Write to a temporary file first so a concurrent reader never sees a partial file
+        let tmp_path = path.with_file_name(format!(
+            "{}.{}.{}.tmp",
+            hash,
+            process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        if let Err(err) = fs::write(&tmp_path, blob).and_then(|_| fs::rename(&tmp_path, &path)) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(err);
+        }
+        Ok((hash, true))
+    }
+
+    pub fn read(&self, hash: &str) -> io::Result<Vec<u8>> {
+        match self.path(hash) {
+            Some(path) => fs::read(path),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid hash")),
+        }
+    }
+
+    /// Remove a thumbnail, a missing file is not an error.
+    pub fn remove(&self, hash: &str) -> io::Result<()> {
+        match self.path(hash) {
+            Some(path) => match fs::remove_file(path) {
+                Ok(()) => Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(err) => Err(err),
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Remove every cached thumbnail.
+    pub fn clear(&self) -> io::Result<()> {
+        if self.dir.is_dir() {
+            for entry in fs::read_dir(&self.dir)? {
+                let path = entry?.path();
+                let is_bucket = path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map(|name| name.len() == 2 && name.chars().all(|ch| ch.is_ascii_hexdigit()))
+                    .unwrap_or(false);
+                if path.is_dir() && is_bucket {
+                    fs::remove_dir_all(path)?;
+                }
+            }
+        }
+        *self.used.lock().unwrap() = Some(0);
+        Ok(())
+    }
+
+    /// Current total size, `load` is called to read it from the database on first use.
+    pub fn used<F: FnOnce() -> u64>(&self, load: F) -> u64 {
+        *self.used.lock().unwrap().get_or_insert_with(load)
+    }
+
+    /// Forget the current total size, it is loaded again on next use.
+    pub fn reset_used(&self) {
+        *self.used.lock().unwrap() = None;
+    }
+
+    pub fn add_used(&self, size: u64) {
+        if let Some(used) = self.used.lock().unwrap().as_mut() {
+            *used += size;
+        }
+    }
+
+    /// Record a read, kept in memory so viewing thumbnails never writes to the database.
+    pub fn touch(&self, hash: &str, accessed_at: i64) {
+        self.touched
+            .lock()
+            .unwrap()
+            .insert(hash.to_string(), accessed_at);
+    }
+
+    pub fn has_touched(&self) -> bool {
+        !self.touched.lock().unwrap().is_empty()
+    }
+
+    /// Reads recorded since the last call, to be written to the database.
+    pub fn take_touched(&self) -> HashMap<String, i64> {
+        std::mem::take(&mut *self.touched.lock().unwrap())
+    }
+
+    /// Put back reads that could not be written, keeping the latest time of each hash.
+    pub fn restore_touched(&self, touched: HashMap<String, i64>) {
+        let mut current = self.touched.lock().unwrap();
+        for (hash, accessed_at) in touched {
+            let value = current.entry(hash).or_insert(accessed_at);
+            *value = (*value).max(accessed_at);
+        }
+    }
+
+    pub fn sub_used(&self, size: u64) {
+        if let Some(used) = self.used.lock().unwrap().as_mut() {
+            *used = used.saturating_sub(size);
+        }
+    }
+}
@@ -1,9 +1,9 @@
 const LANG_CODE = document.documentElement.lang || navigator.language;
 const DATETIME_FORMAT = (new Intl.DateTimeFormat(LANG_CODE, { dateStyle: 'long', timeStyle: 'short' }));
 
-let VIEWS = ['feeds', 'settings'];
-let TAB_IDS = ['feedsTab', 'settingsTab'];
-let VIEW_IDS = ['feedsView', 'settingsView']
+let VIEWS = ['feeds', 'media', 'settings'];
+let TAB_IDS = ['feedsTab', 'mediaTab', 'settingsTab'];
+let VIEW_IDS = ['feedsView', 'mediaView', 'settingsView', 'tweetView']
 
 let currentView = 'feeds';
 let feedsState = {
@@ -13,13 +13,28 @@ let feedsState = {
         has_media_only: undefined,
         since: undefined,
         until: undefined,
+        around: undefined,
+        offset: undefined,
         page: 0,
         count: undefined,
     },
+    cursors: undefined,
     hasPrevious: false,
     hasNext: false,
     showFilter: false,
 };
+let mediaState = {
+    query: {
+        user_name: undefined,
+        media_type: undefined,
+        since: undefined,
+        until: undefined,
+        page: 0,
+        count: undefined,
+    },
+    hasPrevious: false,
+    hasNext: false,
+};
 
 // Utilities
 
@@ -221,6 +236,9 @@ function showView(view) {
             case 'settings':
                 showSettings();
                 break;
+            case 'media':
+                showMedia();
+                break;
             case 'feeds':
             default:
                 showFeeds();
@@ -230,7 +248,7 @@ function showView(view) {
 }
 
 function showSettings() {
-    //
+    fetchBackups();
 }
 
 function feedsHashObject() {
@@ -249,6 +267,8 @@ function updateFeedsStateFromHash() {
             feedsState.query.user_name = query.user_name ? query.user_name : undefined;
             feedsState.query.keyword = query.keyword ? query.keyword : undefined;
             feedsState.query.has_media_only = (query.has_media_only && query.has_media_only === 'true') ? true : undefined;
+            feedsState.query.around = query.around ? query.around : undefined;
+            feedsState.query.offset = (!isNaN(parseInt(query.offset)) && parseInt(query.offset) >= 0) ? parseInt(query.offset) : undefined;
             break;
         default:
         // Not a #feeds path
@@ -268,6 +288,8 @@ function updateFeedsState(evt) {
     feedsState.query.keyword = inputKeyword ? inputKeyword : undefined;
     let inputHasMediaOnly = byId('feedsHasMediaOnlyInput').checked === true;
     feedsState.query.has_media_only = inputHasMediaOnly ? true : undefined;
+    let inputAround = byId('feedsAroundInput').value;
+    feedsState.query.around = inputAround ? inputAround : undefined;
 
     // console.log('updateFeedsState', feedsState);
     return beforeState;
@@ -291,10 +313,14 @@ function onFeedsInputChange(evt) {
     if (src.id !== 'feedsPageInput') {
         feedsState.query.page = 0;
         byId('feedsPageInput').value = 1;
+    } else {
+        // A page number leaves the date picked
+        byId('feedsAroundInput').value = '';
     }
+    feedsState.query.offset = undefined;
     let lastState = updateFeedsState(evt);
     console.log('onFeedsInputChange', feedsState, lastState, evt.srcElement);
-    if (feedsState.query.page !== lastState.query.page || feedsState.query.user_name !== lastState.query.user_name || feedsState.query.keyword !== lastState.query.keyword || feedsState.query.has_media_only !== lastState.query.has_media_only) {
+    if (feedsState.query.page !== lastState.query.page || feedsState.query.user_name !== lastState.query.user_name || feedsState.query.keyword !== lastState.query.keyword || feedsState.query.has_media_only !== lastState.query.has_media_only || feedsState.query.around !== lastState.query.around || lastState.query.offset !== undefined) {
         fetchFeeds();
     }
 }
@@ -343,8 +369,14 @@ function renderFeed(feed) {
     // content:
     // (<actor> {RT <origin>} | date )
     // (<text>           | media x 4 )
-    let isRetweet = feed.retweet ? true : false;
-    let f = isRetweet ? feed.retweet : feed;
+    let isRetweet = feed.retweet_id !== undefined;
+    // The original of a retweet is missing when it was not archived
+    let f = isRetweet ? (feed.retweet || {
+        user_name: feed.retweet_user_name,
+        feed_at: feed.retweet_at,
+        twitter_url: feed.twitter_url,
+        contents: '',
+    }) : feed;
     let isReply = f.contents && f.contents.startsWith('@');
     let hasMedia = f.media !== undefined && f.media.length > 0;
 
@@ -378,6 +410,7 @@ function renderFeed(feed) {
     feedHeaderUsername.href = TWITTER_URL + '/' + f.user_name.substring(1);
     let feedHeaderDateTime = feedElem.querySelector('.feed-header-details .feed-datetime');
     feedHeaderDateTime.textContent = formatDate(new Date(f.feed_at * 1000));
+    feedHeaderDateTime.href = '#/tweet/' + (isRetweet ? feed.retweet_id : f.feed_id);
     let feedHeaderTwitterLink = feedElem.querySelector('.feed-header-details .feed-twitter-url a');
     feedHeaderTwitterLink.href = f.twitter_url;
 
@@ -428,17 +461,28 @@ function fetchFeeds() {
     console.log('fetchFeeds', feedsState.query);
     feedsState.hasNext = false;
     feedsState.hasPrevious = false;
+    feedsState.cursors = undefined;
     const query = Object.assign({}, feedsState.query);
     return fetch('/a/feeds?' + encodeQuery(query))
         .then(res => res.json())
         .then(res => {
             if (res.feeds) {
                 renderFeeds(res.feeds);
-                if (res.feeds.length > 0) {
-                    feedsState.hasNext = true;
-                }
-                if (query.page > 0) {
-                    feedsState.hasPrevious = true;
+                if (res.cursors) {
+                    // Paging from the date picked goes on by offset
+                    feedsState.cursors = res.cursors;
+                    feedsState.query.around = undefined;
+                    feedsState.query.offset = res.query.offset;
+                    feedsState.query.page = res.query.page;
+                    feedsState.hasNext = res.cursors.next !== null;
+                    feedsState.hasPrevious = res.cursors.previous !== null;
+                } else {
+                    if (res.feeds.length > 0) {
+                        feedsState.hasNext = true;
+                    }
+                    if (query.page > 0) {
+                        feedsState.hasPrevious = true;
+                    }
                 }
                 updateFeedsViewState();
             } else {
@@ -448,11 +492,25 @@ function fetchFeeds() {
 }
 
 function nextFeeds() {
+    if (feedsState.cursors) {
+        if (feedsState.cursors.next !== null) {
+            feedsState.query.offset = feedsState.cursors.next;
+            return fetchFeeds();
+        }
+        return;
+    }
     feedsState.query.page++;
     return fetchFeeds();
 }
 
 function prevFeeds() {
+    if (feedsState.cursors) {
+        if (feedsState.cursors.previous !== null) {
+            feedsState.query.offset = feedsState.cursors.previous;
+            return fetchFeeds();
+        }
+        return;
+    }
     if (feedsState.query.page > 0) {
         feedsState.query.page--;
         return fetchFeeds();
@@ -460,6 +518,202 @@ function prevFeeds() {
     return;
 }
 
+// Tweet view, not a tab
+
+function showTweet(feedId) {
+    console.log('showTweet', feedId);
+    TAB_IDS.forEach(id => removeClass(byId(id), 'is-active'));
+    exclusiveNotClass('is-hidden', byId('tweetView'), ...VIEW_IDS.filter(id => id !== 'tweetView').map(id => byId(id)));
+    fetchTweet(feedId);
+}
+
+function renderTweetSection(sectionId, listId, elems) {
+    let listElem = byId(listId);
+    clearChild(listElem);
+    elems.forEach(e => listElem.appendChild(e));
+    if (elems.length > 0) {
+        removeClass(byId(sectionId), 'is-hidden');
+    } else {
+        addClass(byId(sectionId), 'is-hidden');
+    }
+}
+
+function renderQuote(quote) {
+    if (quote.feed) {
+        return renderFeed(quote.feed);
+    }
+    // Not archived, only its link
+    let quoteElem = elem('div', 'panel-block', 'feed');
+    let quoteLink = elem('a');
+    quoteLink.href = quote.url;
+    quoteLink.target = '_blank';
+    quoteLink.rel = 'noopener noreferrer';
+    quoteLink.textContent = quote.url;
+    quoteElem.appendChild(quoteLink);
+    return quoteElem;
+}
+
+function fetchTweet(feedId) {
+    return fetch('/a/feeds/' + feedId)
+        .then(res => res.json())
+        .then(res => {
+            let tweetElem = byId('tweet');
+            clearChild(tweetElem);
+            tweetElem.appendChild(res.feed ? renderFeed(res.feed) : renderEmptyFeed());
+            renderTweetSection('tweetRetweetersSection', 'tweetRetweeters', (res.retweeters || []).map(retweeter => {
+                let tag = elem('a', 'tag');
+                tag.textContent = retweeter.user_name;
+                tag.title = formatDate(new Date(retweeter.retweet_at * 1000));
+                tag.href = TWITTER_URL + '/' + retweeter.user_name.substring(1);
+                tag.target = '_blank';
+                tag.rel = 'noopener noreferrer';
+                return tag;
+            }));
+            renderTweetSection('tweetMentionsSection', 'tweetMentions', (res.mentions_after || []).map(renderFeed));
+            renderTweetSection('tweetQuotesSection', 'tweetQuotes', (res.quotes || []).map(renderQuote));
+            renderTweetSection('tweetQuotedBySection', 'tweetQuotedBy', (res.quoted_by || []).map(renderFeed));
+        });
+}
+
+function mediaHashObject() {
+    let query = Object.assign({}, mediaState.query);
+    query.page = query.page + 1;
+    return query;
+}
+
+function updateMediaStateFromHash() {
+    let hash = window.location.hash;
+    if (/^#media[\?]?/.test(hash)) {
+        const query = decodeQuery(hash.substring('#media'.length));
+        mediaState.query.page = (!isNaN(parseInt(query.page)) ? Math.max(1, parseInt(query.page)) : 1) - 1;
+        mediaState.query.count = (!isNaN(parseInt(query.count)) && parseInt(query.count) > 0) ? parseInt(query.count) : undefined;
+        mediaState.query.user_name = query.user_name ? query.user_name : undefined;
+        mediaState.query.media_type = query.media_type ? query.media_type : undefined;
+        mediaState.query.since = query.since ? query.since : undefined;
+        mediaState.query.until = query.until ? query.until : undefined;
+    }
+}
+
+function updateMediaState(evt) {
+    let beforeState = Object.assign({}, mediaState);
+    beforeState.query = Object.assign({}, mediaState.query);
+
+    let inputPage = parseInt(byId('mediaPageInput').value);
+    mediaState.query.page = !isNaN(inputPage) ? Math.max(inputPage - 1, 0) : 0;
+    let inputUserName = byId('mediaUserNameInput').value;
+    mediaState.query.user_name = inputUserName ? inputUserName : undefined;
+    let inputMediaType = byId('mediaTypeInput').value;
+    mediaState.query.media_type = inputMediaType ? inputMediaType : undefined;
+    let inputSince = byId('mediaSinceInput').value;
+    mediaState.query.since = inputSince ? inputSince : undefined;
+    let inputUntil = byId('mediaUntilInput').value;
+    mediaState.query.until = inputUntil ? inputUntil : undefined;
+
+    return beforeState;
+}
+
+function updateMediaViewState(evt) {
+    byId('mediaPageInput').value = mediaState.query.page + 1;
+    byId('mediaUserNameInput').value = mediaState.query.user_name ? mediaState.query.user_name : '';
+    byId('mediaTypeInput').value = mediaState.query.media_type ? mediaState.query.media_type : '';
+    byId('mediaSinceInput').value = mediaState.query.since ? mediaState.query.since : '';
+    byId('mediaUntilInput').value = mediaState.query.until ? mediaState.query.until : '';
+
+    boolAttr(byId('prevMediaButton'), 'disabled', !mediaState.hasPrevious);
+    boolAttr(byId('nextMediaButton'), 'disabled', !mediaState.hasNext);
+
+    replaceHash('media?' + encodeQuery(mediaHashObject()));
+}
+
+function onMediaInputChange(evt) {
+    let src = evt.srcElement;
+    if (src.id !== 'mediaPageInput') {
+        mediaState.query.page = 0;
+        byId('mediaPageInput').value = 1;
+    }
+    let lastState = updateMediaState(evt);
+    if (Object.keys(mediaState.query).some(key => mediaState.query[key] !== lastState.query[key])) {
+        fetchMedia();
+    }
+}
+
+function showMedia(evt) {
+    updateMediaStateFromHash();
+    fetchMedia();
+}
+
+function renderMedia(media) {
+    let gridElem = byId('mediaGrid');
+    clearChild(gridElem);
+    if (media && media.length > 0) {
+        media.forEach(m => {
+            gridElem.appendChild(renderMediaItem(m));
+        });
+    } else {
+        let emptyElem = renderEmptyFeed();
+        addClass(emptyElem.firstElementChild, 'media-grid-empty');
+        gridElem.appendChild(emptyElem);
+    }
+}
+
+function renderMediaItem(m) {
+    let mediaFileUrl = '/a/media/file/' + m.feed_id + '/' + m.media_id;
+    let mediaPreviewUrl = '/a/media/preview/' + m.feed_id + '/' + m.media_id;
+    let isImage = m.media_type === 'Image' && !m.deleted_at;
+    let itemElem = byId(isImage ? 'media-item-template' : 'media-video-item-template').content.cloneNode(true);
+
+    let mediaLink = itemElem.querySelector('.media-grid-link');
+    mediaLink.href = mediaFileUrl;
+    mediaLink.title = m.twitter_url;
+    if (isImage) {
+        let thumb = itemElem.querySelector('.media-grid-thumbnail');
+        thumb.src = mediaPreviewUrl;
+        thumb.alt = m.twitter_url;
+    } else if (m.deleted_at) {
+        itemElem.querySelector('.media-grid-thumbnail').textContent = 'block';
+    }
+    let userNameLink = itemElem.querySelector('.media-grid-username');
+    userNameLink.textContent = m.user_name;
+    userNameLink.href = TWITTER_URL + '/' + m.user_name.substring(1);
+    itemElem.querySelector('.media-grid-datetime').textContent = formatDate(new Date(m.feed_at * 1000));
+
+    return itemElem;
+}
+
+function fetchMedia() {
+    console.log('fetchMedia', mediaState.query);
+    mediaState.hasNext = false;
+    mediaState.hasPrevious = false;
+    const query = Object.assign({}, mediaState.query);
+    return fetch('/a/media?' + encodeQuery(query))
+        .then(res => res.json())
+        .then(res => {
+            if (res.media) {
+                renderMedia(res.media);
+                if (res.media.length > 0) {
+                    mediaState.hasNext = true;
+                }
+                if (query.page > 0) {
+                    mediaState.hasPrevious = true;
+                }
+            }
+            updateMediaViewState();
+        });
+}
+
+function nextMedia() {
+    mediaState.query.page++;
+    return fetchMedia();
+}
+
+function prevMedia() {
+    if (mediaState.query.page > 0) {
+        mediaState.query.page--;
+        return fetchMedia();
+    }
+    return;
+}
+
 async function formPost(url, formObject) {
     console.log('formPost', url, encodeForm(formObject));
     return fetch(url, {
@@ -498,6 +752,16 @@ async function settingsScan(evt) {
     }
 }
 
+async function settingsRescan(evt) {
+    byId('settingsRescanButton').classList.add('disabled');
+    const res = await formPost('/a/scan', { rescan: 'true' });
+    if (res.status >= 200 && res.status <= 299) {
+        byId('settingsRescanButton').classList.remove('disabled');
+    } else {
+        byId('settingsRescanButton').classList.add('is-danger');
+    }
+}
+
 async function settingsGenerateThumbnails(evt) {
     byId('settingsGenerateThumbnailsButton').classList.add('disabled');
     const res = await formPost('/a/generate_thumbnails', {});
@@ -508,6 +772,26 @@ async function settingsGenerateThumbnails(evt) {
     }
 }
 
+async function settingsMigrateThumbnails(evt) {
+    byId('settingsMigrateThumbnailsButton').classList.add('disabled');
+    const res = await formPost('/a/migrate_thumbnails', {});
+    if (res.status >= 200 && res.status <= 299) {
+        byId('settingsMigrateThumbnailsButton').classList.remove('disabled');
+    } else {
+        byId('settingsMigrateThumbnailsButton').classList.add('is-danger');
+    }
+}
+
+async function settingsVerify(evt) {
+    byId('settingsVerifyButton').classList.add('disabled');
+    const res = await formPost('/a/verify', {});
+    if (res.status >= 200 && res.status <= 299) {
+        byId('settingsVerifyButton').classList.remove('disabled');
+    } else {
+        byId('settingsVerifyButton').classList.add('is-danger');
+    }
+}
+
 async function settingsClean(evt) {
     byId('settingsCleanButton').classList.add('disabled');
     const res = await formPost('/a/clean', {});
@@ -518,6 +802,56 @@ async function settingsClean(evt) {
     }
 }
 
+async function settingsMaintenance(evt) {
+    byId('settingsMaintenanceButton').classList.add('disabled');
+    const res = await formPost('/a/db/maintenance', {});
+    if (res.status >= 200 && res.status <= 299) {
+        byId('settingsMaintenanceButton').classList.remove('disabled');
+    } else {
+        byId('settingsMaintenanceButton').classList.add('is-danger');
+    }
+}
+
+async function fetchBackups() {
+    const res = await fetch('/a/db/backups');
+    if (res.status < 200 || res.status > 299) return;
+    const json = await res.json();
+    const selectElem = byId('settingsRestoreSelect');
+    selectElem.replaceChildren(...json.backups.map(backup => {
+        const optionElem = document.createElement('option');
+        optionElem.value = backup.name;
+        optionElem.textContent = backup.name;
+        return optionElem;
+    }));
+    byId('settingsRestoreButton').disabled = json.backups.length === 0;
+}
+
+async function settingsBackup(evt) {
+    byId('settingsBackupButton').classList.add('disabled');
+    const res = await formPost('/a/db/backup', {});
+    if (res.status >= 200 && res.status <= 299) {
+        byId('settingsBackupButton').classList.remove('disabled');
+        byId('appStateOutput').textContent = JSON.stringify(await res.json(), null, 2);
+        await fetchBackups();
+    } else {
+        byId('settingsBackupButton').classList.add('is-danger');
+    }
+}
+
+async function settingsRestore(evt) {
+    const name = byId('settingsRestoreSelect').value;
+    if (!name || !window.confirm(name)) return;
+    byId('settingsRestoreButton').classList.add('disabled');
+    const res = await formPost('/a/db/restore', { name });
+    byId('appStateOutput').textContent = JSON.stringify(await res.json(), null, 2);
+    if (res.status >= 200 && res.status <= 299) {
+        byId('settingsRestoreButton').classList.remove('disabled');
+        await fetchBackups();
+    } else {
+        byId('settingsRestoreButton').classList.add('is-danger');
+    }
+}
+
 async function settingsState(evt) {
     byId('settingsStateButton').classList.add('disabled');
     const res = await fetch('/a/state');
@@ -529,6 +863,17 @@ async function settingsState(evt) {
     }
 }
 
+async function settingsStats(evt) {
+    byId('settingsStatsButton').classList.add('disabled');
+    const res = await fetch('/a/stats');
+    if (res.status >= 200 && res.status <= 299) {
+        byId('appStateOutput').textContent = JSON.stringify(await res.json(), null, 2);
+        byId('settingsStatsButton').classList.remove('disabled');
+    } else {
+        byId('settingsStatsButton').classList.add('is-danger');
+    }
+}
+
 
 /**
  * /u/username
@@ -545,6 +890,12 @@ function routePage(evt) {
         case /^#feeds[\?]?/.test(hash):
             showView('feeds');
             break;
+        case /^#media[\?]?/.test(hash):
+            showView('media');
+            break;
+        case /^#\/tweet\/\d+$/.test(hash):
+            showTweet(hash.substring('#/tweet/'.length));
+            break;
         default:
             window.location.hash = '#feeds';
     }
@@ -572,14 +923,31 @@ function loadPage() {
     listen('feedsUserNameInput', 'change', onFeedsInputChange);
     listen('feedsKeywordInput', 'change', onFeedsInputChange);
     listen('feedsHasMediaOnlyInput', 'change', onFeedsInputChange);
+    listen('feedsAroundInput', 'change', onFeedsInputChange);
+
+    // Media view
+    listen('nextMediaButton', 'click', nextMedia);
+    listen('prevMediaButton', 'click', prevMedia);
+    listen('mediaPageInput', 'change', onMediaInputChange);
+    listen('mediaUserNameInput', 'change', onMediaInputChange);
+    listen('mediaTypeInput', 'change', onMediaInputChange);
+    listen('mediaSinceInput', 'change', onMediaInputChange);
+    listen('mediaUntilInput', 'change', onMediaInputChange);
 
     // Settings view
     listen('settingsDarkLightSwitch', 'change', toggleTheme);
     listen('settingsSetDataDirButton', 'click', settingsSetDataDir);
     listen('settingsScanButton', 'click', settingsScan);
+    listen('settingsRescanButton', 'click', settingsRescan);
     listen('settingsGenerateThumbnailsButton', 'click', settingsGenerateThumbnails);
+    listen('settingsMigrateThumbnailsButton', 'click', settingsMigrateThumbnails);
+    listen('settingsVerifyButton', 'click', settingsVerify);
     listen('settingsCleanButton', 'click', settingsClean);
+    listen('settingsMaintenanceButton', 'click', settingsMaintenance);
+    listen('settingsBackupButton', 'click', settingsBackup);
+    listen('settingsRestoreButton', 'click', settingsRestore);
     listen('settingsStateButton', 'click', settingsState);
+    listen('settingsStatsButton', 'click', settingsStats);
 
     // Route by hash
     routePage();
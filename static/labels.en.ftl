@@ -10,6 +10,8 @@ feeds-input-username =
 feeds-input-keyword =
   .placeholder = Keyword
 feeds-input-has-media-only = Media only
+feeds-input-around =
+  .title = Jump to date
 feeds-input-page =
   .placeholder = Page
   .aria-label = Page
@@ -19,11 +21,34 @@ feeds-input-next-page =
 feeds-input-previous-page =
   .title = Previous page
   .aria-label = Previous page
+feeds-permalink =
+  .title = Link to this tweet
+media-tab = Media
+media-input-username =
+  .placeholder = Username
+media-input-type-all = All media
+media-input-type-image = Images
+media-input-type-video = Videos
+media-input-since =
+  .title = Since
+media-input-until =
+  .title = Until
 settings-input-set-data-dir =
   .placeholder = New data directory
 settings-set-data-dir-button = Update
 settings-scan-button = Scan
+settings-rescan-button = Read all archives again
 settings-generate-thumbnails-button = Generate thumbnails
+settings-migrate-thumbnails-button = Move thumbnails to cache
+settings-verify-button = Verify media files
 settings-clean-database-button = Delete database
+settings-maintain-database-button = Optimize database
+settings-backup-database-button = Back up database
+settings-restore-database-button = Restore
 settings-server-state-button = Server state
-settings-dark-mode = Dark mode
\ No newline at end of file
+settings-database-stats-button = Database statistics
+settings-dark-mode = Dark mode
+tweet-retweeters = Retweeted by
+tweet-mentions-after = Later mentions
+tweet-quotes = Linked tweets
+tweet-quoted-by = Linked from
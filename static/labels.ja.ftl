@@ -10,6 +10,8 @@ feeds-input-username =
 feeds-input-keyword =
   .placeholder = キーワード検索
 feeds-input-has-media-only = メディア有り
+feeds-input-around =
+  .title = 日付へ移動
 feeds-input-page =
   .placeholder = ページ数
   .aria-label = ページ数
@@ -19,11 +21,34 @@ feeds-input-next-page =
 feeds-input-previous-page =
   .title = 前のページ
   .aria-label = 前のページ
+feeds-permalink =
+  .title = このツイートへのリンク
+media-tab = メディア
+media-input-username =
+  .placeholder = ユーザ名
+media-input-type-all = すべて
+media-input-type-image = 画像
+media-input-type-video = 動画
+media-input-since =
+  .title = 開始日
+media-input-until =
+  .title = 終了日
 settings-input-set-data-dir =
   .placeholder = 新しいデータディレクトリ
 settings-set-data-dir-button = 更新
 settings-scan-button = スキャン
+settings-rescan-button = すべてのアーカイブを再読み込み
 settings-generate-thumbnails-button = サムネイル生成
+settings-migrate-thumbnails-button = サムネイルをキャッシュへ移動
+settings-verify-button = メディアファイルを検証
 settings-clean-database-button = データベース消去
+settings-maintain-database-button = データベースを最適化
+settings-backup-database-button = データベースをバックアップ
+settings-restore-database-button = 復元
 settings-server-state-button = サーバ情報
-settings-dark-mode = ダークモード
\ No newline at end of file
+settings-database-stats-button = データベース統計
+settings-dark-mode = ダークモード
+tweet-retweeters = リツイートしたユーザ
+tweet-mentions-after = その後のメンション
+tweet-quotes = リンク先のツイート
+tweet-quoted-by = リンク元のツイート